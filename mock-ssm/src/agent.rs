@@ -0,0 +1,269 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+use crate::codec::{
+    CHANNEL_CLOSED, ClientMessage, FLAG_DISCONNECT_TO_PORT, FLAG_TERMINATE_SESSION,
+    INPUT_STREAM_DATA, OUTPUT_STREAM_DATA, PAYLOAD_FLAG, PAYLOAD_HANDSHAKE_COMPLETE,
+    PAYLOAD_HANDSHAKE_REQUEST, PAYLOAD_HANDSHAKE_RESPONSE, PAYLOAD_OUTPUT, message_id_string,
+    new_message_id,
+};
+use crate::{Backend, MockError, SharedState};
+
+// Older than 3.0.196.0 so that the plugin forwards a single connection without multiplexing
+const AGENT_VERSION: &str = "3.0.0.0";
+const READ_BUFFER_SIZE: usize = 1024;
+
+enum Outgoing {
+    /// output_stream_data, numbered by the writer
+    Stream(u32, Vec<u8>),
+    /// Sent as is
+    Control(ClientMessage),
+}
+
+pub(crate) async fn serve(listener: TcpListener, state: SharedState) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_data_channel(stream, state).await {
+                eprintln!("mock-ssm agent : {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_data_channel(stream: TcpStream, state: SharedState) -> Result<(), MockError> {
+    let mut path = String::new();
+    let websocket =
+        tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            path = request.uri().path().to_string();
+            Ok(response)
+        })
+        .await
+        .map_err(|e| MockError {
+            msg: format!("WebSocket handshake failed : {}", e),
+        })?;
+    let (mut sink, mut stream) = websocket.split();
+
+    let session_id = path.rsplit('/').next().unwrap_or_default().to_string();
+    let (token_value, properties, mut terminated, backend) = {
+        let state = state.lock().unwrap();
+        let session = state.sessions.get(&session_id).ok_or_else(|| MockError {
+            msg: format!("Unknown session {}", session_id),
+        })?;
+        (
+            session.token_value.clone(),
+            session_properties(&session.parameters),
+            session.terminated.subscribe(),
+            state.backend,
+        )
+    };
+
+    // The client opens the channel with a text frame holding the session token
+    let open_data_channel: Value = match stream.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(text.as_str()).unwrap_or_default(),
+        _ => Value::Null,
+    };
+    if open_data_channel["TokenValue"].as_str() != Some(token_value.as_str())
+        || *terminated.borrow()
+    {
+        let _ = sink.close().await;
+        return Err(MockError {
+            msg: format!("Session {} refused, bad token or terminated", session_id),
+        });
+    }
+
+    let (outgoing_sender, mut outgoing_receiver) = mpsc::unbounded_channel::<Outgoing>();
+    let writer = tokio::spawn(async move {
+        let mut sequence_number = 0;
+        while let Some(outgoing) = outgoing_receiver.recv().await {
+            let message = match outgoing {
+                Outgoing::Stream(payload_type, payload) => {
+                    sequence_number += 1;
+                    ClientMessage::new(
+                        OUTPUT_STREAM_DATA,
+                        sequence_number - 1,
+                        payload_type,
+                        payload,
+                    )
+                }
+                Outgoing::Control(message) => message,
+            };
+            let closing = message.message_type == CHANNEL_CLOSED;
+            if sink.send(Message::binary(message.encode())).await.is_err() || closing {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let handshake_request = json!({
+        "AgentVersion": AGENT_VERSION,
+        "RequestedClientActions": [{
+            "ActionType": "SessionType",
+            "ActionParameters": { "SessionType": "Port", "Properties": properties },
+        }],
+    });
+    let _ = outgoing_sender.send(Outgoing::Stream(
+        PAYLOAD_HANDSHAKE_REQUEST,
+        handshake_request.to_string().into_bytes(),
+    ));
+
+    let mut relay = Relay {
+        backend,
+        connection: None,
+        outgoing_sender: outgoing_sender.clone(),
+    };
+    let mut expected_sequence_number = 0;
+    loop {
+        let frame = tokio::select! {
+            _ = terminated.wait_for(|terminated| *terminated) => {
+                let _ = outgoing_sender.send(Outgoing::Control(channel_closed(&session_id)));
+                break;
+            }
+            frame = stream.next() => frame,
+        };
+        let message = match frame {
+            Some(Ok(Message::Binary(frame))) => ClientMessage::decode(&frame)?,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            Some(Ok(_)) => continue,
+        };
+        if message.message_type != INPUT_STREAM_DATA {
+            // Acknowledgements of our output, nothing is resent so they are dropped
+            continue;
+        }
+
+        let _ = outgoing_sender.send(Outgoing::Control(ClientMessage::acknowledge(&message)));
+        // Resent after a lost acknowledgement, already handled
+        if message.sequence_number < expected_sequence_number {
+            continue;
+        }
+        expected_sequence_number = message.sequence_number + 1;
+
+        match message.payload_type {
+            PAYLOAD_HANDSHAKE_RESPONSE => {
+                let handshake_complete =
+                    json!({ "HandshakeTimeToComplete": 1_000_000, "CustomerMessage": "" });
+                let _ = outgoing_sender.send(Outgoing::Stream(
+                    PAYLOAD_HANDSHAKE_COMPLETE,
+                    handshake_complete.to_string().into_bytes(),
+                ));
+            }
+            PAYLOAD_OUTPUT => relay.forward(message.payload).await?,
+            PAYLOAD_FLAG => match flag(&message.payload) {
+                Some(FLAG_DISCONNECT_TO_PORT) => relay.disconnect().await,
+                Some(FLAG_TERMINATE_SESSION) => {
+                    let _ = outgoing_sender.send(Outgoing::Control(channel_closed(&session_id)));
+                    break;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    relay.disconnect().await;
+    drop(relay);
+    drop(outgoing_sender);
+    let _ = writer.await;
+    Ok(())
+}
+
+/**
+ * The plugin reads the ports from the handshake, as strings where StartSession took lists
+ */
+fn session_properties(parameters: &Value) -> Value {
+    let mut properties = serde_json::Map::new();
+    properties.insert("type".into(), "LocalPortForwarding".into());
+    if let Some(parameters) = parameters.as_object() {
+        for (name, values) in parameters {
+            if let Some(value) = values.get(0).or(Some(values)).and_then(Value::as_str) {
+                properties.insert(name.clone(), value.into());
+            }
+        }
+    }
+    Value::Object(properties)
+}
+
+fn flag(payload: &[u8]) -> Option<u32> {
+    payload.try_into().ok().map(u32::from_be_bytes)
+}
+
+fn channel_closed(session_id: &str) -> ClientMessage {
+    let message_id = new_message_id();
+    let content = json!({
+        "MessageType": CHANNEL_CLOSED,
+        "MessageId": message_id_string(&message_id),
+        "DestinationId": "",
+        "SessionId": session_id,
+        "SchemaVersion": 1,
+        "CreatedDate": "",
+        "Output": "",
+    });
+    ClientMessage {
+        message_id,
+        ..ClientMessage::new(CHANNEL_CLOSED, 0, 0, content.to_string().into_bytes())
+    }
+}
+
+/**
+ * The port side of the agent, connected on the first bytes and dropped on DisconnectToPort
+ * like the SSM agent does
+ */
+struct Relay {
+    backend: Backend,
+    connection: Option<OwnedWriteHalf>,
+    outgoing_sender: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl Relay {
+    async fn forward(&mut self, payload: Vec<u8>) -> Result<(), MockError> {
+        let address = match self.backend {
+            Backend::Echo => {
+                let _ = self
+                    .outgoing_sender
+                    .send(Outgoing::Stream(PAYLOAD_OUTPUT, payload));
+                return Ok(());
+            }
+            Backend::Forward(address) => address,
+        };
+
+        if self.connection.is_none() {
+            let connection = TcpStream::connect(address).await.map_err(|e| MockError {
+                msg: format!("Unable to connect to {} : {}", address, e),
+            })?;
+            let (mut read_half, write_half) = connection.into_split();
+            let outgoing_sender = self.outgoing_sender.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0u8; READ_BUFFER_SIZE];
+                while let Ok(read) = read_half.read(&mut buffer).await {
+                    if read == 0
+                        || outgoing_sender
+                            .send(Outgoing::Stream(PAYLOAD_OUTPUT, buffer[..read].to_vec()))
+                            .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+            self.connection = Some(write_half);
+        }
+
+        let connection = self.connection.as_mut().unwrap();
+        if connection.write_all(&payload).await.is_err() {
+            self.connection = None;
+        }
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            let _ = connection.shutdown().await;
+        }
+    }
+}
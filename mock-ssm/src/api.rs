@@ -0,0 +1,349 @@
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+use crate::{MockCommand, MockSession, SharedState};
+
+const TARGET_PREFIX: &str = "AmazonSSM.";
+
+struct ApiError {
+    status: u16,
+    error_type: &'static str,
+    message: String,
+}
+
+fn bad_request(error_type: &'static str, message: String) -> ApiError {
+    ApiError {
+        status: 400,
+        error_type,
+        message,
+    }
+}
+
+pub(crate) async fn serve(listener: TcpListener, state: SharedState) {
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle_connection(stream, state.clone()));
+    }
+}
+
+/**
+ * Bare HTTP/1.1 with keep-alive, enough for the AWS SDK speaking the awsJson1_1 protocol :
+ * a POST on `/` with the operation in `X-Amz-Target` and a JSON body
+ */
+async fn handle_connection(stream: TcpStream, state: SharedState) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut content_length = 0;
+        let mut operation = String::new();
+        let mut line = String::new();
+
+        // Request line, then headers until the empty line
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                match name.trim().to_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                    "x-amz-target" => operation = value.trim().to_string(),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        if reader.read_exact(&mut body).await.is_err() {
+            return;
+        }
+
+        let (status, response) = match dispatch(&operation, &body, &state) {
+            Ok(response) => (200, response),
+            Err(e) => (
+                e.status,
+                json!({ "__type": e.error_type, "message": e.message }),
+            ),
+        };
+        let response = response.to_string();
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/x-amz-json-1.1\r\nContent-Length: {}\r\nx-amzn-RequestId: mock-ssm\r\n\r\n",
+            status,
+            if status == 200 { "OK" } else { "Bad Request" },
+            response.len()
+        );
+        let stream = reader.get_mut();
+        if stream.write_all(head.as_bytes()).await.is_err()
+            || stream.write_all(response.as_bytes()).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn dispatch(operation: &str, body: &[u8], state: &SharedState) -> Result<Value, ApiError> {
+    let request: Value = serde_json::from_slice(body)
+        .map_err(|e| bad_request("SerializationException", e.to_string()))?;
+    let field = |name: &str| {
+        request[name]
+            .as_str()
+            .map(|value| value.to_string())
+            .ok_or_else(|| bad_request("ValidationException", format!("{} is required", name)))
+    };
+
+    match operation.strip_prefix(TARGET_PREFIX).unwrap_or_default() {
+        "StartSession" => {
+            let target = field("Target")?;
+            let mut state = state.lock().unwrap();
+            state.next_session += 1;
+            let session_id = format!("mock-{}", state.next_session);
+            let session = MockSession {
+                session_id: session_id.clone(),
+                target,
+                document_name: request["DocumentName"]
+                    .as_str()
+                    .unwrap_or("SSM-SessionManagerRunShell")
+                    .to_string(),
+                parameters: request["Parameters"].clone(),
+                token_value: format!("mock-token-{}", state.next_session),
+                terminated: watch::channel(false).0,
+            };
+            let response = session_response(&session, &state.stream_base_url);
+            state.sessions.insert(session_id, session);
+            Ok(response)
+        }
+        "ResumeSession" => {
+            let session_id = field("SessionId")?;
+            let state = state.lock().unwrap();
+            state
+                .sessions
+                .get(&session_id)
+                .filter(|session| !*session.terminated.borrow())
+                .map(|session| session_response(session, &state.stream_base_url))
+                .ok_or_else(|| {
+                    bad_request("DoesNotExistException", format!("{} not found", session_id))
+                })
+        }
+        "TerminateSession" => {
+            let session_id = field("SessionId")?;
+            let state = state.lock().unwrap();
+            // Terminating an unknown or ended session succeeds, as it does on AWS
+            if let Some(session) = state.sessions.get(&session_id) {
+                session.terminated.send_replace(true);
+            }
+            Ok(json!({ "SessionId": session_id }))
+        }
+        "DescribeSessions" => {
+            let state = state.lock().unwrap();
+            let sessions: Vec<Value> = state
+                .sessions
+                .values()
+                .filter(|session| !*session.terminated.borrow())
+                .map(|session| {
+                    json!({
+                        "SessionId": session.session_id,
+                        "Target": session.target,
+                        "DocumentName": session.document_name,
+                        "Status": "Connected",
+                    })
+                })
+                .collect();
+            Ok(json!({ "Sessions": sessions }))
+        }
+        "SendCommand" => {
+            let instance_id = request["InstanceIds"][0]
+                .as_str()
+                .map(|instance_id| instance_id.to_string())
+                .ok_or_else(|| {
+                    bad_request("ValidationException", "InstanceIds is required".into())
+                })?;
+            let document_name = field("DocumentName")?;
+            let mut state = state.lock().unwrap();
+            let lines = request["Parameters"]["commands"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|line| line.as_str())
+                .map(|line| resolve_secure_references(line, &state.parameters))
+                .collect::<Result<_, _>>()?;
+            let command_id = format!("mock-command-{}", state.commands.len() + 1);
+            let response = json!({
+                "Command": {
+                    "CommandId": command_id,
+                    "DocumentName": document_name,
+                    "InstanceIds": [instance_id],
+                    "Status": "Pending",
+                }
+            });
+            state.commands.insert(
+                command_id,
+                MockCommand {
+                    instance_id,
+                    document_name,
+                    parameters: request["Parameters"].clone(),
+                    lines,
+                    polls: 0,
+                },
+            );
+            Ok(response)
+        }
+        "GetCommandInvocation" => {
+            let command_id = field("CommandId")?;
+            let instance_id = field("InstanceId")?;
+            let mut state = state.lock().unwrap();
+            let command = state
+                .commands
+                .get_mut(&command_id)
+                .filter(|command| command.instance_id == instance_id)
+                .ok_or_else(|| {
+                    bad_request(
+                        "InvocationDoesNotExist",
+                        format!("{} not found on {}", command_id, instance_id),
+                    )
+                })?;
+            command.polls += 1;
+            Ok(command_invocation(&command_id, command))
+        }
+        "DescribeInstanceInformation" => {
+            let instance_ids: Vec<&str> = request["Filters"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|filter| filter["Key"] == "InstanceIds")
+                .flat_map(|filter| filter["Values"].as_array().into_iter().flatten())
+                .filter_map(|instance_id| instance_id.as_str())
+                .collect();
+            let instances: Vec<Value> = instance_ids
+                .into_iter()
+                .filter_map(instance_information)
+                .collect();
+            Ok(json!({ "InstanceInformationList": instances }))
+        }
+        "PutParameter" => {
+            let name = field("Name")?;
+            let value = field("Value")?;
+            let mut state = state.lock().unwrap();
+            if state.parameters.contains_key(&name) && request["Overwrite"] != true {
+                return Err(bad_request(
+                    "ParameterAlreadyExists",
+                    format!("{} already exists", name),
+                ));
+            }
+            state.parameters.insert(name, value);
+            Ok(json!({ "Version": 1, "Tier": "Standard" }))
+        }
+        "DeleteParameter" => {
+            let name = field("Name")?;
+            let mut state = state.lock().unwrap();
+            state
+                .parameters
+                .remove(&name)
+                .map(|_| json!({}))
+                .ok_or_else(|| bad_request("ParameterNotFound", format!("{} not found", name)))
+        }
+        _ => Err(bad_request(
+            "UnknownOperationException",
+            format!("Operation {} is not mocked", operation),
+        )),
+    }
+}
+
+/**
+ * Replaces the `{{ssm-secure:name}}` references by the value of the parameter, which must exist
+ * when the command is sent
+ */
+fn resolve_secure_references(
+    line: &str,
+    parameters: &HashMap<String, String>,
+) -> Result<String, ApiError> {
+    const PREFIX: &str = "{{ssm-secure:";
+    let mut resolved = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(PREFIX) {
+        let (name, after) = rest[start + PREFIX.len()..]
+            .split_once("}}")
+            .ok_or_else(|| {
+                bad_request(
+                    "ValidationException",
+                    format!("Unclosed reference in {}", line),
+                )
+            })?;
+        let value = parameters.get(name.trim()).ok_or_else(|| {
+            bad_request("InvalidParameters", format!("Parameter {} not found", name))
+        })?;
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(value);
+        rest = after;
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/**
+ * The script prints its lines back, up to and including the first one containing `throw`
+ * which fails it. The output is cut after the first line while in progress
+ */
+fn command_invocation(command_id: &str, command: &MockCommand) -> Value {
+    let lines: Vec<&str> = command.lines.iter().map(|line| line.as_str()).collect();
+    let failed_at = lines.iter().position(|line| line.contains("throw"));
+    let output = lines[..failed_at.unwrap_or(lines.len())].join("\n");
+    let (status, response_code, output, error) = match (command.polls, failed_at) {
+        (1, _) => (
+            "InProgress",
+            -1,
+            lines.first().copied().unwrap_or_default().to_string(),
+            String::new(),
+        ),
+        (_, Some(failed_at)) => ("Failed", 1, output, lines[failed_at].to_string()),
+        (_, None) => ("Success", 0, output, String::new()),
+    };
+    json!({
+        "CommandId": command_id,
+        "InstanceId": command.instance_id,
+        "DocumentName": command.document_name,
+        "Status": status,
+        "StatusDetails": status,
+        "ResponseCode": response_code,
+        "StandardOutputContent": output,
+        "StandardErrorContent": error,
+    })
+}
+
+/**
+ * Every instance is managed and online, except those whose id contains `unmanaged`, unknown
+ * to SSM, or `lost`, whose agent stopped answering
+ */
+fn instance_information(instance_id: &str) -> Option<Value> {
+    if instance_id.contains("unmanaged") {
+        return None;
+    }
+    let ping_status = match instance_id.contains("lost") {
+        true => "ConnectionLost",
+        false => "Online",
+    };
+    Some(json!({
+        "InstanceId": instance_id,
+        "PingStatus": ping_status,
+        "AgentVersion": "3.3.1142.0",
+        "PlatformType": "Windows",
+        "PlatformName": "Microsoft Windows Server 2022 Datacenter",
+        "ResourceType": "EC2Instance",
+    }))
+}
+
+fn session_response(session: &MockSession, stream_base_url: &str) -> Value {
+    json!({
+        "SessionId": session.session_id,
+        "TokenValue": session.token_value,
+        "StreamUrl": format!("{}/{}?role=publish_subscribe", stream_base_url, session.session_id),
+    })
+}
@@ -0,0 +1,158 @@
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::MockError;
+use crate::codec::{
+    CHANNEL_CLOSED, ClientMessage, INPUT_STREAM_DATA, OUTPUT_STREAM_DATA, PAYLOAD_FLAG,
+    PAYLOAD_HANDSHAKE_COMPLETE, PAYLOAD_HANDSHAKE_REQUEST, PAYLOAD_HANDSHAKE_RESPONSE,
+    PAYLOAD_OUTPUT, message_id_string, new_message_id,
+};
+
+const CLIENT_VERSION: &str = "1.2.0.0";
+
+/**
+ * The session-manager-plugin side of the data channel, reduced to what a test needs to push
+ * bytes through a port forwarding session : open, handshake, send, receive
+ */
+pub struct DataChannelClient {
+    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    sequence_number: i64,
+    expected_sequence_number: i64,
+}
+
+impl DataChannelClient {
+    pub async fn connect(stream_url: &str, token_value: &str) -> Result<Self, MockError> {
+        let (websocket, _) = tokio_tungstenite::connect_async(stream_url)
+            .await
+            .map_err(|e| MockError {
+                msg: format!("connect : Unable to open {} : {}", stream_url, e),
+            })?;
+        let mut client = DataChannelClient {
+            websocket,
+            sequence_number: 0,
+            expected_sequence_number: 0,
+        };
+
+        let open_data_channel = json!({
+            "MessageSchemaVersion": "1.0",
+            "RequestId": message_id_string(&new_message_id()),
+            "TokenValue": token_value,
+            "ClientId": message_id_string(&new_message_id()),
+            "ClientVersion": CLIENT_VERSION,
+        });
+        client
+            .send_frame(Message::text(open_data_channel.to_string()))
+            .await?;
+
+        client
+            .expect_stream_payload(PAYLOAD_HANDSHAKE_REQUEST)
+            .await?;
+        let handshake_response = json!({
+            "ClientVersion": CLIENT_VERSION,
+            "ProcessedClientActions": [{ "ActionType": "SessionType", "ActionStatus": 1 }],
+            "Errors": [],
+        });
+        client
+            .send_input(
+                PAYLOAD_HANDSHAKE_RESPONSE,
+                handshake_response.to_string().into_bytes(),
+            )
+            .await?;
+        client
+            .expect_stream_payload(PAYLOAD_HANDSHAKE_COMPLETE)
+            .await?;
+
+        Ok(client)
+    }
+
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), MockError> {
+        self.send_input(PAYLOAD_OUTPUT, data.to_vec()).await
+    }
+
+    pub async fn send_flag(&mut self, flag: u32) -> Result<(), MockError> {
+        self.send_input(PAYLOAD_FLAG, flag.to_be_bytes().to_vec())
+            .await
+    }
+
+    /// Next bytes from the remote port, `None` once the agent closed the channel
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, MockError> {
+        loop {
+            match self.next_stream_message().await? {
+                Some(message) if message.payload_type == PAYLOAD_OUTPUT => {
+                    return Ok(Some(message.payload));
+                }
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn send_input(&mut self, payload_type: u32, payload: Vec<u8>) -> Result<(), MockError> {
+        let message = ClientMessage::new(
+            INPUT_STREAM_DATA,
+            self.sequence_number,
+            payload_type,
+            payload,
+        );
+        self.sequence_number += 1;
+        self.send_frame(Message::binary(message.encode())).await
+    }
+
+    async fn send_frame(&mut self, frame: Message) -> Result<(), MockError> {
+        self.websocket.send(frame).await.map_err(|e| MockError {
+            msg: format!("send : {}", e),
+        })
+    }
+
+    async fn expect_stream_payload(&mut self, payload_type: u32) -> Result<(), MockError> {
+        match self.next_stream_message().await? {
+            Some(message) if message.payload_type == payload_type => Ok(()),
+            Some(message) => Err(MockError {
+                msg: format!(
+                    "handshake : Expected payload type {}, got {}",
+                    payload_type, message.payload_type
+                ),
+            }),
+            None => Err(MockError {
+                msg: "handshake : Channel closed by the agent".into(),
+            }),
+        }
+    }
+
+    /**
+     * Acknowledges and returns the stream messages in order, skipping acknowledgements
+     */
+    async fn next_stream_message(&mut self) -> Result<Option<ClientMessage>, MockError> {
+        loop {
+            let frame = match self.websocket.next().await {
+                Some(Ok(Message::Binary(frame))) => frame,
+                Some(Ok(Message::Close(_))) | None => return Ok(None),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    return Err(MockError {
+                        msg: format!("receive : {}", e),
+                    });
+                }
+            };
+            let message = ClientMessage::decode(&frame)?;
+            match message.message_type.as_str() {
+                OUTPUT_STREAM_DATA => {
+                    self.send_frame(Message::binary(
+                        ClientMessage::acknowledge(&message).encode(),
+                    ))
+                    .await?;
+                    if message.sequence_number != self.expected_sequence_number {
+                        continue;
+                    }
+                    self.expected_sequence_number += 1;
+                    return Ok(Some(message));
+                }
+                CHANNEL_CLOSED => return Ok(None),
+                _ => continue,
+            }
+        }
+    }
+}
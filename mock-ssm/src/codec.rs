@@ -0,0 +1,226 @@
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::MockError;
+
+/*
+ * Binary frame of the SSM data channel, as read and written by session-manager-plugin :
+ *
+ * | HeaderLength u32 | MessageType [u8; 32] | SchemaVersion u32 | CreatedDate u64 |
+ * | SequenceNumber i64 | Flags u64 | MessageId [u8; 16] | PayloadDigest [u8; 32] |
+ * | PayloadType u32 | PayloadLength u32 | Payload |
+ *
+ * Integers are big endian, HeaderLength is the offset of PayloadLength.
+ */
+const MESSAGE_TYPE_LENGTH: usize = 32;
+const HEADER_LENGTH: usize = 116;
+const PAYLOAD_OFFSET: usize = HEADER_LENGTH + 4;
+
+pub const INPUT_STREAM_DATA: &str = "input_stream_data";
+pub const OUTPUT_STREAM_DATA: &str = "output_stream_data";
+pub const ACKNOWLEDGE: &str = "acknowledge";
+pub const CHANNEL_CLOSED: &str = "channel_closed";
+
+pub const PAYLOAD_OUTPUT: u32 = 1;
+pub const PAYLOAD_HANDSHAKE_REQUEST: u32 = 5;
+pub const PAYLOAD_HANDSHAKE_RESPONSE: u32 = 6;
+pub const PAYLOAD_HANDSHAKE_COMPLETE: u32 = 7;
+pub const PAYLOAD_FLAG: u32 = 10;
+
+pub const FLAG_DISCONNECT_TO_PORT: u32 = 1;
+pub const FLAG_TERMINATE_SESSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientMessage {
+    pub message_type: String,
+    pub schema_version: u32,
+    pub created_date: u64,
+    pub sequence_number: i64,
+    pub flags: u64,
+    pub message_id: [u8; 16],
+    pub payload_type: u32,
+    pub payload: Vec<u8>,
+}
+
+impl ClientMessage {
+    pub fn new(
+        message_type: &str,
+        sequence_number: i64,
+        payload_type: u32,
+        payload: Vec<u8>,
+    ) -> Self {
+        ClientMessage {
+            message_type: message_type.into(),
+            schema_version: 1,
+            created_date: now_millis(),
+            sequence_number,
+            flags: 0,
+            message_id: new_message_id(),
+            payload_type,
+            payload,
+        }
+    }
+
+    /**
+     * Acknowledges a stream data message, the receiving side drops it from its resend buffer
+     */
+    pub fn acknowledge(message: &ClientMessage) -> Self {
+        let content = serde_json::json!({
+            "AcknowledgedMessageType": message.message_type,
+            "AcknowledgedMessageId": message_id_string(&message.message_id),
+            "AcknowledgedMessageSequenceNumber": message.sequence_number,
+            "IsSequentialMessage": true,
+        });
+        ClientMessage {
+            flags: 3,
+            ..ClientMessage::new(ACKNOWLEDGE, 0, 0, content.to_string().into_bytes())
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message_type = [b' '; MESSAGE_TYPE_LENGTH];
+        let type_bytes = self.message_type.as_bytes();
+        let type_length = type_bytes.len().min(MESSAGE_TYPE_LENGTH);
+        message_type[..type_length].copy_from_slice(&type_bytes[..type_length]);
+
+        let mut frame = Vec::with_capacity(PAYLOAD_OFFSET + self.payload.len());
+        frame.extend_from_slice(&(HEADER_LENGTH as u32).to_be_bytes());
+        frame.extend_from_slice(&message_type);
+        frame.extend_from_slice(&self.schema_version.to_be_bytes());
+        frame.extend_from_slice(&self.created_date.to_be_bytes());
+        frame.extend_from_slice(&self.sequence_number.to_be_bytes());
+        frame.extend_from_slice(&self.flags.to_be_bytes());
+        frame.extend_from_slice(&self.message_id);
+        frame.extend_from_slice(&Sha256::digest(&self.payload));
+        frame.extend_from_slice(&self.payload_type.to_be_bytes());
+        frame.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&self.payload);
+        frame
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<ClientMessage, MockError> {
+        if frame.len() < PAYLOAD_OFFSET {
+            return Err(MockError {
+                msg: format!(
+                    "decode : Frame of {} bytes is shorter than a header",
+                    frame.len()
+                ),
+            });
+        }
+        let header_length = be_u32(frame, 0) as usize;
+        if header_length != HEADER_LENGTH {
+            return Err(MockError {
+                msg: format!("decode : Unexpected header length {}", header_length),
+            });
+        }
+        let payload_length = be_u32(frame, HEADER_LENGTH) as usize;
+        let payload = frame
+            .get(PAYLOAD_OFFSET..PAYLOAD_OFFSET + payload_length)
+            .ok_or_else(|| MockError {
+                msg: format!("decode : Payload of {} bytes is truncated", payload_length),
+            })?;
+        if Sha256::digest(payload).as_slice() != &frame[80..112] {
+            return Err(MockError {
+                msg: "decode : Payload digest mismatch".into(),
+            });
+        }
+
+        let mut message_id = [0u8; 16];
+        message_id.copy_from_slice(&frame[64..80]);
+        Ok(ClientMessage {
+            message_type: String::from_utf8_lossy(&frame[4..4 + MESSAGE_TYPE_LENGTH])
+                .trim_end_matches([' ', '\0'])
+                .to_string(),
+            schema_version: be_u32(frame, 36),
+            created_date: u64::from_be_bytes(frame[40..48].try_into().unwrap()),
+            sequence_number: i64::from_be_bytes(frame[48..56].try_into().unwrap()),
+            flags: u64::from_be_bytes(frame[56..64].try_into().unwrap()),
+            message_id,
+            payload_type: be_u32(frame, 112),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+fn be_u32(frame: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(frame[offset..offset + 4].try_into().unwrap())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/**
+ * Unique enough for a mock, the plugin only uses the id to log and acknowledge
+ */
+pub fn new_message_id() -> [u8; 16] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut id = [0u8; 16];
+    id[..8].copy_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    id[8..].copy_from_slice(&nanos.to_be_bytes());
+    id
+}
+
+/**
+ * The plugin writes the least significant half of the UUID first
+ */
+pub fn message_id_string(message_id: &[u8; 16]) -> String {
+    let uuid = [&message_id[8..], &message_id[..8]].concat();
+    let hex: String = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let message = ClientMessage::new(
+            OUTPUT_STREAM_DATA,
+            7,
+            PAYLOAD_OUTPUT,
+            b"\x03\x00\x00\x13".to_vec(),
+        );
+        let frame = message.encode();
+        assert_eq!(frame.len(), PAYLOAD_OFFSET + 4);
+        assert_eq!(&frame[4..22], b"output_stream_data");
+        assert_eq!(ClientMessage::decode(&frame).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_rejects_corrupted_payload() {
+        let mut frame =
+            ClientMessage::new(INPUT_STREAM_DATA, 0, PAYLOAD_OUTPUT, b"hello".to_vec()).encode();
+        let last = frame.len() - 1;
+        frame[last] ^= 0xFF;
+        assert!(ClientMessage::decode(&frame).is_err());
+        assert!(ClientMessage::decode(&frame[..PAYLOAD_OFFSET - 1]).is_err());
+    }
+
+    #[test]
+    fn test_message_id_string() {
+        let mut message_id = [0u8; 16];
+        message_id[..8].copy_from_slice(&[0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        message_id[8..].copy_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
+        assert_eq!(
+            message_id_string(&message_id),
+            "00112233-4455-6677-8899-aabbccddeeff"
+        );
+    }
+}
@@ -0,0 +1,439 @@
+//! Offline stand-in for the SSM endpoints used by vm-connect.
+//!
+//! [`MockSsm`] serves the `StartSession`, `ResumeSession`, `TerminateSession`,
+//! `DescribeSessions`, `SendCommand`, `GetCommandInvocation`, `DescribeInstanceInformation`,
+//! `PutParameter` and `DeleteParameter` JSON API on one port, and the data channel agent on another.
+//! The stream url returned by `StartSession` points at the agent, which answers the
+//! session-manager-plugin handshake for a port forwarding session, then echoes the
+//! forwarded bytes or relays them to a local TCP server. Commands sent with `SendCommand`
+//! print their lines back, and fail on a line containing `throw`, `{{ssm-secure:...}}` references
+//! being resolved from the stored parameters as the SSM agent would. Instances are all managed
+//! and online, unless their id contains `unmanaged` or `lost`. [`proxy::MockProxy`] is a
+//! forward proxy to put in between.
+
+#![allow(clippy::result_large_err)]
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+mod agent;
+mod api;
+pub mod client;
+pub mod codec;
+pub mod proxy;
+
+#[derive(Debug)]
+pub struct MockError {
+    pub msg: String,
+}
+
+impl Display for MockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+/**
+ * What the agent does with the bytes sent through the data channel
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Echo,
+    Forward(SocketAddr),
+}
+
+pub(crate) struct MockSession {
+    pub session_id: String,
+    pub target: String,
+    pub document_name: String,
+    pub parameters: Value,
+    pub token_value: String,
+    pub terminated: watch::Sender<bool>,
+}
+
+pub(crate) struct MockCommand {
+    pub instance_id: String,
+    pub document_name: String,
+    pub parameters: Value,
+    /// The `commands` as run on the instance, parameter references resolved
+    pub lines: Vec<String>,
+    /// `GetCommandInvocation` calls so far, the first one finds the command in progress
+    pub polls: usize,
+}
+
+pub(crate) struct MockState {
+    pub backend: Backend,
+    pub stream_base_url: String,
+    pub sessions: HashMap<String, MockSession>,
+    pub next_session: usize,
+    pub commands: HashMap<String, MockCommand>,
+    pub parameters: HashMap<String, String>,
+}
+
+pub(crate) type SharedState = Arc<Mutex<MockState>>;
+
+pub struct MockSsm {
+    endpoint_url: String,
+    state: SharedState,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockSsm {
+    pub async fn start(backend: Backend) -> std::io::Result<MockSsm> {
+        MockSsm::start_on("127.0.0.1:0", backend).await
+    }
+
+    /**
+     * Binds the API on `address`, the agent gets a free port on the same interface
+     */
+    pub async fn start_on(address: &str, backend: Backend) -> std::io::Result<MockSsm> {
+        let api_listener = TcpListener::bind(address).await?;
+        let api_address = api_listener.local_addr()?;
+        let agent_listener = TcpListener::bind((api_address.ip(), 0)).await?;
+        let agent_address = agent_listener.local_addr()?;
+
+        let state = Arc::new(Mutex::new(MockState {
+            backend,
+            stream_base_url: format!("ws://{}/v1/data-channel", agent_address),
+            sessions: HashMap::new(),
+            next_session: 0,
+            commands: HashMap::new(),
+            parameters: HashMap::new(),
+        }));
+
+        Ok(MockSsm {
+            endpoint_url: format!("http://{}", api_address),
+            tasks: vec![
+                tokio::spawn(api::serve(api_listener, state.clone())),
+                tokio::spawn(agent::serve(agent_listener, state.clone())),
+            ],
+            state,
+        })
+    }
+
+    pub fn endpoint_url(&self) -> String {
+        self.endpoint_url.clone()
+    }
+
+    pub fn active_sessions(&self) -> Vec<String> {
+        self.sessions_where(false)
+    }
+
+    pub fn terminated_sessions(&self) -> Vec<String> {
+        self.sessions_where(true)
+    }
+
+    /// Parameters `StartSession` was called with, `portNumber` and `localPortNumber` for a tunnel
+    pub fn session_parameters(&self, session_id: &str) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .sessions
+            .get(session_id)
+            .map(|session| session.parameters.clone())
+    }
+
+    /// Document and parameters `SendCommand` was called with, `commands` for a script
+    pub fn command(&self, command_id: &str) -> Option<(String, Value)> {
+        let state = self.state.lock().unwrap();
+        state
+            .commands
+            .get(command_id)
+            .map(|command| (command.document_name.clone(), command.parameters.clone()))
+    }
+
+    /// Names of the parameters stored with `PutParameter` and not deleted since
+    pub fn parameter_names(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<String> = state.parameters.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn sessions_where(&self, terminated: bool) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut sessions: Vec<String> = state
+            .sessions
+            .values()
+            .filter(|session| *session.terminated.borrow() == terminated)
+            .map(|session| session.session_id.clone())
+            .collect();
+        sessions.sort();
+        sessions
+    }
+}
+
+impl Drop for MockSsm {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::DataChannelClient;
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    // X.224 Connection Request, the first bytes an RDP client sends
+    const RDP_CONNECTION_REQUEST: &[u8] =
+        b"\x03\x00\x00\x13\x0e\xe0\x00\x00\x00\x00\x00\x01\x00\x08\x00\x03\x00\x00\x00";
+
+    async fn call(mock: &MockSsm, operation: &str, request: Value) -> (u16, Value) {
+        let address = mock
+            .endpoint_url()
+            .trim_start_matches("http://")
+            .to_string();
+        let mut stream = BufReader::new(tokio::net::TcpStream::connect(address).await.unwrap());
+        let body = request.to_string();
+        let head = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nX-Amz-Target: AmazonSSM.{}\r\nContent-Length: {}\r\n\r\n",
+            operation,
+            body.len()
+        );
+        stream.get_mut().write_all(head.as_bytes()).await.unwrap();
+        stream.get_mut().write_all(body.as_bytes()).await.unwrap();
+
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await.unwrap();
+            match line.trim_end().split_once(':') {
+                Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                    content_length = value.trim().parse().unwrap()
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let mut response = vec![0u8; content_length];
+        stream.read_exact(&mut response).await.unwrap();
+        (status, serde_json::from_slice(&response).unwrap())
+    }
+
+    async fn start_session(mock: &MockSsm) -> (String, String, String) {
+        let (status, response) = call(
+            mock,
+            "StartSession",
+            json!({
+                "Target": "i-0123456789abcdef0",
+                "DocumentName": "AWS-StartPortForwardingSession",
+                "Parameters": { "portNumber": ["3389"], "localPortNumber": ["55678"] },
+            }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        (
+            response["SessionId"].as_str().unwrap().to_string(),
+            response["TokenValue"].as_str().unwrap().to_string(),
+            response["StreamUrl"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_echo_and_terminate() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let (session_id, token_value, stream_url) = start_session(&mock).await;
+        assert_eq!(mock.active_sessions(), vec![session_id.clone()]);
+        assert_eq!(
+            mock.session_parameters(&session_id).unwrap()["portNumber"][0],
+            "3389"
+        );
+
+        let mut client = DataChannelClient::connect(&stream_url, &token_value)
+            .await
+            .unwrap();
+        client.send(RDP_CONNECTION_REQUEST).await.unwrap();
+        assert_eq!(
+            client.recv().await.unwrap().as_deref(),
+            Some(RDP_CONNECTION_REQUEST)
+        );
+
+        let (status, _) = call(
+            &mock,
+            "TerminateSession",
+            json!({ "SessionId": session_id }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(client.recv().await.unwrap(), None);
+        assert!(mock.active_sessions().is_empty());
+        assert_eq!(mock.terminated_sessions(), vec![session_id]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_local_server() {
+        // Answers every read with the bytes reversed, so the reply cannot be a plain echo
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut buffer = vec![0u8; 65536];
+            while let Ok(read) = stream.read(&mut buffer).await {
+                if read == 0 {
+                    break;
+                }
+                let reply: Vec<u8> = buffer[..read].iter().rev().copied().collect();
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+
+        let mock = MockSsm::start(Backend::Forward(server_address))
+            .await
+            .unwrap();
+        let (_, token_value, stream_url) = start_session(&mock).await;
+        let mut client = DataChannelClient::connect(&stream_url, &token_value)
+            .await
+            .unwrap();
+
+        client.send(b"0123456789").await.unwrap();
+        let mut received = Vec::new();
+        while received.len() < 10 {
+            received.extend(client.recv().await.unwrap().unwrap());
+        }
+        assert_eq!(received, b"9876543210");
+    }
+
+    #[tokio::test]
+    async fn test_data_channel_refuses_bad_token() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let (_, _, stream_url) = start_session(&mock).await;
+        assert!(
+            DataChannelClient::connect(&stream_url, "not-the-token")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_command() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let invocation = |command_id: &str| {
+            call(
+                &mock,
+                "GetCommandInvocation",
+                json!({ "CommandId": command_id, "InstanceId": "i-0123456789abcdef0" }),
+            )
+        };
+        let send = |commands: &[&str]| {
+            call(
+                &mock,
+                "SendCommand",
+                json!({
+                    "InstanceIds": ["i-0123456789abcdef0"],
+                    "DocumentName": "AWS-RunPowerShellScript",
+                    "Parameters": { "commands": commands },
+                }),
+            )
+        };
+
+        let (status, response) = send(&["first", "second"]).await;
+        assert_eq!(status, 200);
+        let command_id = response["Command"]["CommandId"].as_str().unwrap();
+        assert_eq!(mock.command(command_id).unwrap().1["commands"][1], "second");
+        let (_, response) = invocation(command_id).await;
+        assert_eq!(response["Status"], "InProgress");
+        assert_eq!(response["StandardOutputContent"], "first");
+        let (_, response) = invocation(command_id).await;
+        assert_eq!(response["Status"], "Success");
+        assert_eq!(response["StandardOutputContent"], "first\nsecond");
+
+        let (_, response) = send(&["first", "throw 'broken'", "never"]).await;
+        let command_id = response["Command"]["CommandId"].as_str().unwrap();
+        invocation(command_id).await;
+        let (_, response) = invocation(command_id).await;
+        assert_eq!(response["Status"], "Failed");
+        assert_eq!(response["StandardOutputContent"], "first");
+        assert_eq!(response["StandardErrorContent"], "throw 'broken'");
+
+        let (status, response) = invocation("unknown").await;
+        assert_eq!(status, 400);
+        assert_eq!(response["__type"], "InvocationDoesNotExist");
+    }
+
+    #[tokio::test]
+    async fn test_secure_parameter_reference() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let send = || {
+            call(
+                &mock,
+                "SendCommand",
+                json!({
+                    "InstanceIds": ["i-0123456789abcdef0"],
+                    "DocumentName": "AWS-RunPowerShellScript",
+                    "Parameters": { "commands": ["net user admin '{{ssm-secure:/test/pwd}}'"] },
+                }),
+            )
+        };
+
+        let (status, response) = send().await;
+        assert_eq!(status, 400);
+        assert_eq!(response["__type"], "InvalidParameters");
+
+        let (status, _) = call(
+            &mock,
+            "PutParameter",
+            json!({ "Name": "/test/pwd", "Value": "s3cret", "Type": "SecureString" }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(mock.parameter_names(), vec!["/test/pwd"]);
+        let (_, response) = send().await;
+        let command_id = response["Command"]["CommandId"].as_str().unwrap();
+        let (_, response) = call(
+            &mock,
+            "GetCommandInvocation",
+            json!({ "CommandId": command_id, "InstanceId": "i-0123456789abcdef0" }),
+        )
+        .await;
+        assert_eq!(response["StandardOutputContent"], "net user admin 's3cret'");
+
+        let (status, _) = call(&mock, "DeleteParameter", json!({ "Name": "/test/pwd" })).await;
+        assert_eq!(status, 200);
+        assert!(mock.parameter_names().is_empty());
+        let (status, response) =
+            call(&mock, "DeleteParameter", json!({ "Name": "/test/pwd" })).await;
+        assert_eq!(status, 400);
+        assert_eq!(response["__type"], "ParameterNotFound");
+    }
+
+    #[tokio::test]
+    async fn test_describe_instance_information() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let (status, response) = call(
+            &mock,
+            "DescribeInstanceInformation",
+            json!({ "Filters": [{ "Key": "InstanceIds", "Values": ["i-0123456789abcdef0", "i-lost", "i-unmanaged"] }] }),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let instances = response["InstanceInformationList"].as_array().unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0]["PingStatus"], "Online");
+        assert_eq!(instances[1]["InstanceId"], "i-lost");
+        assert_eq!(instances[1]["PingStatus"], "ConnectionLost");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_operation() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let (status, response) = call(&mock, "CancelCommand", json!({})).await;
+        assert_eq!(status, 400);
+        assert_eq!(response["__type"], "UnknownOperationException");
+
+        let (status, response) = call(&mock, "StartSession", json!({})).await;
+        assert_eq!(status, 400);
+        assert_eq!(response["__type"], "ValidationException");
+    }
+}
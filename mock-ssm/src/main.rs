@@ -0,0 +1,47 @@
+use std::net::SocketAddr;
+
+use vm_connect_mock_ssm::{Backend, MockSsm};
+
+const USAGE: &str = "Usage : mock-ssm [--listen <address:port>] [--forward <address:port>]";
+
+/**
+ * Runs the mock outside of the tests, e.g. as the `ssm` endpoint of a vm-connect target
+ * driving the real session-manager-plugin
+ */
+#[tokio::main]
+async fn main() {
+    let mut listen = "127.0.0.1:0".to_string();
+    let mut backend = Backend::Echo;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--listen", Some(address)) => listen = address,
+            ("--forward", Some(address)) => match address.parse::<SocketAddr>() {
+                Ok(address) => backend = Backend::Forward(address),
+                Err(_) => exit_with_usage(),
+            },
+            _ => exit_with_usage(),
+        }
+    }
+
+    let mock = match MockSsm::start_on(&listen, backend).await {
+        Ok(mock) => mock,
+        Err(e) => {
+            eprintln!("mock-ssm : Unable to listen on {} : {}", listen, e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "mock-ssm : SSM endpoint {} ({:?})",
+        mock.endpoint_url(),
+        backend
+    );
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
@@ -0,0 +1,250 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/**
+ * Local forward proxy : `CONNECT host:port` opens a tunnel, an absolute-form request
+ * (`POST http://host:port/ HTTP/1.1`) is passed on to its host. Either way the client
+ * connection is then relayed as is to that one upstream
+ */
+pub struct MockProxy {
+    url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockProxy {
+    /// With `credentials`, requests without the matching `Proxy-Authorization` get a 407
+    pub async fn start(credentials: Option<(&str, &str)>) -> std::io::Result<MockProxy> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let authorization = credentials.map(|(username, password)| {
+            format!(
+                "Basic {}",
+                STANDARD.encode(format!("{}:{}", username, password))
+            )
+        });
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let task = tokio::spawn(serve(listener, authorization, requests.clone()));
+        Ok(MockProxy {
+            url,
+            requests,
+            task,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Request lines of the connections relayed so far, refused ones are left out
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    authorization: Option<String>,
+    requests: Arc<Mutex<Vec<String>>>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let authorization = authorization.clone();
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, authorization, requests).await {
+                eprintln!("mock-ssm proxy : {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    authorization: Option<String>,
+    requests: Arc<Mutex<Vec<String>>>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let request_line = request_line.trim_end().to_string();
+
+    // Headers are kept to be passed on, except the one meant for the proxy
+    let mut headers = String::new();
+    let mut proxy_authorization = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("proxy-authorization") => {
+                proxy_authorization = Some(value.trim().to_string());
+            }
+            _ => headers.push_str(&line),
+        }
+    }
+
+    if authorization.is_some() && proxy_authorization != authorization {
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"mock-ssm\"\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return bad_gateway(reader.get_mut(), &request_line).await,
+    };
+    let (authority, path) = match method {
+        "CONNECT" => (target.to_string(), None),
+        _ => match target.strip_prefix("http://") {
+            Some(rest) => match rest.find('/') {
+                Some(index) => (rest[..index].to_string(), Some(&rest[index..])),
+                None => (rest.to_string(), Some("/")),
+            },
+            None => return bad_gateway(reader.get_mut(), &request_line).await,
+        },
+    };
+    let authority = match authority.contains(':') {
+        true => authority,
+        false => format!("{}:80", authority),
+    };
+
+    let mut upstream = match TcpStream::connect(&authority).await {
+        Ok(upstream) => upstream,
+        Err(_) => return bad_gateway(reader.get_mut(), &request_line).await,
+    };
+    requests.lock().unwrap().push(request_line.clone());
+
+    match path {
+        None => {
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?
+        }
+        Some(path) => {
+            let head = format!("{} {} {}\r\n{}\r\n", method, path, version, headers);
+            upstream.write_all(head.as_bytes()).await?;
+        }
+    }
+    // Bytes the client sent right after its request head
+    upstream.write_all(reader.buffer()).await?;
+    let mut client = reader.into_inner();
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+    Ok(())
+}
+
+async fn bad_gateway(stream: &mut TcpStream, request_line: &str) -> std::io::Result<()> {
+    stream
+        .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+        .await?;
+    Err(std::io::Error::other(format!(
+        "Unable to relay {}",
+        request_line
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    async fn echo_server() -> String {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        address
+    }
+
+    async fn send_head(proxy: &MockProxy, head: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(proxy.url().trim_start_matches("http://"))
+            .await
+            .unwrap();
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let mut response = vec![0u8; 1024];
+        let read = stream.read(&mut response).await.unwrap();
+        let status_line = String::from_utf8_lossy(&response[..read])
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        (stream, status_line)
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnel() {
+        let upstream = echo_server().await;
+        let proxy = MockProxy::start(Some(("user", "p@ss"))).await.unwrap();
+
+        let head = format!(
+            "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\nProxy-Authorization: Basic dXNlcjpwQHNz\r\n\r\n",
+            upstream
+        );
+        let (mut stream, status_line) = send_head(&proxy, &head).await;
+        assert_eq!(status_line, "HTTP/1.1 200 Connection established");
+
+        stream.write_all(b"through the tunnel").await.unwrap();
+        let mut echoed = [0u8; 18];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"through the tunnel");
+        assert_eq!(
+            proxy.requests(),
+            vec![format!("CONNECT {} HTTP/1.1", upstream)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_absolute_form_request() {
+        let upstream = echo_server().await;
+        let proxy = MockProxy::start(None).await.unwrap();
+
+        let head = format!(
+            "GET http://{}/path?query HTTP/1.1\r\nHost: upstream\r\n\r\n",
+            upstream
+        );
+        // The echo server sends the rewritten request back
+        let (_, request_line) = send_head(&proxy, &head).await;
+        assert_eq!(request_line, "GET /path?query HTTP/1.1");
+        assert_eq!(
+            proxy.requests(),
+            vec![format!("GET http://{}/path?query HTTP/1.1", upstream)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refuses_bad_credentials() {
+        let upstream = echo_server().await;
+        let proxy = MockProxy::start(Some(("user", "p@ss"))).await.unwrap();
+
+        for authorization in ["", "Proxy-Authorization: Basic dXNlcjp3cm9uZw==\r\n"] {
+            let head = format!("CONNECT {} HTTP/1.1\r\n{}\r\n", upstream, authorization);
+            let (_, status_line) = send_head(&proxy, &head).await;
+            assert_eq!(status_line, "HTTP/1.1 407 Proxy Authentication Required");
+        }
+        assert!(proxy.requests().is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use clap::{Parser, Subcommand};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::config::{self, TargetConfig};
+use crate::doctor::{self, CheckStatus};
+use crate::fleet::{self, FleetColumn, FleetCommand};
+use crate::rdp::RdpCredentials;
+use crate::remediation::{self, RemediationAction};
+use crate::ssm;
+use crate::status::{self, StatusErrorKind};
+
+#[cfg(windows)]
+const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+
+#[derive(Debug, Parser)]
+pub struct Opt {
+    /// The AWS Region.
+    #[structopt(short, long)]
+    pub region: Option<String>,
+
+    /// Whether to display additional information.
+    #[structopt(short, long)]
+    pub verbose: bool,
+
+    /// Opens the GUI when left out
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Lists the tunnels of the application running in this directory, with their traffic
+    Sessions,
+    /// Fixes RDP on the instance of a target through SSM Run Command, printing its output
+    Remediate {
+        /// Name or instance ID of a configured target
+        target: String,
+        #[command(subcommand)]
+        action: RemediateCommand,
+    },
+    /// Runs a command document on several targets at once and prints how it went on each
+    FleetRun {
+        /// Name or instance ID of a configured target, repeated for each, all targets when left out
+        #[arg(long = "target")]
+        targets: Vec<String>,
+        #[arg(long, default_value = remediation::RUN_POWERSHELL_DOCUMENT)]
+        document: String,
+        /// Parameters of the document as JSON, e.g. '{ "commands": ["Get-HotFix"] }'
+        #[arg(long, default_value = "")]
+        parameters: String,
+        /// Writes the results with their whole output to a .csv or .json file
+        #[arg(long)]
+        export: Option<PathBuf>,
+    },
+    /// Checks what a connection needs, from the plugin to each target, and prints how to fix it
+    Doctor {
+        /// Local port the tunnel would listen on
+        #[arg(long, default_value = crate::LOCAL_PORT_NUMBER)]
+        port: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemediateCommand {
+    /// Restarts the Remote Desktop service
+    RestartRdp,
+    /// Allows RDP connections and enables the Remote Desktop firewall rules
+    EnableRdp,
+    /// Resets the password of a local user to the one read from the standard input
+    ResetPassword {
+        #[arg(long, default_value = "Administrator")]
+        username: String,
+    },
+    /// Runs a PowerShell script, read from the standard input when left out
+    #[command(name = "powershell")]
+    PowerShell { script: Option<String> },
+}
+
+/**
+ * Runs a command without opening the GUI, returns the exit code
+ */
+pub async fn run(command: CliCommand) -> i32 {
+    #[cfg(windows)]
+    attach_console();
+
+    match command {
+        CliCommand::Sessions => sessions(),
+        CliCommand::Remediate { target, action } => remediate(target, action).await,
+        CliCommand::FleetRun {
+            targets,
+            document,
+            parameters,
+            export,
+        } => fleet_run(targets, document, parameters, export).await,
+        CliCommand::Doctor { port } => doctor(port).await,
+    }
+}
+
+fn find_target(targets: &[TargetConfig], name: &str) -> Option<TargetConfig> {
+    targets
+        .iter()
+        .find(|target| target.name == name || target.instance_id == name)
+        .cloned()
+}
+
+fn sessions() -> i32 {
+    match status::read(Path::new(status::STATUS_FILE)) {
+        Ok(tunnels) if tunnels.is_empty() => {
+            println!("No active tunnel");
+            0
+        }
+        Ok(tunnels) => {
+            println!("{}", status::format_table(&tunnels));
+            0
+        }
+        Err(e) if e.kind == StatusErrorKind::NotRunning => {
+            eprintln!("{}", e.msg);
+            1
+        }
+        Err(e) => {
+            eprintln!("sessions : {}", e.msg);
+            2
+        }
+    }
+}
+
+async fn remediate(target: String, action: RemediateCommand) -> i32 {
+    // Logs are printed as they come, which streams the output of the command
+    let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+    let Some(target) = find_target(&config::load(&logs_sender).targets, &target) else {
+        eprintln!(
+            "remediate : No target named {} in the configuration",
+            target
+        );
+        return 2;
+    };
+    let action = match action {
+        RemediateCommand::RestartRdp => RemediationAction::RestartRdpService,
+        RemediateCommand::EnableRdp => RemediationAction::EnableRdp,
+        RemediateCommand::ResetPassword { username } => {
+            let mut password = String::new();
+            if std::io::stdin().read_line(&mut password).is_err() || password.trim().is_empty() {
+                eprintln!("remediate : No password on the standard input");
+                return 2;
+            }
+            RemediationAction::ResetPassword(RdpCredentials {
+                username,
+                password: password.trim_end_matches(['\r', '\n']).to_string(),
+            })
+        }
+        RemediateCommand::PowerShell {
+            script: Some(script),
+        } => RemediationAction::PowerShell(script),
+        RemediateCommand::PowerShell { script: None } => {
+            let mut script = String::new();
+            if std::io::stdin().read_to_string(&mut script).is_err() {
+                eprintln!("remediate : Unable to read the script from the standard input");
+                return 2;
+            }
+            RemediationAction::PowerShell(script)
+        }
+    };
+
+    let client = ssm::initiate_aws_client(&target).await;
+    match remediation::run(&client, &target.instance_id, &action, &logs_sender).await {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("remediate : {}", e.msg);
+            1
+        }
+    }
+}
+
+async fn fleet_run(
+    names: Vec<String>,
+    document: String,
+    parameters: String,
+    export: Option<PathBuf>,
+) -> i32 {
+    let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+    let command = match FleetCommand::new(&document, &parameters) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("fleet-run : {}", e.msg);
+            return 2;
+        }
+    };
+    let configured = config::load(&logs_sender).targets;
+    let mut targets = vec![];
+    for name in &names {
+        match find_target(&configured, name) {
+            Some(target) => targets.push(target),
+            None => {
+                eprintln!("fleet-run : No target named {} in the configuration", name);
+                return 2;
+            }
+        }
+    }
+    if names.is_empty() {
+        targets = configured;
+    }
+
+    let (results_sender, results_receiver) = std::sync::mpsc::channel();
+    fleet::run(targets, command, results_sender, logs_sender).await;
+    let mut results: Vec<_> = results_receiver.try_iter().collect();
+    fleet::sort(&mut results, FleetColumn::Target, false);
+
+    let header = ["TARGET", "INSTANCE", "STATUS", "CODE", "OUTPUT"].map(String::from);
+    let rows = results.iter().map(|result| {
+        let output = match result.status.as_str() {
+            "Success" => &result.stdout,
+            _ => &result.stderr,
+        };
+        [
+            result.target.clone(),
+            result.instance_id.clone(),
+            result.status.clone(),
+            result
+                .response_code
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "-".into()),
+            output.lines().next().unwrap_or_default().to_string(),
+        ]
+    });
+    println!(
+        "{}",
+        status::align_columns(std::iter::once(header).chain(rows).collect())
+    );
+
+    if let Some(path) = export {
+        let exported = match path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            true => fleet::export_json(&path, &results),
+            false => fleet::export_csv(&path, &results),
+        };
+        if let Err(e) = exported {
+            eprintln!("fleet-run : {}", e.msg);
+            return 2;
+        }
+    }
+    match results.iter().all(|result| result.status == "Success") {
+        true => 0,
+        false => 1,
+    }
+}
+
+async fn doctor(port: String) -> i32 {
+    let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+    let checks = doctor::run(&port, &logs_sender).await;
+    print!("{}", doctor::format_report(&checks));
+    match checks.iter().any(|check| check.status == CheckStatus::Fail) {
+        true => 1,
+        false => 0,
+    }
+}
+
+/**
+ * The executable is built for the windows subsystem, without it nothing printed
+ * reaches the terminal it was started from
+ */
+#[cfg(windows)]
+fn attach_console() {
+    unsafe extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
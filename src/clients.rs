@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use crate::config::LauncherConfig;
+
+/// Names of the launchers available to forwards without defining them under `commands.launchers`
+pub const BUILTIN_CLIENTS: [&str; 7] = ["ssh", "putty", "vnc", "http", "https", "psql", "sqlcmd"];
+
+#[cfg(not(windows))]
+const TERMINAL: &str = "x-terminal-emulator";
+#[cfg(not(windows))]
+const URL_OPENER: &str = "xdg-open";
+
+/**
+ * Launcher of a built-in client, with `{username}` passed only when the forward has one
+ */
+pub fn builtin_launcher(name: &str, username: bool) -> Option<LauncherConfig> {
+    let user_args = |flag: &str| {
+        if username {
+            vec![flag.to_string(), "{username}".to_string()]
+        } else {
+            vec![]
+        }
+    };
+    Some(match name {
+        "ssh" => in_terminal(
+            "ssh",
+            [
+                vec!["-p".into(), "{port}".into()],
+                user_args("-l"),
+                vec!["{host}".into()],
+            ]
+            .concat(),
+        ),
+        "putty" => launcher(
+            "putty",
+            [
+                vec!["-ssh".into(), "-P".into(), "{port}".into()],
+                user_args("-l"),
+                vec!["{host}".into()],
+            ]
+            .concat(),
+        ),
+        "vnc" => launcher("vncviewer", vec!["{host}::{port}".into()]),
+        "http" | "https" => browser(&format!("{}://{{host}}:{{port}}/", name)),
+        "psql" => in_terminal(
+            "psql",
+            [
+                vec!["-h".into(), "{host}".into(), "-p".into(), "{port}".into()],
+                user_args("-U"),
+            ]
+            .concat(),
+        ),
+        "sqlcmd" => in_terminal(
+            "sqlcmd",
+            [
+                vec!["-S".into(), "tcp:{host},{port}".into()],
+                user_args("-U"),
+            ]
+            .concat(),
+        ),
+        _ => return None,
+    })
+}
+
+fn launcher(command: &str, args: Vec<String>) -> LauncherConfig {
+    LauncherConfig {
+        command: command.into(),
+        args,
+        env: HashMap::new(),
+        wait: true,
+    }
+}
+
+/**
+ * Console clients get a window of their own, the application having none. Outside of Windows
+ * the terminal may hand the window over to a server process and return at once, the tunnel
+ * then stays up until stopped
+ */
+fn in_terminal(command: &str, args: Vec<String>) -> LauncherConfig {
+    #[cfg(windows)]
+    let prefix = ["/c", "start", "", "/wait", command];
+    #[cfg(not(windows))]
+    let prefix = ["-e", command];
+    #[cfg(windows)]
+    let terminal = "cmd";
+    #[cfg(not(windows))]
+    let terminal = TERMINAL;
+    LauncherConfig {
+        wait: cfg!(windows),
+        ..launcher(
+            terminal,
+            prefix
+                .iter()
+                .map(|arg| arg.to_string())
+                .chain(args)
+                .collect(),
+        )
+    }
+}
+
+/**
+ * The browser opens the page in a window it may already have, the tunnel then stays up until stopped
+ */
+fn browser(url: &str) -> LauncherConfig {
+    #[cfg(windows)]
+    let opener = launcher(
+        "cmd",
+        vec!["/c".into(), "start".into(), "".into(), url.into()],
+    );
+    #[cfg(not(windows))]
+    let opener = launcher(URL_OPENER, vec![url.into()]);
+    LauncherConfig {
+        wait: false,
+        ..opener
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_launcher() {
+        for name in BUILTIN_CLIENTS {
+            assert!(builtin_launcher(name, false).is_some(), "{}", name);
+        }
+        assert!(builtin_launcher("telnet", false).is_none());
+
+        let ssh = builtin_launcher("ssh", true).unwrap();
+        assert_eq!(ssh.wait, cfg!(windows));
+        assert!(ssh.args.ends_with(&[
+            "ssh".into(),
+            "-p".into(),
+            "{port}".into(),
+            "-l".into(),
+            "{username}".into(),
+            "{host}".into()
+        ]));
+        let psql = builtin_launcher("psql", false).unwrap();
+        assert!(!psql.args.contains(&"{username}".to_string()));
+
+        let https = builtin_launcher("https", false).unwrap();
+        assert!(!https.wait);
+        assert_eq!(
+            https.args.last().map(String::as_str),
+            Some("https://{host}:{port}/")
+        );
+    }
+}
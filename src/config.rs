@@ -0,0 +1,757 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::clients;
+use crate::proxy;
+use crate::utils::send_log;
+
+pub const CONFIG_FILE: &str = "vm-connect-config.json";
+// Deployed by administrators next to the executable or in the system config directory
+const POLICY_FILE: &str = "vm-connect-policy.json";
+#[cfg(not(windows))]
+const SYSTEM_CONFIG_DIR: &str = "/etc/vm-connect";
+
+const VM_TARGET_1: &str = "i-0f30a1dd89600b0dc";
+const VM_TARGET_2: &str = "i-0a6eb481a98d54b72";
+const VM_TARGET_3: &str = "i-03a933321d29f9f95";
+
+const SESSION_MANAGER_PLUGIN: &str = "session-manager-plugin";
+#[cfg(windows)]
+const RDP_CLIENT: &str = "mstsc";
+#[cfg(not(windows))]
+const RDP_CLIENT: &str = "xfreerdp";
+const TUNNEL_READY_TIMEOUT_SECS: u64 = 30;
+const IDLE_WARNING_SECS: u64 = 60;
+const EXTEND_SECS: u64 = 30 * 60;
+// AWS profile used by targets that do not name one
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_targets")]
+    pub targets: Vec<TargetConfig>,
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    /// Settings shared by the targets using an AWS profile, keyed by profile name
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileConfig>,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub rdp_files: RdpFilesConfig,
+}
+
+/**
+ * Where the .rdp files are looked for, relative directories starting from the working directory
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RdpFilesConfig {
+    pub directories: Vec<String>,
+    /// Also looks into their subdirectories
+    pub recursive: bool,
+}
+
+impl Default for RdpFilesConfig {
+    fn default() -> Self {
+        RdpFilesConfig {
+            directories: vec![".".into()],
+            recursive: false,
+        }
+    }
+}
+
+/**
+ * Limits on how long a connection lasts, under `session` in the config file or in the
+ * policy file, which the config file and the targets can then only make stricter
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Connections are closed after this long, never when unset
+    pub max_session_secs: Option<u64>,
+    /// Time added by each extension
+    pub extend_secs: u64,
+    /// Extensions allowed per connection, unlimited when unset
+    pub max_extensions: Option<u32>,
+    /// Set by the policy file
+    #[serde(skip)]
+    pub locked: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            max_session_secs: None,
+            extend_secs: EXTEND_SECS,
+            max_extensions: None,
+            locked: false,
+        }
+    }
+}
+
+impl SessionConfig {
+    /** Strictest limits of two policies, so that no policy file loosens another */
+    fn strictest(self, other: SessionConfig) -> SessionConfig {
+        SessionConfig {
+            max_session_secs: lowest(self.max_session_secs, other.max_session_secs),
+            extend_secs: self.extend_secs.min(other.extend_secs),
+            max_extensions: lowest(self.max_extensions, other.max_extensions),
+            locked: self.locked || other.locked,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Policy {
+    #[serde(default)]
+    session: SessionConfig,
+}
+
+// The lower of two limits, an unset one being no limit
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub endpoints: EndpointsConfig,
+}
+
+/**
+ * Endpoint URLs replacing the public AWS ones, e.g. interface VPC endpoints without
+ * private DNS or LocalStack. `ssmmessages` replaces the host of the data channel stream url
+ */
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct EndpointsConfig {
+    pub ssm: Option<String>,
+    pub ssmmessages: Option<String>,
+    pub ec2: Option<String>,
+    pub sts: Option<String>,
+    pub secretsmanager: Option<String>,
+}
+
+impl EndpointsConfig {
+    fn or(self, fallback: &EndpointsConfig) -> EndpointsConfig {
+        EndpointsConfig {
+            ssm: self.ssm.or_else(|| fallback.ssm.clone()),
+            ssmmessages: self.ssmmessages.or_else(|| fallback.ssmmessages.clone()),
+            ec2: self.ec2.or_else(|| fallback.ec2.clone()),
+            sts: self.sts.or_else(|| fallback.sts.clone()),
+            secretsmanager: self
+                .secretsmanager
+                .or_else(|| fallback.secretsmanager.clone()),
+        }
+    }
+}
+
+/**
+ * Outbound proxy for the AWS API calls and the data channel, `HTTPS_PROXY`, `NO_PROXY`
+ * and `AWS_CA_BUNDLE` fill in what the file leaves unset
+ */
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// `http://host:port`, used for http and https endpoints alike
+    pub url: Option<String>,
+    /// Comma separated hosts, domains (`.corp.example`) or networks reached without the proxy
+    pub no_proxy: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// PEM file of the CA re-signing the traffic of an inspecting proxy, trusted on top of the system ones
+    pub ca_bundle: Option<String>,
+}
+
+impl ProxyConfig {
+    fn or_env(self, env_var: impl Fn(&str) -> Option<String>) -> ProxyConfig {
+        let first_of = |names: &[&str]| names.iter().find_map(|name| env_var(name));
+        ProxyConfig {
+            url: self
+                .url
+                .or_else(|| first_of(&["HTTPS_PROXY", "https_proxy"])),
+            no_proxy: self
+                .no_proxy
+                .or_else(|| first_of(&["NO_PROXY", "no_proxy"])),
+            username: self.username,
+            password: self.password,
+            ca_bundle: self.ca_bundle.or_else(|| first_of(&["AWS_CA_BUNDLE"])),
+        }
+    }
+}
+
+/**
+ * Programs launched by the application and how long the tunnel they make may stay,
+ * names are looked up in PATH
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CommandsConfig {
+    pub session_manager_plugin: String,
+    pub rdp_client: String,
+    /// How long the plugin has to start listening on the local port
+    pub tunnel_ready_timeout_secs: u64,
+    /// Closes the connection after this long without traffic through the local port, never when unset
+    pub idle_timeout_secs: Option<u64>,
+    /// How long before an idle close the warning is shown
+    pub idle_warning_secs: u64,
+    /// Traffic sent to the plugin after this long without any, against the SSM idle session timeout
+    pub keepalive_interval_secs: Option<u64>,
+    /// How long the RDP service has to answer a Connection Request before the client is launched,
+    /// not probed when unset
+    pub rdp_probe_timeout_secs: Option<u64>,
+    /// Ways to start a client, by name, instead of `rdp_client`
+    pub launchers: HashMap<String, LauncherConfig>,
+    /// Launcher of the targets that do not name one, `rdp_client` when unset
+    pub launcher: Option<String>,
+}
+
+/**
+ * A client started with `command` and `args`, in which `{host}`, `{port}`, `{rdp_file}` and
+ * `{username}` are replaced, as in the values of `env`
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LauncherConfig {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// The connection ends with the process, otherwise it stays up until stopped from the
+    /// application, for clients handing the connection over to an instance already running
+    #[serde(default = "default_wait")]
+    pub wait: bool,
+}
+
+fn default_wait() -> bool {
+    true
+}
+
+impl CommandsConfig {
+    /**
+     * Launcher of the target, none meaning `rdp_client`
+     */
+    pub fn launcher_for(&self, target: &TargetConfig) -> Result<Option<LauncherConfig>, String> {
+        let Some(name) = target.launcher.as_ref().or(self.launcher.as_ref()) else {
+            return Ok(None);
+        };
+        self.launchers
+            .get(name)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| format!("No launcher named {} under commands.launchers", name))
+    }
+
+    /**
+     * Launcher of the forward, those under `launchers` replacing the built-in clients of the same name
+     */
+    pub fn forward_launcher(&self, forward: &ForwardConfig) -> Result<LauncherConfig, String> {
+        self.launchers
+            .get(&forward.launcher)
+            .cloned()
+            .or_else(|| clients::builtin_launcher(&forward.launcher, forward.username.is_some()))
+            .ok_or_else(|| {
+                format!(
+                    "No launcher named {} under commands.launchers nor among the built-in clients",
+                    forward.launcher
+                )
+            })
+    }
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        CommandsConfig {
+            session_manager_plugin: SESSION_MANAGER_PLUGIN.into(),
+            rdp_client: RDP_CLIENT.into(),
+            tunnel_ready_timeout_secs: TUNNEL_READY_TIMEOUT_SECS,
+            idle_timeout_secs: None,
+            idle_warning_secs: IDLE_WARNING_SECS,
+            keepalive_interval_secs: None,
+            rdp_probe_timeout_secs: None,
+            launchers: HashMap::new(),
+            launcher: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct TargetConfig {
+    pub name: String,
+    pub instance_id: String,
+    /// Region of the instance, falls back to `--region` then to the default provider chain
+    #[serde(default)]
+    pub region: Option<String>,
+    /// PEM file of the key pair the instance was launched with, used to decrypt its Windows password
+    #[serde(default)]
+    pub key_pair_file: Option<String>,
+    /// Secret holding the RDP credentials, fetched at connect time
+    #[serde(default)]
+    pub credentials_source: Option<CredentialsSource>,
+    /// AWS named profile for credentials and region, the default chain otherwise
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Overrides the endpoints of its profile
+    #[serde(default)]
+    pub endpoints: EndpointsConfig,
+    /// Copied from the top-level proxy settings
+    #[serde(skip)]
+    pub proxy: ProxyConfig,
+    /// Overrides the top-level `max_session_secs` of `session`
+    #[serde(default)]
+    pub max_session_secs: Option<u64>,
+    /// Overrides `launcher` of `commands`
+    #[serde(default)]
+    pub launcher: Option<String>,
+    /// Ports of the instance other than RDP, opened with a client of their own
+    #[serde(default)]
+    pub forwards: Vec<ForwardConfig>,
+    /// Session limits of this target once the policy is applied
+    #[serde(skip)]
+    pub session: SessionConfig,
+}
+
+/**
+ * Either `{ "secrets_manager": "<secret ARN or name>" }` or `{ "parameter": "<SecureString name>" }`,
+ * the value being a password or a JSON object with `username` and `password`
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialsSource {
+    SecretsManager(String),
+    Parameter(String),
+}
+
+/**
+ * A port of the instance forwarded to the local port, `launcher` being a built-in client
+ * (ssh, putty, vnc, http, https, psql, sqlcmd) or a name under `commands.launchers`
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ForwardConfig {
+    pub name: String,
+    pub remote_port: u16,
+    pub launcher: String,
+    /// Replaces `{username}`, left out of the built-in clients when unset
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+impl TargetConfig {
+    pub fn label(&self) -> String {
+        match &self.region {
+            Some(region) => format!("{} ({})", self.name, region),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            targets: default_targets(),
+            commands: CommandsConfig::default(),
+            profiles: HashMap::new(),
+            proxy: ProxyConfig::default(),
+            session: SessionConfig::default(),
+            rdp_files: RdpFilesConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /**
+     * Fills in the endpoints each target leaves unset from its profile, so that a target
+     * carries everything needed to build its clients
+     */
+    fn with_profile_endpoints(mut self) -> Config {
+        for target in &mut self.targets {
+            let profile = target.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+            if let Some(profile_config) = self.profiles.get(profile) {
+                target.endpoints = target.endpoints.clone().or(&profile_config.endpoints);
+            }
+        }
+        self
+    }
+
+    /**
+     * Completes the proxy settings from the environment and hands them to every target,
+     * settings the client cannot be built from are dropped
+     */
+    fn with_proxy(
+        mut self,
+        env_var: impl Fn(&str) -> Option<String>,
+        logs_sender: &std::sync::mpsc::Sender<String>,
+    ) -> Config {
+        self.proxy = self.proxy.or_env(env_var);
+        if let Err(e) = proxy::http_client(&self.proxy) {
+            send_log(
+                format!("Config : invalid proxy settings, ignored : {}", e.msg),
+                logs_sender,
+            );
+            self.proxy = ProxyConfig::default();
+        }
+        for target in &mut self.targets {
+            target.proxy = self.proxy.clone();
+        }
+        self
+    }
+
+    /**
+     * Resolves the session limits of every target, the policy replacing the extension
+     * settings and capping the maximum duration
+     */
+    fn with_session_policy(mut self, policy: Option<&SessionConfig>) -> Config {
+        for target in &mut self.targets {
+            let mut session = SessionConfig {
+                max_session_secs: target.max_session_secs.or(self.session.max_session_secs),
+                ..self.session.clone()
+            };
+            if let Some(policy) = policy {
+                session = SessionConfig {
+                    max_session_secs: lowest(session.max_session_secs, policy.max_session_secs),
+                    locked: true,
+                    ..policy.clone()
+                };
+            }
+            target.session = session;
+        }
+        self
+    }
+}
+
+// System locations first, only administrators write there
+fn policy_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    #[cfg(windows)]
+    if let Some(program_data) = env_var("ProgramData") {
+        paths.push(
+            PathBuf::from(program_data)
+                .join("vm-connect")
+                .join(POLICY_FILE),
+        );
+    }
+    #[cfg(not(windows))]
+    paths.push(PathBuf::from(SYSTEM_CONFIG_DIR).join(POLICY_FILE));
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+    {
+        paths.push(dir.join(POLICY_FILE));
+    }
+    paths
+}
+
+/**
+ * Policy files found merged into their strictest limits, as the one next to the
+ * executable may be writable by users. An invalid one is reported and skipped
+ */
+fn load_policy(logs_sender: &std::sync::mpsc::Sender<String>) -> Option<SessionConfig> {
+    policy_paths()
+        .into_iter()
+        .filter_map(|path| {
+            let content = fs::read_to_string(&path).ok()?;
+            match serde_json::from_str::<Policy>(&content) {
+                Ok(policy) => {
+                    send_log(
+                        format!("Config : Session policy read from {}", path.display()),
+                        logs_sender,
+                    );
+                    Some(policy.session)
+                }
+                Err(e) => {
+                    send_log(
+                        format!("Config : invalid {}, ignored : {}", path.display(), e),
+                        logs_sender,
+                    );
+                    None
+                }
+            }
+        })
+        .reduce(SessionConfig::strictest)
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn default_targets() -> Vec<TargetConfig> {
+    [("VM 1", VM_TARGET_1), ("VM 2", VM_TARGET_2), ("VM 3", VM_TARGET_3)]
+        .into_iter()
+        .map(|(name, instance_id)| TargetConfig {
+            name: name.into(),
+            instance_id: instance_id.into(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/**
+ * Reads the config file from the working directory, a missing or invalid file
+ * falls back to the default targets
+ */
+pub fn load(logs_sender: &std::sync::mpsc::Sender<String>) -> Config {
+    let policy = load_policy(logs_sender);
+    let content = match fs::read_to_string(CONFIG_FILE) {
+        Ok(content) => content,
+        Err(_) => {
+            send_log(
+                format!("Config : {} not found, using default targets", CONFIG_FILE),
+                logs_sender,
+            );
+            return Config::default()
+                .with_proxy(env_var, logs_sender)
+                .with_session_policy(policy.as_ref());
+        }
+    };
+
+    match serde_json::from_str::<Config>(&content) {
+        Ok(config) => config
+            .with_profile_endpoints()
+            .with_proxy(env_var, logs_sender)
+            .with_session_policy(policy.as_ref()),
+        Err(e) => {
+            send_log(
+                format!("Config : invalid {}, using default targets : {}", CONFIG_FILE, e),
+                logs_sender,
+            );
+            Config::default()
+                .with_proxy(env_var, logs_sender)
+                .with_session_policy(policy.as_ref())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_endpoints() {
+        let config = serde_json::from_str::<Config>(
+            r#"{
+                "targets": [
+                    { "name": "VPC", "instance_id": "i-1", "profile": "vpc", "endpoints": { "ec2": "https://ec2.vpce" } },
+                    { "name": "Default", "instance_id": "i-2" },
+                    { "name": "Other", "instance_id": "i-3", "profile": "other" }
+                ],
+                "profiles": {
+                    "vpc": { "endpoints": { "ssm": "https://ssm.vpce", "ec2": "https://ec2.profile" } },
+                    "default": { "endpoints": { "ssm": "http://localhost:4566" } }
+                }
+            }"#,
+        )
+        .unwrap()
+        .with_profile_endpoints();
+
+        assert_eq!(
+            config.targets[0].endpoints.ssm.as_deref(),
+            Some("https://ssm.vpce")
+        );
+        assert_eq!(
+            config.targets[0].endpoints.ec2.as_deref(),
+            Some("https://ec2.vpce"),
+            "The endpoint of the target should win over the one of its profile"
+        );
+        assert_eq!(
+            config.targets[1].endpoints.ssm.as_deref(),
+            Some("http://localhost:4566")
+        );
+        assert_eq!(config.targets[2].endpoints, EndpointsConfig::default());
+    }
+
+    #[test]
+    fn test_proxy_from_env() {
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let env = |name: &str| match name {
+            "HTTPS_PROXY" => Some("http://env-proxy:3128".to_string()),
+            "https_proxy" => Some("http://ignored:3128".to_string()),
+            "NO_PROXY" => Some(".corp.example,10.0.0.0/8".to_string()),
+            _ => None,
+        };
+
+        let config = Config::default().with_proxy(env, &logs_sender);
+        assert_eq!(config.proxy.url.as_deref(), Some("http://env-proxy:3128"));
+        assert_eq!(
+            config.proxy.no_proxy.as_deref(),
+            Some(".corp.example,10.0.0.0/8")
+        );
+        assert!(
+            config
+                .targets
+                .iter()
+                .all(|target| target.proxy == config.proxy)
+        );
+
+        let config = serde_json::from_str::<Config>(
+            r#"{ "proxy": { "url": "http://file-proxy:8080", "username": "user", "password": "secret" } }"#,
+        )
+        .unwrap()
+        .with_proxy(env, &logs_sender);
+        assert_eq!(
+            config.proxy.url.as_deref(),
+            Some("http://file-proxy:8080"),
+            "The file should win over the environment"
+        );
+        assert_eq!(config.proxy.username.as_deref(), Some("user"));
+        assert_eq!(
+            config.proxy.no_proxy.as_deref(),
+            Some(".corp.example,10.0.0.0/8")
+        );
+    }
+
+    #[test]
+    fn test_session_policy() {
+        let config = serde_json::from_str::<Config>(
+            r#"{
+                "targets": [
+                    { "name": "Short", "instance_id": "i-1", "max_session_secs": 1800 },
+                    { "name": "Long", "instance_id": "i-2", "max_session_secs": 86400 },
+                    { "name": "Default", "instance_id": "i-3" }
+                ],
+                "session": { "max_session_secs": 7200, "extend_secs": 3600 }
+            }"#,
+        )
+        .unwrap();
+
+        let unlocked = config.clone().with_session_policy(None);
+        let limits: Vec<Option<u64>> = unlocked
+            .targets
+            .iter()
+            .map(|target| target.session.max_session_secs)
+            .collect();
+        assert_eq!(limits, vec![Some(1800), Some(86400), Some(7200)]);
+        assert_eq!(unlocked.targets[0].session.extend_secs, 3600);
+        assert!(!unlocked.targets[0].session.locked);
+
+        let policy = serde_json::from_str::<Policy>(
+            r#"{ "session": { "max_session_secs": 14400, "max_extensions": 0 } }"#,
+        )
+        .unwrap()
+        .session;
+        let locked = config.with_session_policy(Some(&policy));
+        let limits: Vec<Option<u64>> = locked
+            .targets
+            .iter()
+            .map(|target| target.session.max_session_secs)
+            .collect();
+        assert_eq!(
+            limits,
+            vec![Some(1800), Some(14400), Some(7200)],
+            "The policy should only shorten the limits of the config file"
+        );
+        assert!(locked.targets.iter().all(|target| target.session.locked
+            && target.session.max_extensions == Some(0)
+            && target.session.extend_secs == EXTEND_SECS));
+    }
+
+    #[test]
+    fn test_policies_strictest() {
+        let policies = [
+            r#"{ "session": { "max_session_secs": 14400, "extend_secs": 1800 } }"#,
+            r#"{ "session": { "max_session_secs": 28800, "max_extensions": 2 } }"#,
+        ]
+        .map(|content| serde_json::from_str::<Policy>(content).unwrap().session);
+
+        for [first, second] in [policies.clone(), [policies[1].clone(), policies[0].clone()]] {
+            assert_eq!(
+                first.strictest(second),
+                SessionConfig {
+                    max_session_secs: Some(14400),
+                    extend_secs: 1800,
+                    max_extensions: Some(2),
+                    locked: false,
+                },
+                "A policy file should not loosen another, whatever the order"
+            );
+        }
+    }
+
+    #[test]
+    fn test_launcher_for() {
+        let config = serde_json::from_str::<Config>(
+            r#"{
+                "targets": [
+                    { "name": "Default", "instance_id": "i-1" },
+                    { "name": "Remmina", "instance_id": "i-2", "launcher": "remmina" },
+                    { "name": "Unknown", "instance_id": "i-3", "launcher": "royal-ts" }
+                ],
+                "commands": {
+                    "launcher": "xfreerdp",
+                    "launchers": {
+                        "xfreerdp": { "command": "xfreerdp", "args": ["/v:{host}:{port}", "/u:{username}", "/dynamic-resolution"] },
+                        "remmina": { "command": "remmina", "args": ["-c", "rdp://{username}@{host}:{port}"], "wait": false }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let commands = &config.commands;
+
+        let launcher = commands.launcher_for(&config.targets[0]).unwrap().unwrap();
+        assert_eq!(launcher.command, "xfreerdp");
+        assert!(launcher.wait);
+        let launcher = commands.launcher_for(&config.targets[1]).unwrap().unwrap();
+        assert_eq!(launcher.command, "remmina");
+        assert!(!launcher.wait);
+        assert!(commands.launcher_for(&config.targets[2]).is_err());
+        assert_eq!(
+            CommandsConfig::default().launcher_for(&config.targets[0]),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_forward_launcher() {
+        let config = serde_json::from_str::<Config>(
+            r#"{
+                "targets": [{
+                    "name": "Linux",
+                    "instance_id": "i-1",
+                    "forwards": [
+                        { "name": "SSH", "remote_port": 22, "launcher": "ssh", "username": "ec2-user" },
+                        { "name": "Grafana", "remote_port": 3000, "launcher": "http" },
+                        { "name": "Telnet", "remote_port": 23, "launcher": "telnet" }
+                    ]
+                }],
+                "commands": {
+                    "launchers": { "http": { "command": "firefox", "args": ["http://{host}:{port}"] } }
+                }
+            }"#,
+        )
+        .unwrap();
+        let forwards = &config.targets[0].forwards;
+        assert_eq!(forwards[0].remote_port, 22);
+        let ssh = config.commands.forward_launcher(&forwards[0]).unwrap();
+        assert!(ssh.args.contains(&"{username}".to_string()));
+        let http = config.commands.forward_launcher(&forwards[1]).unwrap();
+        assert_eq!(http.command, "firefox");
+        assert!(http.wait);
+        assert!(config.commands.forward_launcher(&forwards[2]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_proxy_ignored() {
+        let (logs_sender, logs_receiver) = std::sync::mpsc::channel();
+        let config = serde_json::from_str::<Config>(
+            r#"{ "proxy": { "url": "http://proxy:3128", "ca_bundle": "/nonexistent/ca.pem" } }"#,
+        )
+        .unwrap()
+        .with_proxy(|_| None, &logs_sender);
+
+        assert_eq!(config.proxy, ProxyConfig::default());
+        assert_eq!(config.targets[0].proxy, ProxyConfig::default());
+        assert!(
+            logs_receiver
+                .try_iter()
+                .any(|log| log.contains("invalid proxy settings"))
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use keyring::Entry;
+use serde::Deserialize;
+
+use crate::config::{CredentialsSource, TargetConfig};
+use crate::rdp::RdpCredentials;
+use crate::ssm::{initiate_aws_client, load_aws_config};
+use crate::utils::send_log;
+
+pub const CREDENTIALS_SERVICE: &str = "vm-connect";
+const DEFAULT_USERNAME: &str = "Administrator";
+
+#[derive(Debug)]
+pub struct CredentialsError {
+    pub kind: CredentialsErrorKind,
+    pub msg: String,
+}
+
+#[derive(Debug)]
+pub enum CredentialsErrorKind {
+    Keyring,
+    Serde,
+    Api,
+    NotFound,
+}
+
+#[derive(Deserialize)]
+struct SecretCredentials {
+    #[serde(alias = "Username")]
+    username: Option<String>,
+    #[serde(alias = "Password")]
+    password: String,
+}
+
+/**
+ * One entry per target in the platform secret store (Credential Manager on Windows,
+ * Secret Service on Linux), holding the username and password as JSON
+ */
+fn entry(target: &TargetConfig) -> Result<Entry, CredentialsError> {
+    Entry::new(CREDENTIALS_SERVICE, &target.instance_id).map_err(|e| CredentialsError {
+        kind: CredentialsErrorKind::Keyring,
+        msg: format!("credentials : Unable to open keyring entry : {}", e),
+    })
+}
+
+pub fn load(target: &TargetConfig) -> Result<Option<RdpCredentials>, CredentialsError> {
+    match entry(target)?.get_password() {
+        Ok(secret) => serde_json::from_str(&secret)
+            .map(Some)
+            .map_err(|_| CredentialsError {
+                kind: CredentialsErrorKind::Serde,
+                msg: "credentials : Stored credentials are invalid".into(),
+            }),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(CredentialsError {
+            kind: CredentialsErrorKind::Keyring,
+            msg: format!("credentials : Unable to read stored credentials : {}", e),
+        }),
+    }
+}
+
+pub fn store(target: &TargetConfig, credentials: &RdpCredentials) -> Result<(), CredentialsError> {
+    let secret = serde_json::to_string(credentials).map_err(|_| CredentialsError {
+        kind: CredentialsErrorKind::Serde,
+        msg: "credentials : Unable to serialize credentials".into(),
+    })?;
+
+    entry(target)?
+        .set_password(&secret)
+        .map_err(|e| CredentialsError {
+            kind: CredentialsErrorKind::Keyring,
+            msg: format!("credentials : Unable to store credentials : {}", e),
+        })
+}
+
+pub fn delete(target: &TargetConfig) -> Result<(), CredentialsError> {
+    match entry(target)?.delete_credential() {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(CredentialsError {
+            kind: CredentialsErrorKind::Keyring,
+            msg: format!("credentials : Unable to delete stored credentials : {}", e),
+        }),
+    }
+}
+
+/**
+ * Fetches the credentials referenced by the target with the same AWS configuration as the tunnel,
+ * the secret is kept in memory only
+ */
+pub async fn fetch(
+    target: &TargetConfig,
+    source: &CredentialsSource,
+    fallback: Option<&RdpCredentials>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<RdpCredentials, CredentialsError> {
+    let secret = match source {
+        CredentialsSource::SecretsManager(secret_id) => {
+            send_log(
+                format!("credentials : Fetch secret {}...", secret_id),
+                logs_sender,
+            );
+            let shared_config = load_aws_config(target).await;
+            let mut config = aws_sdk_secretsmanager::config::Builder::from(&shared_config);
+            if let Some(endpoint_url) = &target.endpoints.secretsmanager {
+                config = config.endpoint_url(endpoint_url);
+            }
+            aws_sdk_secretsmanager::Client::from_conf(config.build())
+                .get_secret_value()
+                .secret_id(secret_id)
+                .send()
+                .await
+                .map_err(|e| CredentialsError {
+                    kind: CredentialsErrorKind::Api,
+                    msg: format!("credentials : GetSecretValue failed : {}", e),
+                })?
+                .secret_string()
+                .map(|secret| secret.to_string())
+        }
+        CredentialsSource::Parameter(name) => {
+            send_log(
+                format!("credentials : Fetch parameter {}...", name),
+                logs_sender,
+            );
+            initiate_aws_client(target)
+                .await
+                .get_parameter()
+                .name(name)
+                .with_decryption(true)
+                .send()
+                .await
+                .map_err(|e| CredentialsError {
+                    kind: CredentialsErrorKind::Api,
+                    msg: format!("credentials : GetParameter failed : {}", e),
+                })?
+                .parameter()
+                .and_then(|parameter| parameter.value())
+                .map(|value| value.to_string())
+        }
+    };
+
+    let secret = secret.ok_or_else(|| CredentialsError {
+        kind: CredentialsErrorKind::NotFound,
+        msg: "credentials : Secret has no string value".into(),
+    })?;
+    send_log("credentials : Secret fetched".into(), logs_sender);
+
+    let default_username = fallback
+        .map(|credentials| credentials.username.as_str())
+        .filter(|username| !username.is_empty())
+        .unwrap_or(DEFAULT_USERNAME);
+    Ok(parse_secret(&secret, default_username))
+}
+
+fn parse_secret(secret: &str, default_username: &str) -> RdpCredentials {
+    match serde_json::from_str::<SecretCredentials>(secret) {
+        Ok(parsed) => RdpCredentials {
+            username: parsed
+                .username
+                .unwrap_or_else(|| default_username.to_string()),
+            password: parsed.password,
+        },
+        Err(_) => RdpCredentials {
+            username: default_username.to_string(),
+            password: secret.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_secret() {
+        let json = parse_secret(r#"{"username":"admin","password":"s3cret"}"#, "Administrator");
+        assert_eq!(json.username, "admin");
+        assert_eq!(json.password, "s3cret");
+
+        let password_only = parse_secret(r#"{"Password":"s3cret"}"#, "Administrator");
+        assert_eq!(password_only.username, "Administrator");
+        assert_eq!(password_only.password, "s3cret");
+
+        let plain = parse_secret("s3cret", "Administrator");
+        assert_eq!(plain.username, "Administrator");
+        assert_eq!(plain.password, "s3cret");
+    }
+}
@@ -0,0 +1,574 @@
+use aws_sdk_ssm::Client;
+use aws_sdk_ssm::types::{InstanceInformationStringFilter, PingStatus};
+use std::collections::HashSet;
+use std::fs;
+use std::net::TcpListener;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tokio::task::JoinSet;
+
+use crate::clients;
+use crate::config::{self, Config, RdpFilesConfig, TargetConfig};
+use crate::rdp_files;
+use crate::ssm;
+#[cfg(windows)]
+use crate::utils::CREATE_NO_WINDOW;
+use crate::utils::send_log;
+
+const PLUGIN_INSTALL_URL: &str = "https://docs.aws.amazon.com/systems-manager/latest/userguide/session-manager-working-with-install-plugin.html";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but probably not the way it was meant to
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        }
+    }
+}
+
+/**
+ * Outcome of one prerequisite, with how to fix it when it did not pass
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &str, detail: String) -> Check {
+        Check {
+            name: name.into(),
+            status: CheckStatus::Pass,
+            detail,
+            fix: None,
+        }
+    }
+
+    fn warn(name: &str, detail: String, fix: String) -> Check {
+        Check {
+            name: name.into(),
+            status: CheckStatus::Warn,
+            detail,
+            fix: Some(fix),
+        }
+    }
+
+    fn fail(name: &str, detail: String, fix: String) -> Check {
+        Check {
+            name: name.into(),
+            status: CheckStatus::Fail,
+            detail,
+            fix: Some(fix),
+        }
+    }
+}
+
+/**
+ * Checks everything a connection needs, from the working directory, the AWS calls last.
+ * `local_port` is the one the next tunnel would listen on
+ */
+pub async fn run(local_port: &str, logs_sender: &std::sync::mpsc::Sender<String>) -> Vec<Check> {
+    send_log("Doctor : Checking the environment...".into(), logs_sender);
+    let config = config::load(logs_sender);
+    let mut checks = vec![
+        check_config(fs::read_to_string(config::CONFIG_FILE).ok().as_deref()),
+        check_session_manager_plugin(&config.commands.session_manager_plugin),
+        check_rdp_client(&rdp_client(&config)),
+        check_rdp_files(&config.rdp_files, logs_sender),
+        check_local_port(local_port),
+    ];
+    checks.extend(check_identities(&config.targets).await);
+    checks.extend(check_targets(&config.targets).await);
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Fail)
+        .count();
+    send_log(
+        format!("Doctor : {} checks, {} failed", checks.len(), failed),
+        logs_sender,
+    );
+    checks
+}
+
+// Command of the default launcher, as it is before expansion
+fn rdp_client(config: &Config) -> String {
+    match config.commands.launcher_for(&TargetConfig::default()) {
+        Ok(Some(launcher)) => launcher.command,
+        _ => config.commands.rdp_client.clone(),
+    }
+}
+
+pub fn format_report(checks: &[Check]) -> String {
+    let mut report = String::new();
+    for check in checks {
+        report.push_str(&format!(
+            "[{}] {} : {}\n",
+            check.status.label(),
+            check.name,
+            check.detail
+        ));
+        if let Some(fix) = &check.fix {
+            report.push_str(&format!("       Fix : {}\n", fix));
+        }
+    }
+    report
+}
+
+/**
+ * `content` is that of the config file, missing when it could not be read
+ */
+fn check_config(content: Option<&str>) -> Check {
+    const NAME: &str = "Configuration";
+    let Some(content) = content else {
+        return Check::warn(
+            NAME,
+            format!(
+                "{} not found, the default targets are used",
+                config::CONFIG_FILE
+            ),
+            format!(
+                "Write your targets to {} in the working directory",
+                config::CONFIG_FILE
+            ),
+        );
+    };
+    let config = match serde_json::from_str::<Config>(content) {
+        Ok(config) => config,
+        Err(e) => {
+            return Check::fail(
+                NAME,
+                format!("Invalid {} : {}", config::CONFIG_FILE, e),
+                format!(
+                    "Fix {}, the default targets are used meanwhile",
+                    config::CONFIG_FILE
+                ),
+            );
+        }
+    };
+
+    if config.targets.is_empty() {
+        return Check::fail(
+            NAME,
+            "No target".into(),
+            format!("Add targets to {}", config::CONFIG_FILE),
+        );
+    }
+    let invalid: Vec<&str> = config
+        .targets
+        .iter()
+        .filter(|target| !is_managed_instance_id(&target.instance_id))
+        .map(|target| target.instance_id.as_str())
+        .collect();
+    if !invalid.is_empty() {
+        return Check::fail(
+            NAME,
+            format!("Invalid instance IDs {}", invalid.join(", ")),
+            "Instance IDs look like i-0123456789abcdef0, or mi-0123456789abcdef0 for hybrid nodes"
+                .into(),
+        );
+    }
+    let unknown_launchers: Vec<String> = config
+        .targets
+        .iter()
+        .filter_map(|target| config.commands.launcher_for(target).err())
+        .collect();
+    if let Some(msg) = unknown_launchers.first() {
+        return Check::fail(
+            NAME,
+            msg.clone(),
+            "Name a launcher defined under commands.launchers, or none to use commands.rdp_client"
+                .into(),
+        );
+    }
+    let unknown_forward_launchers: Vec<String> = config
+        .targets
+        .iter()
+        .flat_map(|target| &target.forwards)
+        .filter_map(|forward| config.commands.forward_launcher(forward).err())
+        .collect();
+    if let Some(msg) = unknown_forward_launchers.first() {
+        return Check::fail(
+            NAME,
+            msg.clone(),
+            format!(
+                "Name a built-in client ({}) or a launcher defined under commands.launchers",
+                clients::BUILTIN_CLIENTS.join(", ")
+            ),
+        );
+    }
+    let mut names = HashSet::new();
+    let duplicates: Vec<&str> = config
+        .targets
+        .iter()
+        .filter(|target| !names.insert(target.name.as_str()))
+        .map(|target| target.name.as_str())
+        .collect();
+    if !duplicates.is_empty() {
+        return Check::warn(
+            NAME,
+            format!("Several targets named {}", duplicates.join(", ")),
+            "Give each target its own name, the command line picks the first one".into(),
+        );
+    }
+    Check::pass(NAME, format!("{} targets", config.targets.len()))
+}
+
+fn is_managed_instance_id(instance_id: &str) -> bool {
+    let id = instance_id
+        .strip_prefix("i-")
+        .or_else(|| instance_id.strip_prefix("mi-"))
+        .unwrap_or_default();
+    matches!(id.len(), 8 | 17) && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn check_session_manager_plugin(command: &str) -> Check {
+    const NAME: &str = "Session Manager plugin";
+    let fix = format!(
+        "Install it from {}, or set commands.session_manager_plugin to its path in {}",
+        PLUGIN_INSTALL_URL,
+        config::CONFIG_FILE
+    );
+    let mut plugin = Command::new(command);
+    plugin.arg("--version");
+    #[cfg(windows)]
+    plugin.creation_flags(CREATE_NO_WINDOW);
+    match plugin.output() {
+        Ok(output) if output.status.success() => Check::pass(
+            NAME,
+            format!(
+                "{} version {}",
+                command,
+                String::from_utf8_lossy(&output.stdout).trim()
+            ),
+        ),
+        Ok(output) => Check::warn(
+            NAME,
+            format!(
+                "{} --version exited with {} : {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            fix,
+        ),
+        Err(e) => Check::fail(NAME, format!("Unable to run {} : {}", command, e), fix),
+    }
+}
+
+/**
+ * Only looks the client up, mstsc has no option to print its version and exit
+ */
+fn check_rdp_client(command: &str) -> Check {
+    const NAME: &str = "RDP client";
+    match find_executable(command) {
+        Some(path) => Check::pass(NAME, format!("{} found at {}", command, path.display())),
+        None => Check::fail(
+            NAME,
+            format!("{} not found", command),
+            match cfg!(windows) {
+                true => format!(
+                    "mstsc ships with Windows, set commands.rdp_client to its path in {}",
+                    config::CONFIG_FILE
+                ),
+                false => format!(
+                    "Install FreeRDP, e.g. the freerdp2-x11 package, or set commands.rdp_client to its path in {}",
+                    config::CONFIG_FILE
+                ),
+            },
+        ),
+    }
+}
+
+fn find_executable(command: &str) -> Option<PathBuf> {
+    let path = Path::new(command);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let names = [
+        command.to_string(),
+        format!("{}{}", command, std::env::consts::EXE_SUFFIX),
+    ];
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+}
+
+fn check_rdp_files(
+    rdp_files: &RdpFilesConfig,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Check {
+    const NAME: &str = "RDP files";
+    let directories = rdp_files
+        .directories
+        .iter()
+        .map(|dir| display_dir(Path::new(dir)))
+        .collect::<Vec<String>>()
+        .join(", ");
+    match rdp_files::find(rdp_files, logs_sender).len() {
+        0 => Check::fail(
+            NAME,
+            format!("No .rdp file in {}", directories),
+            "Save a connection to localhost:<local port> from the Remote Desktop client, next to vm-connect or in one of rdp_files.directories"
+                .into(),
+        ),
+        count => Check::pass(NAME, format!("{} in {}", count, directories)),
+    }
+}
+
+fn display_dir(dir: &Path) -> String {
+    fs::canonicalize(dir)
+        .unwrap_or_else(|_| dir.to_path_buf())
+        .display()
+        .to_string()
+}
+
+fn check_local_port(local_port: &str) -> Check {
+    const NAME: &str = "Local port";
+    let Ok(port) = local_port.trim().parse::<u16>() else {
+        return Check::fail(
+            NAME,
+            format!("Invalid local port {}", local_port),
+            "Choose a port between 1024 and 65535".into(),
+        );
+    };
+    match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(_) => Check::pass(NAME, format!("{} is free", port)),
+        Err(e) => Check::fail(
+            NAME,
+            format!("{} is not available : {}", port, e),
+            "Close the tunnel or application using it, or choose another local port".into(),
+        ),
+    }
+}
+
+/**
+ * Checks the credentials of each AWS profile in use once, through its first target
+ */
+async fn check_identities(targets: &[TargetConfig]) -> Vec<Check> {
+    let mut profiles = HashSet::new();
+    let mut checks = vec![];
+    for target in targets {
+        let profile = target.profile.as_deref().unwrap_or(config::DEFAULT_PROFILE);
+        if !profiles.insert(profile) {
+            continue;
+        }
+        let name = format!("AWS credentials ({})", profile);
+        checks.push(match ssm::get_caller_identity(target).await {
+            Ok(arn) => Check::pass(&name, arn),
+            Err(e) => Check::fail(
+                &name,
+                e.msg,
+                format!(
+                    "Renew the credentials of profile {}, e.g. aws sso login --profile {}, or check ~/.aws/credentials",
+                    profile, profile
+                ),
+            ),
+        });
+    }
+    checks
+}
+
+/**
+ * Checks that SSM sees each target online, all at once, each through the client of its
+ * own profile and region
+ */
+async fn check_targets(targets: &[TargetConfig]) -> Vec<Check> {
+    let mut requests = JoinSet::new();
+    for (index, target) in targets.iter().cloned().enumerate() {
+        requests.spawn(async move {
+            let client = ssm::initiate_aws_client(&target).await;
+            (index, check_target(&client, &target).await)
+        });
+    }
+    let mut checks = requests.join_all().await;
+    checks.sort_by_key(|(index, _)| *index);
+    checks.into_iter().map(|(_, check)| check).collect()
+}
+
+async fn check_target(client: &Client, target: &TargetConfig) -> Check {
+    let name = format!("Target {}", target.label());
+    let region = ssm::client_region(client);
+    let filter = match InstanceInformationStringFilter::builder()
+        .key("InstanceIds")
+        .values(&target.instance_id)
+        .build()
+    {
+        Ok(filter) => filter,
+        Err(e) => {
+            return Check::fail(
+                &name,
+                format!("Invalid instance filter : {}", e),
+                "Check the instance ID of the target".into(),
+            );
+        }
+    };
+    let output = match client
+        .describe_instance_information()
+        .filters(filter)
+        .send()
+        .await
+    {
+        Ok(output) => output,
+        Err(e) => {
+            return Check::fail(
+                &name,
+                format!("DescribeInstanceInformation failed : {}", e),
+                "Check the credentials of the target and that they allow ssm:DescribeInstanceInformation"
+                    .into(),
+            );
+        }
+    };
+
+    let Some(instance) = output
+        .instance_information_list()
+        .iter()
+        .find(|instance| instance.instance_id() == Some(target.instance_id.as_str()))
+    else {
+        return Check::fail(
+            &name,
+            format!("{} is not managed by SSM in {}", target.instance_id, region),
+            "Check the region of the target, that the instance is running, and that its instance profile allows SSM, e.g. with AmazonSSMManagedInstanceCore"
+                .into(),
+        );
+    };
+    match instance.ping_status() {
+        Some(PingStatus::Online) => Check::pass(
+            &name,
+            format!(
+                "Online in {}, agent {} on {}",
+                region,
+                instance.agent_version().unwrap_or("unknown"),
+                instance.platform_name().unwrap_or("unknown platform")
+            ),
+        ),
+        status => Check::fail(
+            &name,
+            format!(
+                "SSM agent {} in {}",
+                status.map(|status| status.as_str()).unwrap_or("unknown"),
+                region
+            ),
+            "Start the instance, or restart its SSM agent, and check that it reaches the SSM endpoints"
+                .into(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fake_commands, free_local_port, mock_ssm_client};
+    use vm_connect_mock_ssm::{Backend, MockSsm};
+
+    #[test]
+    fn test_check_config() {
+        assert_eq!(check_config(None).status, CheckStatus::Warn);
+        assert_eq!(check_config(Some("{ targets")).status, CheckStatus::Fail);
+        assert_eq!(
+            check_config(Some(r#"{ "targets": [] }"#)).status,
+            CheckStatus::Fail
+        );
+
+        let check = check_config(Some(
+            r#"{ "targets": [{ "name": "VM 1", "instance_id": "i-0123456789abcdef0" }, { "name": "VM 2", "instance_id": "vm-2" }] }"#,
+        ));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.ends_with("vm-2"));
+
+        let check = check_config(Some(
+            r#"{ "targets": [{ "name": "VM 1", "instance_id": "i-0123456789abcdef0" }, { "name": "VM 1", "instance_id": "mi-0123456789abcdef1" }] }"#,
+        ));
+        assert_eq!(check.status, CheckStatus::Warn);
+
+        let check = check_config(Some(
+            r#"{ "targets": [{ "name": "VM 1", "instance_id": "i-0123456789abcdef0", "launcher": "remmina" }] }"#,
+        ));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("remmina"));
+
+        let check = check_config(Some(
+            r#"{ "targets": [{ "name": "VM 1", "instance_id": "i-0123456789abcdef0", "forwards": [{ "name": "SSH", "remote_port": 22, "launcher": "telnet" }] }] }"#,
+        ));
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("telnet"));
+
+        let check = check_config(Some(
+            r#"{ "targets": [{ "name": "VM 1", "instance_id": "i-0123456789abcdef0" }, { "name": "VM 2", "instance_id": "i-01234567" }] }"#,
+        ));
+        assert_eq!(check, Check::pass("Configuration", "2 targets".into()));
+    }
+
+    #[test]
+    fn test_check_commands() {
+        let commands = fake_commands(1);
+        let check = check_session_manager_plugin(&commands.session_manager_plugin);
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert!(check.detail.ends_with("version 1.2.707.0"));
+        assert_eq!(
+            check_rdp_client(&commands.rdp_client).status,
+            CheckStatus::Pass
+        );
+
+        let check = check_session_manager_plugin("not-a-session-manager-plugin");
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.fix.unwrap().contains(PLUGIN_INSTALL_URL));
+        assert_eq!(
+            check_rdp_client("not-an-rdp-client").status,
+            CheckStatus::Fail
+        );
+    }
+
+    #[test]
+    fn test_check_local_port() {
+        assert_eq!(
+            check_local_port(&free_local_port()).status,
+            CheckStatus::Pass
+        );
+        assert_eq!(check_local_port("port").status, CheckStatus::Fail);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        assert_eq!(check_local_port(&port).status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_check_target_against_mock() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let client = mock_ssm_client(mock.endpoint_url());
+        let target = |instance_id: &str| TargetConfig {
+            name: "VM 1".into(),
+            instance_id: instance_id.into(),
+            ..Default::default()
+        };
+
+        let check = check_target(&client, &target("i-0123456789abcdef0")).await;
+        assert_eq!(check.status, CheckStatus::Pass);
+        assert!(check.detail.starts_with("Online in"), "{}", check.detail);
+
+        let check = check_target(&client, &target("i-lost")).await;
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.starts_with("SSM agent ConnectionLost"));
+
+        let check = check_target(&client, &target("i-unmanaged")).await;
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check.detail.contains("not managed by SSM"));
+        assert!(check.fix.unwrap().contains("region"));
+
+        assert!(format_report(&[check]).starts_with("[FAIL] Target VM 1"));
+    }
+}
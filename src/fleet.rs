@@ -0,0 +1,326 @@
+use aws_sdk_ssm::Client;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tokio::task::JoinSet;
+
+use crate::config::TargetConfig;
+use crate::history::csv_field;
+use crate::remediation;
+use crate::ssm::initiate_aws_client;
+
+const CSV_HEADER: &str = "target,instance_id,status,response_code,stdout,stderr";
+// Status of a target the command could not be sent to or followed on
+const ERROR_STATUS: &str = "Error";
+
+#[derive(Debug)]
+pub struct FleetError {
+    pub kind: FleetErrorKind,
+    pub msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FleetErrorKind {
+    Parameters,
+    IO,
+    Serde,
+}
+
+/**
+ * A command document and its parameters, sent as is to every selected target
+ */
+#[derive(Debug, Clone)]
+pub struct FleetCommand {
+    pub document_name: String,
+    pub parameters: HashMap<String, Vec<String>>,
+}
+
+impl FleetCommand {
+    /**
+     * Parameters as in the console JSON, `{ "commands": ["..."] }`, a single string
+     * standing for a list of one
+     */
+    pub fn new(document_name: &str, parameters: &str) -> Result<FleetCommand, FleetError> {
+        if document_name.trim().is_empty() {
+            return Err(invalid_parameters("No document name".into()));
+        }
+        let parameters = match parameters.trim() {
+            "" => HashMap::new(),
+            parameters => serde_json::from_str::<HashMap<String, Value>>(parameters)
+                .map_err(|e| {
+                    invalid_parameters(format!("Parameters are not a JSON object : {}", e))
+                })?
+                .into_iter()
+                .map(|(name, value)| parameter_values(&name, value).map(|values| (name, values)))
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(FleetCommand {
+            document_name: document_name.trim().to_string(),
+            parameters,
+        })
+    }
+}
+
+fn parameter_values(name: &str, value: Value) -> Result<Vec<String>, FleetError> {
+    let values = match value {
+        Value::Array(values) => values,
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| match value {
+            Value::String(value) => Ok(value),
+            other => Err(invalid_parameters(format!(
+                "{} holds {}, only strings are allowed",
+                name, other
+            ))),
+        })
+        .collect()
+}
+
+fn invalid_parameters(msg: String) -> FleetError {
+    FleetError {
+        kind: FleetErrorKind::Parameters,
+        msg: format!("FleetCommand : {}", msg),
+    }
+}
+
+/**
+ * How the command ended on one target, `response_code` is missing when it never ran
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FleetResult {
+    pub target: String,
+    pub instance_id: String,
+    pub status: String,
+    pub response_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FleetColumn {
+    Target,
+    InstanceId,
+    Status,
+    ResponseCode,
+}
+
+/**
+ * Runs the command on all targets at once, each through the client of its own profile and
+ * region. Results are sent as they come, the channel is closed once all are in
+ */
+pub async fn run(
+    targets: Vec<TargetConfig>,
+    command: FleetCommand,
+    results_sender: std::sync::mpsc::Sender<FleetResult>,
+    logs_sender: std::sync::mpsc::Sender<String>,
+) {
+    let mut runs = JoinSet::new();
+    for target in targets {
+        let command = command.clone();
+        let logs_sender = logs_sender.clone();
+        runs.spawn(async move {
+            let client = initiate_aws_client(&target).await;
+            run_on(&client, &target, &command, &logs_sender).await
+        });
+    }
+    while let Some(result) = runs.join_next().await {
+        // Receiver is gone when the app exited meanwhile
+        if let Ok(result) = result
+            && results_sender.send(result).is_err()
+        {
+            return;
+        }
+    }
+}
+
+pub async fn run_on(
+    client: &Client,
+    target: &TargetConfig,
+    command: &FleetCommand,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> FleetResult {
+    let invocation = remediation::send_and_wait(
+        client,
+        &target.instance_id,
+        &command.document_name,
+        command.parameters.clone(),
+        "vm-connect fleet run",
+        logs_sender,
+    )
+    .await;
+    let (status, response_code, stdout, stderr) = match invocation {
+        Ok(invocation) => (
+            invocation.status,
+            Some(invocation.response_code),
+            invocation.stdout,
+            invocation.stderr,
+        ),
+        Err(e) => (ERROR_STATUS.to_string(), None, String::new(), e.msg),
+    };
+    FleetResult {
+        target: target.label(),
+        instance_id: target.instance_id.clone(),
+        status,
+        response_code,
+        stdout,
+        stderr,
+    }
+}
+
+pub fn sort(results: &mut [FleetResult], column: FleetColumn, descending: bool) {
+    results.sort_by(|a, b| {
+        let ordering = match column {
+            FleetColumn::Target => a.target.cmp(&b.target),
+            FleetColumn::InstanceId => a.instance_id.cmp(&b.instance_id),
+            FleetColumn::Status => a.status.cmp(&b.status),
+            FleetColumn::ResponseCode => a.response_code.cmp(&b.response_code),
+        }
+        .then_with(|| a.target.cmp(&b.target));
+        match descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    });
+}
+
+pub fn export_json(path: &Path, results: &[FleetResult]) -> Result<(), FleetError> {
+    let content = serde_json::to_string_pretty(results).map_err(|e| FleetError {
+        kind: FleetErrorKind::Serde,
+        msg: format!("export : Unable to serialize results : {}", e),
+    })?;
+    write_export(path, &content)
+}
+
+pub fn export_csv(path: &Path, results: &[FleetResult]) -> Result<(), FleetError> {
+    let mut content = format!("{}\n", CSV_HEADER);
+    for result in results {
+        let fields = [
+            result.target.clone(),
+            result.instance_id.clone(),
+            result.status.clone(),
+            result
+                .response_code
+                .map(|code| code.to_string())
+                .unwrap_or_default(),
+            result.stdout.clone(),
+            result.stderr.clone(),
+        ];
+        let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        content.push_str(&line.join(","));
+        content.push('\n');
+    }
+    write_export(path, &content)
+}
+
+fn write_export(path: &Path, content: &str) -> Result<(), FleetError> {
+    fs::write(path, content).map_err(|e| FleetError {
+        kind: FleetErrorKind::IO,
+        msg: format!("export : Unable to write {} : {}", path.display(), e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_ssm_client;
+    use vm_connect_mock_ssm::{Backend, MockSsm};
+
+    fn target(name: &str, instance_id: &str) -> TargetConfig {
+        TargetConfig {
+            name: name.into(),
+            instance_id: instance_id.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fleet_command() {
+        let command = FleetCommand::new(
+            "AWS-RunPowerShellScript",
+            r#"{ "commands": ["Get-HotFix", "exit 0"], "executionTimeout": "600" }"#,
+        )
+        .unwrap();
+        assert_eq!(command.parameters["commands"], vec!["Get-HotFix", "exit 0"]);
+        assert_eq!(command.parameters["executionTimeout"], vec!["600"]);
+        assert!(FleetCommand::new("AWS-RunPatchBaseline", "").is_ok());
+
+        for (document_name, parameters) in [
+            ("", ""),
+            ("AWS-RunPowerShellScript", "Get-HotFix"),
+            ("AWS-RunPowerShellScript", r#"{ "commands": [1] }"#),
+        ] {
+            assert!(
+                FleetCommand::new(document_name, parameters)
+                    .is_err_and(|e| e.kind == FleetErrorKind::Parameters)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_on_against_mock() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let client = mock_ssm_client(mock.endpoint_url());
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+
+        let command = FleetCommand::new(
+            "AWS-RunPowerShellScript",
+            r#"{ "commands": ["Get-HotFix", "throw 'missing KB'"] }"#,
+        )
+        .unwrap();
+        let result = run_on(
+            &client,
+            &target("VM 1", "i-0123456789abcdef0"),
+            &command,
+            &logs_sender,
+        )
+        .await;
+        assert_eq!(
+            result,
+            FleetResult {
+                target: "VM 1".into(),
+                instance_id: "i-0123456789abcdef0".into(),
+                status: "Failed".into(),
+                response_code: Some(1),
+                stdout: "Get-HotFix".into(),
+                stderr: "throw 'missing KB'".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_sort_and_export() {
+        let result = |target: &str, status: &str, response_code: Option<i32>| FleetResult {
+            target: target.into(),
+            instance_id: format!("i-{}", target),
+            status: status.into(),
+            response_code,
+            stdout: "KB5034441, KB5034439".into(),
+            stderr: String::new(),
+        };
+        let mut results = vec![
+            result("b", "Success", Some(0)),
+            result("c", "Error", None),
+            result("a", "Failed", Some(1)),
+        ];
+        sort(&mut results, FleetColumn::Status, false);
+        let targets: Vec<&str> = results.iter().map(|r| r.target.as_str()).collect();
+        assert_eq!(targets, vec!["c", "a", "b"]);
+        sort(&mut results, FleetColumn::ResponseCode, true);
+        let targets: Vec<&str> = results.iter().map(|r| r.target.as_str()).collect();
+        assert_eq!(targets, vec!["a", "b", "c"]);
+
+        let path =
+            std::env::temp_dir().join(format!("vm-connect-{}-fleet.csv", std::process::id()));
+        export_csv(&path, &results).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "a,i-a,Failed,1,\"KB5034441, KB5034439\",");
+        assert_eq!(lines[3], "c,i-c,Error,,\"KB5034441, KB5034439\",");
+    }
+}
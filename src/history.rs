@@ -0,0 +1,366 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::TargetConfig;
+use crate::tasks_handler::{TaskHandlerError, TaskHandlerErrorKind};
+use crate::utils::{redact, send_log};
+
+pub const HISTORY_FILE: &str = "vm-connect-history.jsonl";
+
+const CSV_HEADER: &str = "started_at,stopped_at,duration_secs,target,instance_id,region,profile,session_id,local_port,user,aws_identity,exit_reason,error_kind,error";
+
+#[derive(Debug)]
+pub struct HistoryError {
+    pub kind: HistoryErrorKind,
+    pub msg: String,
+}
+
+#[derive(Debug)]
+pub enum HistoryErrorKind {
+    IO,
+    Serde,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ExitReason {
+    /// The RDP client was closed
+    Closed,
+    /// Stopped from the application, or the application exited
+    Stopped,
+    /// Closed after the idle timeout without traffic
+    IdleTimeout,
+    /// Closed at the maximum session duration
+    MaxDuration,
+    Failed,
+}
+
+/**
+ * One connection, written once it is over. Times are UTC, `session_id` is missing when
+ * the session could not be started
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub started_at: String,
+    pub stopped_at: String,
+    pub duration_secs: u64,
+    pub target: String,
+    pub instance_id: String,
+    pub region: Option<String>,
+    pub profile: Option<String>,
+    pub session_id: Option<String>,
+    pub local_port: String,
+    /// Account logged on the workstation
+    pub user: String,
+    /// ARN the AWS calls were made as
+    pub aws_identity: Option<String>,
+    pub exit_reason: ExitReason,
+    pub error_kind: Option<TaskHandlerErrorKind>,
+    pub error: Option<String>,
+}
+
+impl SessionRecord {
+    pub fn new(
+        target: &TargetConfig,
+        local_port: &str,
+        started_at: SystemTime,
+        stopped_at: SystemTime,
+        stop_requested: bool,
+        result: &Result<(), TaskHandlerError>,
+    ) -> SessionRecord {
+        let exit_reason = match (result, stop_requested) {
+            (Err(_), _) => ExitReason::Failed,
+            (Ok(_), true) => ExitReason::Stopped,
+            (Ok(_), false) => ExitReason::Closed,
+        };
+        SessionRecord {
+            started_at: format_utc(started_at),
+            stopped_at: format_utc(stopped_at),
+            duration_secs: stopped_at
+                .duration_since(started_at)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            target: target.name.clone(),
+            instance_id: target.instance_id.clone(),
+            region: target.region.clone(),
+            profile: target.profile.clone(),
+            session_id: None,
+            local_port: local_port.to_string(),
+            user: workstation_user(),
+            aws_identity: None,
+            exit_reason,
+            error_kind: result.as_ref().err().map(|e| e.kind.clone()),
+            // Error messages can quote AWS responses, masked like the logs
+            error: result.as_ref().err().map(|e| redact(&e.msg)),
+        }
+    }
+}
+
+fn workstation_user() -> String {
+    ["USERNAME", "USER"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .unwrap_or_default()
+}
+
+/**
+ * Appends the record as one JSON line, the file is never rewritten so that entries
+ * cannot be lost by a crash
+ */
+pub fn append(path: &Path, record: &SessionRecord) -> Result<(), HistoryError> {
+    let line = serde_json::to_string(record).map_err(|e| HistoryError {
+        kind: HistoryErrorKind::Serde,
+        msg: format!("append : Unable to serialize session record : {}", e),
+    })?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(format!("{}\n", line).as_bytes()))
+        .map_err(|e| HistoryError {
+            kind: HistoryErrorKind::IO,
+            msg: format!("append : Unable to write {} : {}", path.display(), e),
+        })
+}
+
+/**
+ * Oldest first, a missing file is an empty history and unreadable lines are skipped
+ */
+pub fn load(path: &Path, logs_sender: &std::sync::mpsc::Sender<String>) -> Vec<SessionRecord> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return vec![];
+    };
+
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(
+            |(index, line)| match serde_json::from_str::<SessionRecord>(line) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    send_log(
+                        format!(
+                            "History : Skipping line {} of {} : {}",
+                            index + 1,
+                            path.display(),
+                            e
+                        ),
+                        logs_sender,
+                    );
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+pub fn export_json(path: &Path, records: &[SessionRecord]) -> Result<(), HistoryError> {
+    let content = serde_json::to_string_pretty(records).map_err(|e| HistoryError {
+        kind: HistoryErrorKind::Serde,
+        msg: format!("export_json : Unable to serialize history : {}", e),
+    })?;
+    write_export(path, &content)
+}
+
+pub fn export_csv(path: &Path, records: &[SessionRecord]) -> Result<(), HistoryError> {
+    let mut content = format!("{}\n", CSV_HEADER);
+    for record in records {
+        let fields = [
+            record.started_at.clone(),
+            record.stopped_at.clone(),
+            record.duration_secs.to_string(),
+            record.target.clone(),
+            record.instance_id.clone(),
+            record.region.clone().unwrap_or_default(),
+            record.profile.clone().unwrap_or_default(),
+            record.session_id.clone().unwrap_or_default(),
+            record.local_port.clone(),
+            record.user.clone(),
+            record.aws_identity.clone().unwrap_or_default(),
+            format!("{:?}", record.exit_reason),
+            record
+                .error_kind
+                .as_ref()
+                .map(|kind| format!("{:?}", kind))
+                .unwrap_or_default(),
+            record.error.clone().unwrap_or_default(),
+        ];
+        let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        content.push_str(&line.join(","));
+        content.push('\n');
+    }
+    write_export(path, &content)
+}
+
+fn write_export(path: &Path, content: &str) -> Result<(), HistoryError> {
+    fs::write(path, content).map_err(|e| HistoryError {
+        kind: HistoryErrorKind::IO,
+        msg: format!("export : Unable to write {} : {}", path.display(), e),
+    })
+}
+
+pub fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/**
+ * `2025-05-14T09:30:00Z`, without pulling a date crate for it
+ */
+pub fn format_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("vm-connect-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn record(result: &Result<(), TaskHandlerError>, stop_requested: bool) -> SessionRecord {
+        let started_at = UNIX_EPOCH + Duration::from_secs(1_747_215_000);
+        SessionRecord::new(
+            &TargetConfig {
+                name: "VM, \"prod\"".into(),
+                instance_id: "i-0123456789abcdef0".into(),
+                profile: Some("prod".into()),
+                ..Default::default()
+            },
+            "55678",
+            started_at,
+            started_at + Duration::from_secs(754),
+            stop_requested,
+            result,
+        )
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_utc(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00Z"
+        );
+        assert_eq!(
+            format_utc(UNIX_EPOCH + Duration::from_secs(1_747_215_000)),
+            "2025-05-14T09:30:00Z"
+        );
+    }
+
+    #[test]
+    fn test_exit_reason() {
+        assert_eq!(record(&Ok(()), false).exit_reason, ExitReason::Closed);
+        assert_eq!(record(&Ok(()), true).exit_reason, ExitReason::Stopped);
+
+        let failed = record(
+            &Err(TaskHandlerError {
+                kind: TaskHandlerErrorKind::RDP,
+                msg: "RDP client exited with code 4".into(),
+            }),
+            true,
+        );
+        assert_eq!(failed.exit_reason, ExitReason::Failed);
+        assert_eq!(failed.error_kind, Some(TaskHandlerErrorKind::RDP));
+        assert_eq!(failed.duration_secs, 754);
+    }
+
+    #[test]
+    fn test_append_and_load() {
+        let path = temp_file("history.jsonl");
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        assert!(load(&path, &logs_sender).is_empty());
+
+        let first = SessionRecord {
+            session_id: Some("user-0123456789abcdef0".into()),
+            ..record(&Ok(()), false)
+        };
+        let second = record(
+            &Err(TaskHandlerError {
+                kind: TaskHandlerErrorKind::SSM,
+                msg: "Error when starting session".into(),
+            }),
+            false,
+        );
+        append(&path, &first).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(b"not json\n"))
+            .unwrap();
+        append(&path, &second).unwrap();
+
+        assert_eq!(load(&path, &logs_sender), vec![first, second]);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let path = temp_file("history.csv");
+        let records = vec![record(
+            &Err(TaskHandlerError {
+                kind: TaskHandlerErrorKind::RDPAndSSM,
+                msg: "SSM Error : a\nRDP Error : b".into(),
+            }),
+            false,
+        )];
+        export_csv(&path, &records).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with(
+            "2025-05-14T09:30:00Z,2025-05-14T09:42:34Z,754,\"VM, \"\"prod\"\"\",i-0123456789abcdef0,,prod,"
+        ));
+        assert!(content.ends_with(",Failed,RDPAndSSM,\"SSM Error : a\nRDP Error : b\"\n"));
+
+        let path_json = temp_file("history.json");
+        export_json(&path_json, &records).unwrap();
+        let exported: Vec<SessionRecord> =
+            serde_json::from_str(&fs::read_to_string(&path_json).unwrap()).unwrap();
+        assert_eq!(exported, records);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(path_json);
+    }
+}
@@ -1,251 +1,1751 @@
 #![allow(clippy::result_large_err)]
 #![windows_subsystem = "windows"]
 
+use clap::Parser;
 use core::f32;
 use eframe::egui;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::sync::Arc;
 use std::sync::mpsc::Sender as LogsSender;
-use std::{fs, io, path::PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, path::PathBuf};
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::Sender;
 
+mod cli;
+mod clients;
+mod config;
+use config::{CommandsConfig, ForwardConfig, RdpFilesConfig, TargetConfig};
+
+mod credentials;
+mod doctor;
+use doctor::{Check, CheckStatus};
+
+mod ec2;
+use ec2::{ConsoleScreenshot, Ec2Error, PowerAction};
+mod fleet;
+use fleet::{FleetColumn, FleetCommand, FleetResult};
+mod history;
+use history::{ExitReason, SessionRecord};
+mod proxy;
+mod relay;
+use relay::TrafficStats;
+mod remediation;
+use remediation::{RemediationAction, RemediationError};
 mod rdp;
+use rdp::RdpCredentials;
+mod rdp_files;
+mod rdp_probe;
+mod session_api;
 mod ssm;
+mod status;
+use status::TunnelStatus;
 
 mod tasks_handler;
+use tasks_handler::{Connection, ConnectionSpec, HandlerChannels};
 
 mod messages;
-use messages::ApplicationExitedMessage;
+use messages::{ApplicationExitedMessage, SessionInfoMessage};
 
 mod errors;
 
 mod utils;
 use utils::send_log;
 
-const RDP_EXTENSION: &str = "rdp";
-const VM_TARGET_1: &str = "i-0f30a1dd89600b0dc";
-const VM_TARGET_2: &str = "i-0a6eb481a98d54b72";
-const VM_TARGET_3: &str = "i-03a933321d29f9f95";
+#[cfg(test)]
+mod test_support;
+
 const LOCAL_PORT_NUMBER: &str = "55678";
+const CLIPBOARD_CLEAR_DELAY: Duration = Duration::from_secs(30);
+// Older logs are dropped from the log panel, the log file keeps them
+const LOGS_OUTPUT_MAX_LEN: usize = 64 * 1024;
+const INSTANCE_STATES_REFRESH: Duration = Duration::from_secs(30);
+// While an instance starts or stops, and right after a power action
+const INSTANCE_STATES_QUICK_REFRESH: Duration = Duration::from_secs(5);
 
-fn read_files_in_directory_with_extension(
-    extension: &str,
-    logs_sender: &LogsSender<String>,
-) -> io::Result<Vec<PathBuf>> {
-    let file_paths = fs::read_dir(".")?
-        .map(|res| res.map(|entry| entry.path()))
-        .collect::<Result<Vec<_>, io::Error>>()?;
-
-    Ok(file_paths
-        .into_iter()
-        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
-        .collect::<Vec<PathBuf>>())
+// Relative to the working directory when found there, the whole path otherwise
+fn rdp_file_label(path: &Path) -> String {
+    path.strip_prefix(".").unwrap_or(path).display().to_string()
 }
 
-fn find_rdp_files(logs_sender: &LogsSender<String>) -> Vec<PathBuf> {
-    match read_files_in_directory_with_extension(RDP_EXTENSION, logs_sender) {
-        Ok(vec) => vec,
-        Err(e) => {
-            send_log(
-                format!("find_rdp_files error : {}", e.to_string()),
-                logs_sender,
-            );
-            vec![]
+struct Session {
+    target: TargetConfig,
+    // Name of the forward, none for RDP
+    forward: Option<String>,
+    // No client was launched, the address is shown to be copied
+    tunnel_only: bool,
+    local_port_number: String,
+    started_at: SystemTime,
+    session_info_receiver: std::sync::mpsc::Receiver<SessionInfoMessage>,
+    aws_identity: Option<String>,
+    session_id: Option<String>,
+    region: Option<String>,
+    idle_closes_at: Option<Instant>,
+    idle_closed: bool,
+    deadline_sender: tokio::sync::watch::Sender<Option<Instant>>,
+    extensions: u32,
+    max_duration_reached: bool,
+    traffic: Option<Arc<TrafficStats>>,
+    // Total bytes at the start of the current throughput window
+    throughput_sample: Option<(Instant, u64)>,
+    throughput: u64,
+    application_exit_sender: Option<Sender<ApplicationExitedMessage>>,
+    join_handler: Option<std::thread::JoinHandle<Result<(), tasks_handler::TaskHandlerError>>>,
+}
+
+// Screenshot and serial output of the console, fetched together
+type ConsoleDiagnostic = (Result<ConsoleScreenshot, Ec2Error>, Result<String, Ec2Error>);
+// By instance id
+type InstanceStates = Vec<(String, Result<String, Ec2Error>)>;
+// Index of the target, whether its credentials were deleted rather than stored, and the outcome
+type CredentialsUpdate = (usize, bool, Result<(), credentials::CredentialsError>);
+
+#[derive(PartialEq)]
+enum Tab {
+    Connection,
+    History,
+    Fleet,
+    Diagnostic,
+    Doctor,
+}
+
+impl Session {
+    fn label(&self) -> String {
+        match &self.forward {
+            Some(forward) => format!("{} - {} - port {}", self.target.label(), forward, self.local_port_number),
+            None => format!("{} - port {}", self.target.label(), self.local_port_number),
+        }
+    }
+
+    // Returns true when an idle warning just came in
+    fn poll_info(&mut self) -> bool {
+        let mut warned = false;
+        for message in self.session_info_receiver.try_iter() {
+            match message {
+                SessionInfoMessage::Identity(arn) => self.aws_identity = Some(arn),
+                SessionInfoMessage::SessionId(session_id) => self.session_id = Some(session_id),
+                SessionInfoMessage::Region(region) => self.region = Some(region),
+                SessionInfoMessage::IdleWarning(closes_in) => {
+                    self.idle_closes_at = Some(Instant::now() + Duration::from_secs(closes_in));
+                    warned = true;
+                }
+                SessionInfoMessage::Active => self.idle_closes_at = None,
+                SessionInfoMessage::IdleClosed => {
+                    self.idle_closes_at = None;
+                    self.idle_closed = true;
+                }
+                SessionInfoMessage::MaxDurationReached => self.max_duration_reached = true,
+                SessionInfoMessage::Traffic(traffic) => self.traffic = Some(traffic),
+            }
+        }
+        self.update_throughput();
+        warned
+    }
+
+    fn update_throughput(&mut self) {
+        let Some(traffic) = &self.traffic else {
+            return;
+        };
+        let now = Instant::now();
+        let total = traffic.bytes_sent() + traffic.bytes_received();
+        match self.throughput_sample {
+            Some((sampled_at, _)) if now - sampled_at < Duration::from_secs(1) => {}
+            Some((sampled_at, sampled_total)) => {
+                self.throughput = ((total - sampled_total) as f64 / (now - sampled_at).as_secs_f64()) as u64;
+                self.throughput_sample = Some((now, total));
+            }
+            None => self.throughput_sample = Some((now, total)),
         }
     }
+
+    fn status(&self) -> Option<TunnelStatus> {
+        let traffic = self.traffic.as_ref()?;
+        Some(TunnelStatus {
+            target: self.target.label(),
+            instance_id: self.target.instance_id.clone(),
+            local_port: self.local_port_number.clone(),
+            session_id: self.session_id.clone(),
+            uptime_secs: traffic.uptime().as_secs(),
+            bytes_sent: traffic.bytes_sent(),
+            bytes_received: traffic.bytes_received(),
+            throughput: self.throughput,
+            connections: traffic.active_connections(),
+            response_time_ms: traffic.response_time().map(|response_time| response_time.as_millis() as u64),
+        })
+    }
+
+    // Called once its handler returned, a stop sent beforehand means it was stopped from the app
+    fn record(&mut self, result: &Result<(), tasks_handler::TaskHandlerError>) -> SessionRecord {
+        self.poll_info();
+        let mut record = SessionRecord::new(
+            &self.target,
+            &self.local_port_number,
+            self.started_at,
+            SystemTime::now(),
+            self.application_exit_sender.is_none(),
+            result,
+        );
+        record.aws_identity = self.aws_identity.clone();
+        record.session_id = self.session_id.clone();
+        // The configured one is kept when the connection failed before the region was resolved
+        record.region = self.region.clone().or(record.region);
+        if self.idle_closed && result.is_ok() {
+            record.exit_reason = ExitReason::IdleTimeout;
+        }
+        if self.max_duration_reached && result.is_ok() {
+            record.exit_reason = ExitReason::MaxDuration;
+        }
+        record
+    }
+
+    fn can_extend(&self) -> bool {
+        self.application_exit_sender.is_some()
+            && self.target.session.max_extensions.is_none_or(|max| self.extensions < max)
+    }
+
+    fn extend(&mut self, logs_sender: &LogsSender<String>) {
+        let extend_by = Duration::from_secs(self.target.session.extend_secs);
+        self.deadline_sender
+            .send_modify(|deadline| *deadline = deadline.map(|deadline| deadline + extend_by));
+        self.extensions += 1;
+        send_log(
+            format!("GUI : {} extended by {} min", self.label(), extend_by.as_secs() / 60),
+            logs_sender,
+        );
+    }
+
+    fn stop(&mut self, logs_sender: &LogsSender<String>) {
+        self.application_exit_sender.take().map_or_else(
+            || send_log("No session to stop".into(), logs_sender),
+            |tx| {
+                if tx.send(ApplicationExitedMessage).is_err() {
+                    send_log(
+                        "Egui app : ApplicationExitedMessage receiver was dropped".into(),
+                        logs_sender,
+                    );
+                }
+            },
+        );
+    }
+}
+
+fn instance_state_label(state: &str) -> &str {
+    match state {
+        "pending" => "en démarrage",
+        "running" => "démarrée",
+        "stopping" => "en arrêt",
+        "stopped" => "arrêtée",
+        "shutting-down" => "en suppression",
+        "terminated" => "supprimée",
+        other => other,
+    }
+}
+
+fn power_action_label(action: PowerAction) -> &'static str {
+    match action {
+        PowerAction::Start => "Démarrer",
+        PowerAction::Stop => "Arrêter",
+        PowerAction::Reboot => "Redémarrer",
+        PowerAction::Hibernate => "Mettre en veille prolongée",
+    }
 }
 
 struct EguiApp {
     username: String,
     pwd: String,
-    vm_target: String,
-    disabled: bool,
+    key_pair_file: String,
+    inject_credentials: bool,
+    password_receiver: Option<std::sync::mpsc::Receiver<Result<String, ec2::Ec2Error>>>,
+    /// The password copied, and when it is to be cleared from the clipboard
+    copied_password: Option<(String, Instant)>,
     logs_output: String,
-    application_exit_sender: Option<Sender<ApplicationExitedMessage>>,
     logs_receiver: std::sync::mpsc::Receiver<String>,
     logs_sender: std::sync::mpsc::Sender<String>,
-    vm1_target: String,
-    vm2_target: String,
-    vm3_target: String,
+    targets: Vec<TargetConfig>,
+    commands: CommandsConfig,
+    selected_target: usize,
+    credentials_loaded_for: Option<usize>,
+    stored_credentials_receiver: Option<
+        std::sync::mpsc::Receiver<Result<Option<RdpCredentials>, credentials::CredentialsError>>,
+    >,
+    credentials_update_receiver: Option<std::sync::mpsc::Receiver<CredentialsUpdate>>,
     selected_rdp_file: Option<PathBuf>,
-    join_handler: Option<std::thread::JoinHandle<Result<(), tasks_handler::TaskHandlerError>>>,
-    handler_running: bool,
+    // Forward of the selected target to open instead of RDP, by name
+    selected_forward: Option<String>,
+    tunnel_only: bool,
+    sessions: Vec<Session>,
     rdp_files: Vec<PathBuf>,
+    rdp_files_config: RdpFilesConfig,
+    // Picked from anywhere, kept when the directories are scanned again
+    picked_rdp_files: Vec<PathBuf>,
+    picked_rdp_file_receiver: Option<std::sync::mpsc::Receiver<Option<PathBuf>>>,
+    rdp_files_changed: std::sync::mpsc::Receiver<()>,
+    // Watching stops when it is dropped
+    _rdp_files_watcher: Option<notify::RecommendedWatcher>,
     local_port_number: String,
+    tab: Tab,
+    history: Vec<SessionRecord>,
+    status_written_at: Option<Instant>,
+    remediation_receiver: Option<std::sync::mpsc::Receiver<Result<String, RemediationError>>>,
+    powershell_script: String,
+    fleet_selected: Vec<bool>,
+    fleet_document: String,
+    fleet_parameters: String,
+    fleet_results: Vec<FleetResult>,
+    fleet_receiver: Option<std::sync::mpsc::Receiver<FleetResult>>,
+    fleet_expected: usize,
+    fleet_sort: (FleetColumn, bool),
+    diagnostic_receiver: Option<std::sync::mpsc::Receiver<ConsoleDiagnostic>>,
+    diagnostic_target: Option<String>,
+    console_screenshot: Option<egui::TextureHandle>,
+    console_output: String,
+    /// By instance id, the error message when the state could not be fetched
+    instance_states: HashMap<String, Result<String, String>>,
+    instance_states_receiver: Option<std::sync::mpsc::Receiver<InstanceStates>>,
+    instance_states_refresh_at: Instant,
+    // Kept for all the refreshes rather than built for each
+    instance_states_runtime: tokio::runtime::Runtime,
+    power_confirmation: Option<(usize, PowerAction)>,
+    power_receiver: Option<std::sync::mpsc::Receiver<Result<(), Ec2Error>>>,
+    doctor_checks: Vec<Check>,
+    doctor_receiver: Option<std::sync::mpsc::Receiver<Vec<Check>>>,
 }
 
 impl Default for EguiApp {
     fn default() -> Self {
         let (logs_sender, logs_receiver) = std::sync::mpsc::channel();
-        let rdp_files = find_rdp_files(&logs_sender);
-        let first_file_name = rdp_files
-            .first()
-            .and_then(|path| path.file_name())
-            .and_then(|s| s.to_str())
-            .unwrap_or_default()
-            .to_string();
+        let config = config::load(&logs_sender);
+        let rdp_files = rdp_files::find(&config.rdp_files, &logs_sender);
+        let (rdp_files_changed_sender, rdp_files_changed) = std::sync::mpsc::channel();
+        let rdp_files_watcher =
+            rdp_files::watch(&config.rdp_files, rdp_files_changed_sender, &logs_sender);
+        let history = history::load(Path::new(history::HISTORY_FILE), &logs_sender);
 
         Self {
             username: "Administrator".to_owned(),
             pwd: "".into(),
-            disabled: false,
+            key_pair_file: "".into(),
+            inject_credentials: true,
+            password_receiver: None,
+            copied_password: None,
             logs_output: "LOGS :\n".into(),
-            application_exit_sender: None,
             logs_receiver,
             logs_sender,
-            vm_target: VM_TARGET_1.into(),
-            vm1_target: VM_TARGET_1.into(),
-            vm2_target: VM_TARGET_2.into(),
-            vm3_target: VM_TARGET_3.into(),
+            fleet_selected: vec![false; config.targets.len()],
+            targets: config.targets,
+            commands: config.commands,
+            selected_target: 0,
+            credentials_loaded_for: None,
+            stored_credentials_receiver: None,
+            credentials_update_receiver: None,
             selected_rdp_file: rdp_files.first().map(|path| path.to_owned()),
-            join_handler: None,
-            handler_running: false,
-            rdp_files: rdp_files,
+            selected_forward: None,
+            tunnel_only: false,
+            sessions: vec![],
+            rdp_files,
+            rdp_files_config: config.rdp_files,
+            picked_rdp_files: vec![],
+            picked_rdp_file_receiver: None,
+            rdp_files_changed,
+            _rdp_files_watcher: rdp_files_watcher,
             local_port_number: LOCAL_PORT_NUMBER.to_string(),
+            tab: Tab::Connection,
+            history,
+            status_written_at: None,
+            remediation_receiver: None,
+            powershell_script: String::new(),
+            fleet_document: remediation::RUN_POWERSHELL_DOCUMENT.into(),
+            fleet_parameters: "{ \"commands\": [\"Get-HotFix | Sort-Object InstalledOn -Descending | Select-Object -First 5\"] }".into(),
+            fleet_results: vec![],
+            fleet_receiver: None,
+            fleet_expected: 0,
+            fleet_sort: (FleetColumn::Target, false),
+            diagnostic_receiver: None,
+            diagnostic_target: None,
+            console_screenshot: None,
+            console_output: String::new(),
+            instance_states: HashMap::new(),
+            instance_states_receiver: None,
+            instance_states_refresh_at: Instant::now(),
+            instance_states_runtime: tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .enable_all()
+                .build()
+                .unwrap(),
+            power_confirmation: None,
+            power_receiver: None,
+            doctor_checks: vec![],
+            doctor_receiver: None,
+        }
+    }
+}
+
+impl EguiApp {
+    fn port_in_use(&self) -> bool {
+        self.sessions
+            .iter()
+            .any(|session| session.local_port_number == self.local_port_number)
+    }
+
+    fn refresh_rdp_files(&mut self) {
+        if self.rdp_files_changed.try_iter().count() == 0 {
+            return;
+        }
+        let mut rdp_files = rdp_files::find(&self.rdp_files_config, &self.logs_sender);
+        for path in &self.picked_rdp_files {
+            if !rdp_files.contains(path) {
+                rdp_files.push(path.clone());
+            }
+        }
+        send_log(format!("GUI : RDP files changed, {} found", rdp_files.len()), &self.logs_sender);
+        if !self.selected_rdp_file.as_ref().is_some_and(|path| rdp_files.contains(path)) {
+            self.selected_rdp_file = rdp_files.first().cloned();
+        }
+        self.rdp_files = rdp_files;
+    }
+
+    // The dialog would block the GUI until closed
+    fn pick_rdp_file(&mut self) {
+        let (tx_path, rx_path) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let file = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(
+                    rfd::AsyncFileDialog::new()
+                        .add_filter("Connexion RDP", &["rdp"])
+                        .pick_file(),
+                );
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_path.send(file.map(|file| file.path().to_path_buf()));
+        });
+        self.picked_rdp_file_receiver = Some(rx_path);
+    }
+
+    fn poll_picked_rdp_file(&mut self, ctx: &egui::Context) {
+        let received = self
+            .picked_rdp_file_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok(path)) => {
+                self.picked_rdp_file_receiver = None;
+                // None when the dialog was cancelled
+                let Some(path) = path else {
+                    return;
+                };
+                send_log(format!("GUI : Added RDP file {}", path.display()), &self.logs_sender);
+                if !self.rdp_files.contains(&path) {
+                    self.rdp_files.push(path.clone());
+                    self.picked_rdp_files.push(path.clone());
+                }
+                self.selected_rdp_file = Some(path);
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.picked_rdp_file_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    // None when RDP is chosen or the selected target has no forward of that name
+    fn selected_forward(&self) -> Option<ForwardConfig> {
+        let name = self.selected_forward.as_ref()?;
+        self.targets
+            .get(self.selected_target)?
+            .forwards
+            .iter()
+            .find(|forward| &forward.name == name)
+            .cloned()
+    }
+
+    fn fetch_windows_password(&mut self) {
+        let Some(target) = self.targets.get(self.selected_target).cloned() else {
+            return;
+        };
+        let key_pair_file = match self.key_pair_file.is_empty() {
+            true => target.key_pair_file.clone(),
+            false => Some(self.key_pair_file.clone()),
+        };
+        let Some(key_pair_file) = key_pair_file else {
+            send_log(
+                "GUI : No key pair file set for this target, cannot decrypt its password".into(),
+                &self.logs_sender,
+            );
+            return;
+        };
+
+        let (tx_password, rx_password) = std::sync::mpsc::channel();
+        let logs_sender = self.logs_sender.clone();
+        std::thread::spawn(move || {
+            let password = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(ec2::get_windows_password(target, key_pair_file, logs_sender));
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_password.send(password);
+        });
+        self.password_receiver = Some(rx_password);
+    }
+
+    fn run_remediation(&mut self, action: RemediationAction) {
+        let Some(target) = self.targets.get(self.selected_target).cloned() else {
+            return;
+        };
+        let (tx_result, rx_result) = std::sync::mpsc::channel();
+        let logs_sender = self.logs_sender.clone();
+        std::thread::spawn(move || {
+            let result = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    let client = ssm::initiate_aws_client(&target).await;
+                    remediation::run(&client, &target.instance_id, &action, &logs_sender).await
+                });
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_result.send(result);
+        });
+        self.remediation_receiver = Some(rx_result);
+    }
+
+    fn poll_remediation(&mut self, ctx: &egui::Context) {
+        let received = self
+            .remediation_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok(result)) => {
+                self.remediation_receiver = None;
+                match result {
+                    Ok(_) => send_log("GUI : Run Command succeeded".into(), &self.logs_sender),
+                    Err(e) => send_log("GUI : ".to_string() + &e.msg, &self.logs_sender),
+                }
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.remediation_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    fn remediation_menu(&mut self, ui: &mut egui::Ui) {
+        let mut action = None;
+        if ui.button("Redémarrer le service RDP").clicked() {
+            action = Some(RemediationAction::RestartRdpService);
+        }
+        if ui.button("Activer RDP dans le pare-feu").clicked() {
+            action = Some(RemediationAction::EnableRdp);
+        }
+        if ui
+            .add_enabled(!self.pwd.is_empty(), egui::Button::new("Réinitialiser le mot de passe"))
+            .on_hover_text("Donne le mot de passe saisi à l'utilisateur saisi, via un paramètre SecureString supprimé ensuite")
+            .on_disabled_hover_text("Saisir le nouveau mot de passe")
+            .clicked()
+        {
+            action = Some(RemediationAction::ResetPassword(RdpCredentials {
+                username: self.username.clone(),
+                password: self.pwd.clone(),
+            }));
+        }
+        ui.separator();
+        ui.label("Script PowerShell : ");
+        ui.add(egui::TextEdit::multiline(&mut self.powershell_script).code_editor().desired_rows(4));
+        if ui
+            .add_enabled(!self.powershell_script.trim().is_empty(), egui::Button::new("Exécuter"))
+            .clicked()
+        {
+            action = Some(RemediationAction::PowerShell(self.powershell_script.clone()));
+        }
+
+        if let Some(action) = action {
+            ui.close_menu();
+            self.run_remediation(action);
+        }
+    }
+
+    // Reads username and password from the secret store whenever another target is selected,
+    // the store may block on an unlock prompt
+    fn load_stored_credentials(&mut self) {
+        if self.credentials_loaded_for == Some(self.selected_target) {
+            return;
+        }
+        self.credentials_loaded_for = Some(self.selected_target);
+        let Some(target) = self.targets.get(self.selected_target).cloned() else {
+            return;
+        };
+
+        let (tx_credentials, rx_credentials) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // Receiver is gone when the app exited or another target was selected meanwhile
+            let _ = tx_credentials.send(credentials::load(&target));
+        });
+        // The result of a previous target is dropped along with its receiver
+        self.stored_credentials_receiver = Some(rx_credentials);
+    }
+
+    fn poll_stored_credentials(&mut self, ctx: &egui::Context) {
+        let received = self
+            .stored_credentials_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok(loaded)) => {
+                self.stored_credentials_receiver = None;
+                match loaded {
+                    Ok(Some(stored)) => {
+                        self.username = stored.username;
+                        self.pwd = stored.password;
+                    }
+                    Ok(None) => self.pwd.clear(),
+                    Err(e) => {
+                        self.pwd.clear();
+                        send_log("GUI : ".to_string() + &e.msg, &self.logs_sender);
+                    }
+                }
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.stored_credentials_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    // The secret store may wait for the user to unlock it
+    fn store_credentials(&mut self) {
+        let index = self.selected_target;
+        let Some(target) = self.targets.get(index).cloned() else {
+            return;
+        };
+        let stored = RdpCredentials {
+            username: self.username.clone(),
+            password: self.pwd.clone(),
+        };
+
+        let (tx_update, rx_update) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_update.send((index, false, credentials::store(&target, &stored)));
+        });
+        self.credentials_update_receiver = Some(rx_update);
+    }
+
+    fn delete_credentials(&mut self) {
+        let index = self.selected_target;
+        let Some(target) = self.targets.get(index).cloned() else {
+            return;
+        };
+
+        let (tx_update, rx_update) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_update.send((index, true, credentials::delete(&target)));
+        });
+        self.credentials_update_receiver = Some(rx_update);
+    }
+
+    fn poll_credentials_update(&mut self, ctx: &egui::Context) {
+        let received = self
+            .credentials_update_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok((index, deleted, result))) => {
+                self.credentials_update_receiver = None;
+                let label = self.targets.get(index).map(|target| target.label()).unwrap_or_default();
+                match result {
+                    Ok(_) if deleted => {
+                        send_log(format!("GUI : Credentials deleted for {}", label), &self.logs_sender);
+                        if index == self.selected_target {
+                            self.pwd.clear();
+                        }
+                    }
+                    Ok(_) => send_log(format!("GUI : Credentials stored for {}", label), &self.logs_sender),
+                    Err(e) => send_log("GUI : ".to_string() + &e.msg, &self.logs_sender),
+                }
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.credentials_update_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    fn poll_windows_password(&mut self, ctx: &egui::Context) {
+        let received = self
+            .password_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok(Ok(password))) => {
+                self.pwd = password;
+                self.password_receiver = None;
+                send_log("GUI : Windows password retrieved".into(), &self.logs_sender);
+            }
+            Some(Ok(Err(e))) => {
+                self.password_receiver = None;
+                send_log("GUI : ".to_string() + &e.msg, &self.logs_sender);
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.password_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    fn export_history(&self, extension: &str) {
+        // Colons are not allowed in Windows file names
+        let path = PathBuf::from(format!(
+            "vm-connect-history-{}.{}",
+            history::format_utc(SystemTime::now()).replace(':', "-"),
+            extension
+        ));
+        let exported = match extension {
+            "csv" => history::export_csv(&path, &self.history),
+            _ => history::export_json(&path, &self.history),
+        };
+        match exported {
+            Ok(_) => send_log(
+                format!("GUI : History exported to {}", path.display()),
+                &self.logs_sender,
+            ),
+            Err(e) => send_log("GUI : ".to_string() + &e.msg, &self.logs_sender),
+        }
+    }
+
+    fn history_ui(&self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Exporter en CSV").clicked() {
+                self.export_history("csv");
+            }
+            if ui.button("Exporter en JSON").clicked() {
+                self.export_history("json");
+            }
+        });
+        egui::ScrollArea::both().show(ui, |ui| {
+            egui::Grid::new("history").striped(true).show(ui, |ui| {
+                for header in ["Début (UTC)", "Durée", "VM", "Port", "Profil", "Session", "Utilisateur", "Fin"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+                // Most recent first
+                for record in self.history.iter().rev() {
+                    ui.label(&record.started_at);
+                    ui.label(format!("{} min {:02} s", record.duration_secs / 60, record.duration_secs % 60));
+                    ui.label(&record.target);
+                    ui.label(&record.local_port);
+                    ui.label(record.profile.as_deref().unwrap_or("-"));
+                    ui.label(record.session_id.as_deref().unwrap_or("-"));
+                    ui.label(&record.user)
+                        .on_hover_text(record.aws_identity.as_deref().unwrap_or("Identité AWS inconnue"));
+                    match (&record.exit_reason, &record.error_kind) {
+                        (ExitReason::Closed, _) => ui.label("Fermée"),
+                        (ExitReason::Stopped, _) => ui.label("Arrêtée"),
+                        (ExitReason::IdleTimeout, _) => ui.label("Inactivité"),
+                        (ExitReason::MaxDuration, _) => ui.label("Durée maximale"),
+                        (ExitReason::Failed, kind) => ui
+                            .label(format!("Échec : {}", kind.as_ref().map(|kind| kind.to_string()).unwrap_or_default()))
+                            .on_hover_text(record.error.as_deref().unwrap_or_default()),
+                    };
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    fn run_fleet(&mut self) {
+        let command = match FleetCommand::new(&self.fleet_document, &self.fleet_parameters) {
+            Ok(command) => command,
+            Err(e) => {
+                send_log("GUI : ".to_string() + &e.msg, &self.logs_sender);
+                return;
+            }
+        };
+        let targets: Vec<TargetConfig> = self
+            .targets
+            .iter()
+            .zip(&self.fleet_selected)
+            .filter(|(_, selected)| **selected)
+            .map(|(target, _)| target.clone())
+            .collect();
+        send_log(
+            format!("GUI : Running {} on {} targets", command.document_name, targets.len()),
+            &self.logs_sender,
+        );
+
+        let (tx_results, rx_results) = std::sync::mpsc::channel();
+        let logs_sender = self.logs_sender.clone();
+        self.fleet_expected = targets.len();
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(fleet::run(targets, command, tx_results, logs_sender));
+        });
+        self.fleet_results.clear();
+        self.fleet_receiver = Some(rx_results);
+    }
+
+    fn poll_fleet(&mut self, ctx: &egui::Context) {
+        let Some(receiver) = &self.fleet_receiver else {
+            return;
+        };
+        loop {
+            match receiver.try_recv() {
+                Ok(result) => self.fleet_results.push(result),
+                Err(std::sync::mpsc::TryRecvError::Empty) => {
+                    ctx.request_repaint_after(Duration::from_millis(200));
+                    break;
+                }
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    self.fleet_receiver = None;
+                    send_log(
+                        format!(
+                            "GUI : Fleet run done, {} of {} succeeded",
+                            self.fleet_results.iter().filter(|result| result.status == "Success").count(),
+                            self.fleet_results.len()
+                        ),
+                        &self.logs_sender,
+                    );
+                    break;
+                }
+            }
+        }
+        fleet::sort(&mut self.fleet_results, self.fleet_sort.0, self.fleet_sort.1);
+    }
+
+    fn export_fleet_results(&self, extension: &str) {
+        let path = PathBuf::from(format!(
+            "vm-connect-fleet-{}.{}",
+            history::format_utc(SystemTime::now()).replace(':', "-"),
+            extension
+        ));
+        let exported = match extension {
+            "csv" => fleet::export_csv(&path, &self.fleet_results),
+            _ => fleet::export_json(&path, &self.fleet_results),
+        };
+        match exported {
+            Ok(_) => send_log(
+                format!("GUI : Fleet results exported to {}", path.display()),
+                &self.logs_sender,
+            ),
+            Err(e) => send_log("GUI : ".to_string() + &e.msg, &self.logs_sender),
+        }
+    }
+
+    fn fleet_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal_wrapped(|ui| {
+            for (target, selected) in self.targets.iter().zip(self.fleet_selected.iter_mut()) {
+                ui.checkbox(selected, target.label());
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Tout sélectionner").clicked() {
+                self.fleet_selected.fill(true);
+            }
+            if ui.button("Tout désélectionner").clicked() {
+                self.fleet_selected.fill(false);
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Document : ");
+            ui.text_edit_singleline(&mut self.fleet_document);
+        });
+        ui.label("Paramètres (JSON) : ");
+        ui.add(egui::TextEdit::multiline(&mut self.fleet_parameters).code_editor().desired_rows(3).desired_width(f32::INFINITY));
+        ui.horizontal(|ui| {
+            let running = self.fleet_receiver.is_some();
+            if ui
+                .add_enabled(!running && self.fleet_selected.contains(&true), egui::Button::new("Lancer"))
+                .on_disabled_hover_text("Sélectionner des VM, une seule exécution à la fois")
+                .clicked()
+            {
+                self.run_fleet();
+            }
+            if running {
+                ui.spinner();
+                ui.label(format!("{} / {}", self.fleet_results.len(), self.fleet_expected));
+            }
+            if ui.add_enabled(!self.fleet_results.is_empty(), egui::Button::new("Exporter en CSV")).clicked() {
+                self.export_fleet_results("csv");
+            }
+            if ui.add_enabled(!self.fleet_results.is_empty(), egui::Button::new("Exporter en JSON")).clicked() {
+                self.export_fleet_results("json");
+            }
+        });
+
+        egui::ScrollArea::both().show(ui, |ui| {
+            egui::Grid::new("fleet").striped(true).show(ui, |ui| {
+                for (header, column) in [
+                    ("VM", Some(FleetColumn::Target)),
+                    ("Instance", Some(FleetColumn::InstanceId)),
+                    ("Statut", Some(FleetColumn::Status)),
+                    ("Code", Some(FleetColumn::ResponseCode)),
+                    ("Sortie", None),
+                    ("Erreurs", None),
+                ] {
+                    let Some(column) = column else {
+                        ui.strong(header);
+                        continue;
+                    };
+                    let (sorted_by, descending) = self.fleet_sort;
+                    let arrow = match (sorted_by == column, descending) {
+                        (true, false) => " ⬆",
+                        (true, true) => " ⬇",
+                        (false, _) => "",
+                    };
+                    if ui.button(egui::RichText::new(format!("{}{}", header, arrow)).strong()).clicked() {
+                        self.fleet_sort = (column, sorted_by == column && !descending);
+                        fleet::sort(&mut self.fleet_results, column, self.fleet_sort.1);
+                    }
+                }
+                ui.end_row();
+                for result in &self.fleet_results {
+                    ui.label(&result.target);
+                    ui.label(&result.instance_id);
+                    match result.status.as_str() {
+                        "Success" => ui.label(&result.status),
+                        _ => ui.colored_label(ui.visuals().error_fg_color, &result.status),
+                    };
+                    ui.label(result.response_code.map(|code| code.to_string()).unwrap_or_else(|| "-".into()));
+                    for output in [&result.stdout, &result.stderr] {
+                        ui.label(output.lines().next().unwrap_or_default())
+                            .on_hover_text(output.as_str());
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    fn refresh_instance_states_when_due(&mut self, ctx: &egui::Context) {
+        if let Some(receiver) = &self.instance_states_receiver {
+            match receiver.try_recv() {
+                Ok(states) => {
+                    self.instance_states_receiver = None;
+                    for (instance_id, state) in states {
+                        let state = state.map_err(|e| e.msg);
+                        // Logged once, not at every refresh
+                        if let Err(msg) = &state
+                            && self.instance_states.get(&instance_id) != Some(&state)
+                        {
+                            send_log("GUI : ".to_string() + msg, &self.logs_sender);
+                        }
+                        self.instance_states.insert(instance_id, state);
+                    }
+                    let transitional = self
+                        .instance_states
+                        .values()
+                        .any(|state| state.as_deref().is_ok_and(ec2::is_transitional));
+                    self.instance_states_refresh_at = Instant::now()
+                        + match transitional {
+                            true => INSTANCE_STATES_QUICK_REFRESH,
+                            false => INSTANCE_STATES_REFRESH,
+                        };
+                }
+                Err(std::sync::mpsc::TryRecvError::Empty) => {
+                    ctx.request_repaint_after(Duration::from_millis(200));
+                }
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                    self.instance_states_receiver = None;
+                    self.instance_states_refresh_at = Instant::now() + INSTANCE_STATES_REFRESH;
+                }
+            }
+            return;
+        }
+        // Only while the targets list is shown, a refresh is due as soon as it is shown again
+        let minimized = ctx.input(|input| input.viewport().minimized == Some(true));
+        if self.tab != Tab::Connection
+            || minimized
+            || Instant::now() < self.instance_states_refresh_at
+            || self.targets.is_empty()
+        {
+            return;
+        }
+
+        let (tx_states, rx_states) = std::sync::mpsc::channel();
+        let targets = self.targets.clone();
+        self.instance_states_runtime.spawn(async move {
+            let states = ec2::get_instance_states(targets).await;
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_states.send(states);
+        });
+        self.instance_states_receiver = Some(rx_states);
+    }
+
+    fn run_power_action(&mut self, index: usize, action: PowerAction) {
+        let Some(target) = self.targets.get(index).cloned() else {
+            return;
+        };
+        let (tx_result, rx_result) = std::sync::mpsc::channel();
+        let logs_sender = self.logs_sender.clone();
+        std::thread::spawn(move || {
+            let result = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(ec2::power(&target, action, &logs_sender));
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_result.send(result);
+        });
+        self.power_receiver = Some(rx_result);
+    }
+
+    fn poll_power_action(&mut self, ctx: &egui::Context) {
+        let received = self
+            .power_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok(result)) => {
+                self.power_receiver = None;
+                if let Err(e) = result {
+                    send_log("GUI : ".to_string() + &e.msg, &self.logs_sender);
+                }
+                // The instance is leaving its state by now
+                self.instance_states_refresh_at = Instant::now() + Duration::from_secs(1);
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.power_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    fn targets_ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("targets").striped(true).show(ui, |ui| {
+            for (index, target) in self.targets.iter().enumerate() {
+                ui.selectable_value(&mut self.selected_target, index, target.label());
+                let state = self.instance_states.get(&target.instance_id);
+                match state {
+                    Some(Ok(state)) => {
+                        ui.label(instance_state_label(state));
+                    }
+                    Some(Err(msg)) => {
+                        ui.colored_label(ui.visuals().error_fg_color, "inconnu")
+                            .on_hover_text(msg);
+                    }
+                    None => {
+                        ui.label("-");
+                    }
+                }
+                for action in [PowerAction::Start, PowerAction::Stop, PowerAction::Reboot, PowerAction::Hibernate] {
+                    let allowed = state.is_some_and(|state| state.as_deref().is_ok_and(|state| action.allowed_in(state)));
+                    if ui
+                        .add_enabled(allowed && self.power_receiver.is_none(), egui::Button::new(power_action_label(action)))
+                        .clicked()
+                    {
+                        self.power_confirmation = Some((index, action));
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    }
+
+    fn power_confirmation_ui(&mut self, ctx: &egui::Context) {
+        let Some((index, action)) = self.power_confirmation else {
+            return;
+        };
+        let Some(target) = self.targets.get(index) else {
+            self.power_confirmation = None;
+            return;
+        };
+        let question = format!("{} {} ({}) ?", power_action_label(action), target.label(), target.instance_id);
+
+        let mut confirmed = None;
+        let modal = egui::Modal::new(egui::Id::new("power_confirmation")).show(ctx, |ui| {
+            ui.label(question);
+            if action != PowerAction::Start {
+                ui.label("Les sessions RDP ouvertes sur la VM seront coupées.");
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Confirmer").clicked() {
+                    confirmed = Some(true);
+                }
+                if ui.button("Annuler").clicked() {
+                    confirmed = Some(false);
+                }
+            });
+        });
+        if modal.should_close() && confirmed.is_none() {
+            confirmed = Some(false);
+        }
+        match confirmed {
+            Some(true) => {
+                self.power_confirmation = None;
+                self.run_power_action(index, action);
+            }
+            Some(false) => self.power_confirmation = None,
+            None => {}
+        }
+    }
+
+    fn fetch_console_diagnostic(&mut self) {
+        let Some(target) = self.targets.get(self.selected_target).cloned() else {
+            return;
+        };
+        self.diagnostic_target = Some(target.label());
+        let (tx_diagnostic, rx_diagnostic) = std::sync::mpsc::channel();
+        let logs_sender = self.logs_sender.clone();
+        std::thread::spawn(move || {
+            let diagnostic = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    tokio::join!(
+                        ec2::get_console_screenshot(&target, &logs_sender),
+                        ec2::get_console_output(&target, &logs_sender)
+                    )
+                });
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_diagnostic.send(diagnostic);
+        });
+        self.diagnostic_receiver = Some(rx_diagnostic);
+    }
+
+    fn poll_console_diagnostic(&mut self, ctx: &egui::Context) {
+        let received = self
+            .diagnostic_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok((screenshot, output))) => {
+                self.diagnostic_receiver = None;
+                // Each one may fail alone, e.g. no screenshot of an instance type without a display
+                self.console_screenshot = match screenshot {
+                    Ok(screenshot) => Some(ctx.load_texture(
+                        "console-screenshot",
+                        egui::ColorImage::from_rgb([screenshot.width, screenshot.height], &screenshot.rgb),
+                        egui::TextureOptions::LINEAR,
+                    )),
+                    Err(e) => {
+                        send_log("GUI : ".to_string() + &e.msg, &self.logs_sender);
+                        None
+                    }
+                };
+                self.console_output = match output {
+                    Ok(output) => output,
+                    Err(e) => {
+                        send_log("GUI : ".to_string() + &e.msg, &self.logs_sender);
+                        String::new()
+                    }
+                };
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.diagnostic_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    fn diagnostic_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal_wrapped(|ui| {
+            for (index, target) in self.targets.iter().enumerate() {
+                ui.selectable_value(&mut self.selected_target, index, target.label());
+            }
+        });
+        ui.horizontal(|ui| {
+            let running = self.diagnostic_receiver.is_some();
+            if ui
+                .add_enabled(!running, egui::Button::new("Capturer la console"))
+                .on_hover_text("Capture d'écran et sortie série de la VM sélectionnée, sans passer par SSM")
+                .on_disabled_hover_text("Capture en cours")
+                .clicked()
+            {
+                self.fetch_console_diagnostic();
+            }
+            if running {
+                ui.spinner();
+            }
+            if let Some(target) = &self.diagnostic_target {
+                ui.label(target);
+            }
+        });
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            match &self.console_screenshot {
+                Some(texture) => {
+                    ui.add(egui::Image::new(texture).shrink_to_fit());
+                }
+                None => {
+                    ui.label("Pas de capture d'écran");
+                }
+            }
+            ui.separator();
+            ui.label("Sortie de la console série : ");
+            match self.console_output.trim().is_empty() {
+                true => ui.label("Pas de sortie"),
+                false => ui.label(egui::RichText::new(&self.console_output).monospace()),
+            };
+        });
+    }
+
+    fn run_doctor(&mut self) {
+        let (tx_checks, rx_checks) = std::sync::mpsc::channel();
+        let logs_sender = self.logs_sender.clone();
+        let local_port_number = self.local_port_number.clone();
+        std::thread::spawn(move || {
+            let checks = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(doctor::run(&local_port_number, &logs_sender));
+            // Receiver is gone when the app exited meanwhile
+            let _ = tx_checks.send(checks);
+        });
+        self.doctor_receiver = Some(rx_checks);
+    }
+
+    fn poll_doctor(&mut self, ctx: &egui::Context) {
+        let received = self
+            .doctor_receiver
+            .as_ref()
+            .map(|receiver| receiver.try_recv());
+
+        match received {
+            Some(Ok(checks)) => {
+                self.doctor_receiver = None;
+                self.doctor_checks = checks;
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) => {
+                ctx.request_repaint_after(Duration::from_millis(200));
+            }
+            Some(Err(std::sync::mpsc::TryRecvError::Disconnected)) => {
+                self.doctor_receiver = None;
+            }
+            None => {}
+        }
+    }
+
+    fn doctor_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let running = self.doctor_receiver.is_some();
+            if ui
+                .add_enabled(!running, egui::Button::new("Lancer les vérifications"))
+                .on_hover_text("Plugin Session Manager, client RDP, configuration, fichiers .rdp, port local, identifiants AWS et VM joignables par SSM")
+                .on_disabled_hover_text("Vérifications en cours")
+                .clicked()
+            {
+                self.run_doctor();
+            }
+            if running {
+                ui.spinner();
+            }
+        });
+
+        egui::ScrollArea::both().show(ui, |ui| {
+            egui::Grid::new("doctor").striped(true).show(ui, |ui| {
+                for header in ["Statut", "Vérification", "Détail", "Correction"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+                for check in &self.doctor_checks {
+                    match check.status {
+                        CheckStatus::Pass => ui.label("OK"),
+                        CheckStatus::Warn => ui.colored_label(ui.visuals().warn_fg_color, "Attention"),
+                        CheckStatus::Fail => ui.colored_label(ui.visuals().error_fg_color, "Échec"),
+                    };
+                    ui.label(&check.name);
+                    ui.label(&check.detail);
+                    ui.label(check.fix.as_deref().unwrap_or_default());
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    fn record_session(
+        history: &mut Vec<SessionRecord>,
+        session: &mut Session,
+        result: &Result<(), tasks_handler::TaskHandlerError>,
+        logs_sender: &LogsSender<String>,
+    ) {
+        let record = session.record(result);
+        if let Err(e) = history::append(Path::new(history::HISTORY_FILE), &record) {
+            send_log("Egui app : ".to_string() + &e.msg, logs_sender);
+        }
+        history.push(record);
+    }
+
+    // Read by `vm-connect sessions`, best effort since the GUI shows the same
+    fn write_status_when_due(&mut self) {
+        let tunnels: Vec<TunnelStatus> = self.sessions.iter().filter_map(Session::status).collect();
+        if tunnels.is_empty() {
+            // Only once the last tunnel went away, no file meaning no active tunnel
+            if self.status_written_at.take().is_some() {
+                let _ = fs::remove_file(status::STATUS_FILE);
+            }
+            return;
+        }
+        if self.status_written_at.is_some_and(|written_at| written_at.elapsed() < Duration::from_secs(1)) {
+            return;
+        }
+        self.status_written_at = Some(Instant::now());
+        if let Err(e) = status::write(Path::new(status::STATUS_FILE), &tunnels) {
+            send_log("Egui app : ".to_string() + &e.msg, &self.logs_sender);
+        }
+    }
+
+    fn clear_clipboard_when_due(&mut self, ctx: &egui::Context) {
+        if let Some((_, clear_at)) = &self.copied_password {
+            let now = Instant::now();
+            if now >= *clear_at {
+                self.clear_clipboard();
+            } else {
+                ctx.request_repaint_after(*clear_at - now);
+            }
+        }
+    }
+
+    // Only while it still holds the password, whatever was copied since is left alone
+    fn clear_clipboard(&mut self) {
+        let Some((password, _)) = self.copied_password.take() else {
+            return;
+        };
+        let cleared = arboard::Clipboard::new().and_then(|mut clipboard| {
+            match clipboard.get_text().is_ok_and(|text| text == password) {
+                true => clipboard.clear().map(|_| true),
+                false => Ok(false),
+            }
+        });
+        match cleared {
+            Ok(true) => send_log("GUI : Clipboard cleared".into(), &self.logs_sender),
+            Ok(false) => send_log("GUI : Clipboard no longer holds the password, left as is".into(), &self.logs_sender),
+            Err(e) => send_log(format!("GUI : Unable to clear the clipboard : {}", e), &self.logs_sender),
         }
     }
 }
 
 impl eframe::App for EguiApp {
     fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
-        send_log("Egui app : Terminate session...".into(), &self.logs_sender);
-        self.application_exit_sender.take().map_or_else(|| send_log("No session to stop".into(), &self.logs_sender) , |tx| {
-            tx.send(ApplicationExitedMessage).expect("ApplicationExitedMessage receiver was dropped");
-            self.join_handler.take().map_or_else(|| send_log("No thread to stop".into(), &self.logs_sender), |h|  {
-                send_log("Egui app : stop app msg sent to handler, waiting for handler thread to stop.".into(), &self.logs_sender);
+        send_log("Egui app : Terminate sessions...".into(), &self.logs_sender);
+        if self.sessions.is_empty() {
+            send_log("No session to stop".into(), &self.logs_sender);
+        }
+        for session in self.sessions.iter_mut() {
+            session.stop(&self.logs_sender);
+        }
+        for mut session in self.sessions.drain(..) {
+            session.join_handler.take().map_or_else(|| send_log("No thread to stop".into(), &self.logs_sender), |h|  {
+                send_log(format!("Egui app : stop app msg sent to handler of {}, waiting for handler thread to stop.", session.label()), &self.logs_sender);
                 let join_result = h.join().expect("Error while joining handler thread");
+                Self::record_session(&mut self.history, &mut session, &join_result, &self.logs_sender);
                 if let Err(e) = join_result {
                     send_log("Egui app : ".to_string() + &e.msg, &self.logs_sender);
                 }
                 send_log("Egui app : Handler thread done".into(), &self.logs_sender);
             });
-        });
+        }
+        let _ = fs::remove_file(status::STATUS_FILE);
+        self.clear_clipboard();
     }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Try to see whether task handler is done, if so reactivate app
-        let taken_handler = self.join_handler.take_if(|handler| handler.is_finished());
+        // Try to see whether task handlers are done, if so remove their session
+        let logs_sender = self.logs_sender.clone();
+        let history = &mut self.history;
+        self.sessions.retain_mut(|session| {
+            let taken_handler = session
+                .join_handler
+                .take_if(|handler| handler.is_finished());
 
-        if taken_handler.is_some() {
-            let a = taken_handler
-                .unwrap()
-                .join()
-                .expect("Error while joining handler thread");
-            if let Err(e) = a {
-                send_log(
-                    "Egui app : ".to_string() + &e.kind.to_string() + " : " + &e.msg,
-                    &self.logs_sender,
-                );
+            match taken_handler {
+                Some(handler) => {
+                    let a = handler.join().expect("Error while joining handler thread");
+                    Self::record_session(history, session, &a, &logs_sender);
+                    if let Err(e) = a {
+                        send_log(
+                            "Egui app : ".to_string() + &e.kind.to_string() + " : " + &e.msg,
+                            &logs_sender,
+                        );
+                    }
+                    false
+                }
+                None => true,
+            }
+        });
+
+        for session in self.sessions.iter_mut() {
+            if session.poll_info() {
+                ctx.send_viewport_cmd(egui::ViewportCommand::RequestUserAttention(
+                    egui::UserAttentionType::Informational,
+                ));
             }
-            self.disabled = false;
-            self.handler_running = false;
-            self.application_exit_sender = None;
         }
+        // Keeps the countdowns, traffic and status file up to date and notices tunnels that ended on their own
+        ctx.request_repaint_after(Duration::from_secs(1));
+        self.write_status_when_due();
+
+        for log in self.logs_receiver.try_iter() {
+            self.logs_output.push_str(&log);
+        }
+        if self.logs_output.len() > LOGS_OUTPUT_MAX_LEN {
+            let mut cut = self.logs_output.len() - LOGS_OUTPUT_MAX_LEN;
+            while !self.logs_output.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.logs_output.drain(..cut);
+        }
+
+        self.load_stored_credentials();
+        self.poll_stored_credentials(ctx);
+        self.poll_credentials_update(ctx);
+        self.poll_windows_password(ctx);
+        self.poll_remediation(ctx);
+        self.poll_fleet(ctx);
+        self.poll_console_diagnostic(ctx);
+        self.poll_power_action(ctx);
+        self.poll_doctor(ctx);
+        self.refresh_instance_states_when_due(ctx);
+        self.power_confirmation_ui(ctx);
+        self.clear_clipboard_when_due(ctx);
+        self.refresh_rdp_files();
+        self.poll_picked_rdp_file(ctx);
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Connexion VM SSM");
-            // ui.horizontal(|ui| {
-            //     let username_label = ui.label("Nom d'Utilisateur VM : ");
-            //     ui.text_edit_singleline(&mut self.username)
-            //         .labelled_by(username_label.id);
-            // });
-            // ui.horizontal(|ui| {
-            //     ui.label("Mot de passe VM : ");
-            //     ui.add(egui::TextEdit::singleline(&mut self.pwd).password(true));
-            // });
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.tab, Tab::Connection, "Connexion");
+                ui.selectable_value(&mut self.tab, Tab::History, "Historique");
+                ui.selectable_value(&mut self.tab, Tab::Fleet, "Parc");
+                ui.selectable_value(&mut self.tab, Tab::Diagnostic, "Diagnostic");
+                ui.selectable_value(&mut self.tab, Tab::Doctor, "Vérifications");
+            });
+            ui.separator();
+            if self.tab == Tab::History {
+                self.history_ui(ui);
+                return;
+            }
+            if self.tab == Tab::Fleet {
+                self.fleet_ui(ui);
+                return;
+            }
+            if self.tab == Tab::Diagnostic {
+                self.diagnostic_ui(ui);
+                return;
+            }
+            if self.tab == Tab::Doctor {
+                self.doctor_ui(ui);
+                return;
+            }
+            ui.horizontal(|ui| {
+                let username_label = ui.label("Nom d'Utilisateur VM : ");
+                ui.text_edit_singleline(&mut self.username)
+                    .labelled_by(username_label.id);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Mot de passe VM : ");
+                ui.add(egui::TextEdit::singleline(&mut self.pwd).password(true));
+                if ui
+                    .add_enabled(!self.pwd.is_empty(), egui::Button::new("Copier"))
+                    .on_hover_text("Le presse-papiers est vidé après 30 secondes, ou à la fermeture, s'il contient encore le mot de passe")
+                    .clicked()
+                {
+                    ui.ctx().copy_text(self.pwd.clone());
+                    self.copied_password = Some((self.pwd.clone(), Instant::now() + CLIPBOARD_CLEAR_DELAY));
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Clé (.pem) : ");
+                ui.text_edit_singleline(&mut self.key_pair_file)
+                    .on_hover_text("Vide : clé configurée pour la VM");
+                if ui
+                    .add_enabled(self.password_receiver.is_none(), egui::Button::new("Récupérer le mot de passe"))
+                    .on_disabled_hover_text("Récupération en cours")
+                    .clicked()
+                {
+                    self.fetch_windows_password();
+                }
+            });
+            ui.horizontal(|ui| {
+                let updating = self.credentials_update_receiver.is_some();
+                if ui
+                    .add_enabled(!self.pwd.is_empty() && !updating, egui::Button::new("Enregistrer les identifiants"))
+                    .on_hover_text("Stockés dans le coffre de mots de passe du système pour cette VM")
+                    .on_disabled_hover_text("Saisir le mot de passe, ou attendre la fin de l'enregistrement en cours")
+                    .clicked()
+                {
+                    self.store_credentials();
+                }
+                if ui
+                    .add_enabled(!updating, egui::Button::new("Oublier les identifiants"))
+                    .clicked()
+                {
+                    self.delete_credentials();
+                }
+            });
+            ui.checkbox(&mut self.inject_credentials, "Injecter les identifiants dans la session RDP");
             ui.horizontal(|ui| {
                 ui.label("Port local : ");
                 ui.add(egui::TextEdit::singleline(&mut self.local_port_number));
             });
-            ui.horizontal(|ui| {
-                ui.selectable_value(&mut self.vm_target, self.vm1_target.clone(), "VM 1");
-                ui.selectable_value(&mut self.vm_target, self.vm2_target.clone(), "VM 2");
-                ui.selectable_value(&mut self.vm_target, self.vm3_target.clone(), "VM 3");
-                
+            self.targets_ui(ui);
+            ui.add_enabled_ui(self.remediation_receiver.is_none(), |ui| {
+                ui.menu_button("Dépannage", |ui| self.remediation_menu(ui))
+                    .response
+                    .on_hover_text("Commandes lancées sur la VM sélectionnée par SSM Run Command, leur sortie s'affiche dans le journal")
+                    .on_disabled_hover_text("Commande en cours");
             });
-            egui::ComboBox::from_label("Choisir une connection RDP")
-                .selected_text(
-                    &self
-                        .selected_rdp_file.as_ref()
-                        .and_then(|path| path.file_name())
-                        .and_then(|os_str| os_str.to_str())
-                        .map(|s| s.to_string())
-                        .unwrap_or_default(),
-                )
-                .show_ui(ui, |ui| {
-                    self.rdp_files
-                        .iter()
-                        .filter_map(|path| path.file_name().map(|file_name| (path, file_name)))
-                        .filter_map(|(path, os_str)| os_str.to_str().map(|s| (path, s)))
-                        .for_each(|(path, file_name)| {
+            let forwards = self.targets.get(self.selected_target).map(|target| target.forwards.clone()).unwrap_or_default();
+            let selected_forward = self.selected_forward();
+            if !forwards.is_empty() {
+                egui::ComboBox::from_label("Client")
+                    .selected_text(selected_forward.as_ref().map(|forward| forward.name.as_str()).unwrap_or("RDP"))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.selected_forward, None, "RDP");
+                        for forward in &forwards {
                             ui.selectable_value(
-                                &mut self.selected_rdp_file,
-                                Some(path.to_owned()),
-                                file_name,
+                                &mut self.selected_forward,
+                                Some(forward.name.clone()),
+                                format!("{} (port {}, {})", forward.name, forward.remote_port, forward.launcher),
                             );
+                        }
+                    });
+            }
+            ui.checkbox(&mut self.tunnel_only, "Tunnel seul, sans lancer de client")
+                .on_hover_text("Le tunnel reste ouvert jusqu'à l'arrêt, pour un client lancé à la main sur l'adresse locale");
+            if selected_forward.is_none() && !self.tunnel_only {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Choisir une connection RDP")
+                        .selected_text(
+                            self.selected_rdp_file
+                                .as_deref()
+                                .map(rdp_file_label)
+                                .unwrap_or_default(),
+                        )
+                        .show_ui(ui, |ui| {
+                            for path in &self.rdp_files {
+                                ui.selectable_value(
+                                    &mut self.selected_rdp_file,
+                                    Some(path.to_owned()),
+                                    rdp_file_label(path),
+                                );
+                            }
                         });
+                    if ui
+                        .add_enabled(
+                            self.picked_rdp_file_receiver.is_none(),
+                            egui::Button::new("Ajouter un fichier…"),
+                        )
+                        .on_hover_text("Ajoute un fichier .rdp d'un autre dossier jusqu'à la fermeture de l'application")
+                        .on_disabled_hover_text("Sélection en cours")
+                        .clicked()
+                    {
+                        self.pick_rdp_file();
+                    }
                 });
+            }
             ui.horizontal(|ui| {
                 if ui
-                    .add_enabled(!self.disabled, egui::Button::new("Connection"))
-                    .on_disabled_hover_text("Tunnel déjà lancé")
+                    .add_enabled(!self.port_in_use(), egui::Button::new("Connection"))
+                    .on_disabled_hover_text("Port local déjà utilisé par un tunnel")
                     .clicked()
                 {
                     let rdp_file_path_str_opt = self.selected_rdp_file.as_ref().and_then(|path| path.to_str()).map(|s| s.to_string());
+                    let connection_opt = match selected_forward {
+                        Some(forward) if self.tunnel_only => Some(Connection::Tunnel(forward.remote_port.to_string())),
+                        None if self.tunnel_only => Some(Connection::Tunnel(ssm::RDP_PORT_NUMBER.to_string())),
+                        Some(forward) => Some(Connection::Forward(forward)),
+                        None => rdp_file_path_str_opt.map(|path| Connection::Rdp(path, self.username.clone())),
+                    };
+                    let target_opt = self.targets.get(self.selected_target).cloned();
 
-                    if let Some(rdp_file_path_str) = rdp_file_path_str_opt {
+                    if let (Some(connection), Some(target)) = (connection_opt, target_opt) {
+                        let forward = self.selected_forward().map(|forward| forward.name);
+                        let tunnel_only = matches!(connection, Connection::Tunnel(_));
 
                         let (tx_exit, rx_exit) = oneshot::channel();
 
-                        self.application_exit_sender = Some(tx_exit);
-
-                        let target = self.vm_target.clone();
                         let logs_sender = self.logs_sender.clone();
                         let local_port_number = self.local_port_number.clone();
+                        let commands = self.commands.clone();
+                        let session_target = target.clone();
+                        let (session_info_sender, session_info_receiver) = std::sync::mpsc::channel();
+                        let deadline = target.session.max_session_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+                        let (deadline_sender, deadline_receiver) = tokio::sync::watch::channel(deadline);
+                        // With a credentials source, the typed username is the default of a secret without one
+                        let has_credentials = !self.pwd.is_empty() || target.credentials_source.is_some();
+                        let credentials = (self.inject_credentials && has_credentials).then(|| RdpCredentials {
+                            username: self.username.clone(),
+                            password: self.pwd.clone(),
+                        });
 
                         // Spawns a thread that spawns a tokio task so that
                         // the gui stays synchronous while still making sure tasks are done
-                        self.join_handler = Some(std::thread::spawn(move || {
+                        let join_handler = std::thread::spawn(move || {
                             tokio::runtime::Builder::new_multi_thread()
                                 .enable_all()
                                 .build()
                                 .unwrap()
-                                .block_on(tasks_handler::start(
-                                    target,
-                                    rdp_file_path_str,
-                                    rx_exit,
-                                    local_port_number,
-                                    logs_sender,
-                                ))
-                        }));
-
-                        self.disabled = true;
-                        self.handler_running = true;
+                                .block_on(async move {
+                                    // One client per tunnel, in the region of its target
+                                    if let Some(arn) = ssm::log_caller_identity(&target, &logs_sender).await {
+                                        let _ = session_info_sender.send(SessionInfoMessage::Identity(arn));
+                                    }
+                                    let session_api = session_api::SdkSessionApi::new(&target).await;
+                                    tasks_handler::start(
+                                        session_api,
+                                        ConnectionSpec {
+                                            target,
+                                            connection,
+                                            credentials,
+                                            local_port_number,
+                                            commands,
+                                        },
+                                        HandlerChannels {
+                                            app_exit_receiver: rx_exit,
+                                            session_info_sender,
+                                            deadline_receiver,
+                                            logs_sender,
+                                        },
+                                    )
+                                    .await
+                                })
+                        });
+
+                        self.sessions.push(Session {
+                            target: session_target,
+                            forward,
+                            tunnel_only,
+                            local_port_number: self.local_port_number.clone(),
+                            started_at: SystemTime::now(),
+                            session_info_receiver,
+                            aws_identity: None,
+                            session_id: None,
+                            region: None,
+                            idle_closes_at: None,
+                            idle_closed: false,
+                            deadline_sender,
+                            extensions: 0,
+                            max_duration_reached: false,
+                            traffic: None,
+                            throughput_sample: None,
+                            throughput: 0,
+                            application_exit_sender: Some(tx_exit),
+                            join_handler: Some(join_handler),
+                        });
                     } else {
                         let logs_sender = self.logs_sender.clone();
-                        send_log("GUI : Error while trying to launch connection, file invalid or does not exist".into(), &logs_sender);
+                        send_log("GUI : Error while trying to launch connection, file or target invalid or does not exist".into(), &logs_sender);
+                    }
+                }
+            });
+            if !self.sessions.is_empty() {
+                ui.separator();
+                ui.label("Tunnels actifs :");
+            }
+            for session in self.sessions.iter_mut() {
+                ui.horizontal(|ui| {
+                    ui.label(session.label());
+                    if session.tunnel_only {
+                        let address = format!("{}:{}", ssm::LOCAL_HOST, session.local_port_number);
+                        ui.monospace(&address);
+                        if ui.button("Copier l'adresse").clicked() {
+                            ui.ctx().copy_text(address);
+                        }
+                    }
+                    if let Some(closes_at) = session.idle_closes_at {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            format!("Inactif, fermeture dans {} s", closes_at.saturating_duration_since(Instant::now()).as_secs()),
+                        )
+                        .on_hover_text("Aucun trafic sur le port local, reconnectez le client RDP pour garder le tunnel ouvert");
+                    }
+                    let deadline = *session.deadline_sender.borrow();
+                    if let Some(deadline) = deadline {
+                        let left = deadline.saturating_duration_since(Instant::now()).as_secs();
+                        let countdown = ui.label(format!("Fin dans {}:{:02}:{:02}", left / 3600, left % 3600 / 60, left % 60));
+                        if session.target.session.locked {
+                            countdown.on_hover_text("Durée maximale fixée par la politique de l'organisation");
+                        }
+                        if ui
+                            .add_enabled(session.can_extend(), egui::Button::new("Prolonger"))
+                            .on_hover_text(format!("Ajoute {} min", session.target.session.extend_secs / 60))
+                            .on_disabled_hover_text("Plus de prolongation possible")
+                            .clicked()
+                        {
+                            session.extend(&self.logs_sender);
+                        }
+                    }
+                    if ui
+                        .add_enabled(session.application_exit_sender.is_some(), egui::Button::new("Arrêter"))
+                        .on_disabled_hover_text("Arrêt en cours")
+                        .clicked()
+                    {
+                        session.stop(&self.logs_sender);
                     }
+                });
+                if let Some(tunnel) = session.status() {
+                    ui.weak(format!(
+                        "↑ {}  ↓ {}  {}/s  {} connexion(s)  depuis {}  réponse {}",
+                        status::format_bytes(tunnel.bytes_sent),
+                        status::format_bytes(tunnel.bytes_received),
+                        status::format_bytes(tunnel.throughput),
+                        tunnel.connections,
+                        status::format_duration(tunnel.uptime_secs),
+                        status::format_response_time(tunnel.response_time_ms),
+                    ))
+                    .on_hover_text("Temps de réponse de la VM à travers le tunnel, aller-retour du data channel compris");
                 }
+            }
+            ui.separator();
+            egui::CollapsingHeader::new("Journal").show(ui, |ui| {
+                egui::ScrollArea::vertical()
+                    .max_width(f32::INFINITY)
+                    .max_height(200f32)
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        ui.group(|ui| {
+                            ui.label(&self.logs_output);
+                            ui.set_width(ui.available_width());
+                        });
+                    })
             });
-            // For debug, no need in production since we output to log file
-            // egui::ScrollArea::vertical()
-            //     .max_width(f32::INFINITY)
-            //     .stick_to_bottom(true)
-            //     .show(ui, |ui| {
-            //         ui.group(|ui| {
-            //             let new_log = self.logs_receiver.try_recv().unwrap_or_default();
-            //             self.logs_output.push_str(&new_log);
-
-            //             ui.label(&self.logs_output);
-            //             ui.set_width(ui.available_width());
-            //         });
-            //     })
         });
     }
 }
 
 #[tokio::main]
 async fn main() -> eframe::Result {
+    if let Some(command) = cli::Opt::parse().command {
+        std::process::exit(cli::run(command).await);
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([500f32, 300f32])
@@ -1,7 +1,38 @@
+use std::sync::Arc;
+
+use crate::relay::TrafficStats;
+
 #[derive(Debug)]
 pub struct SSMTunnelLaunchedMessage {
-    pub ok: bool
+    pub ok: bool,
+    pub session_id: String,
+    /// Whether the plugin serves several connections, closing one then leaves the session up
+    pub multiplexing: bool
+}
+
+#[derive(Debug)]
+pub struct ApplicationExitedMessage;
+
+/// The session ended on the server side while the tunnel was up
+#[derive(Debug)]
+pub struct TunnelClosedMessage {
+    pub timed_out: bool,
+    pub reason: String
 }
 
+/// What a running connection learns about itself, shown in the GUI and kept in the session history
 #[derive(Debug)]
-pub struct ApplicationExitedMessage;
\ No newline at end of file
+pub enum SessionInfoMessage {
+    Identity(String),
+    SessionId(String),
+    /// Resolved from the target, `--region` or the profile
+    Region(String),
+    /// Counters of the tunnel, updated while it runs
+    Traffic(Arc<TrafficStats>),
+    /// No traffic for a while, seconds left before the connection is closed
+    IdleWarning(u64),
+    /// Traffic again after a warning
+    Active,
+    IdleClosed,
+    MaxDurationReached
+}
\ No newline at end of file
@@ -1,9 +1,18 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+#[cfg(not(windows))]
+use std::io::Write;
+#[cfg(windows)]
 use std::os::windows::process::CommandExt;
 use std::{path::PathBuf, process::Command};
 use tokio::sync::oneshot::Receiver;
 
+use crate::config::LauncherConfig;
 use crate::messages::ApplicationExitedMessage;
-use crate::utils::{CREATE_NO_WINDOW, send_log};
+use crate::ssm::LOCAL_HOST;
+use crate::utils::send_log;
+#[cfg(windows)]
+use crate::utils::CREATE_NO_WINDOW;
 
 #[derive(Debug)]
 pub struct RDPError {
@@ -15,28 +24,130 @@ pub struct RDPError {
 enum RDPErrorKind {
     Kill,
     Spawn,
+    Exit,
+}
+
+#[cfg(windows)]
+const DEFAULT_RDP_HOST: &str = "localhost";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RdpCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for RdpCredentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RdpCredentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
 }
 
 pub struct RDPTaskInstance {
     receiver_app_exit: Receiver<ApplicationExitedMessage>,
     logs_sender: std::sync::mpsc::Sender<String>,
+    _staged_credentials: Option<StagedCredentials>,
+    /// Whether the connection ends with the client
+    wait: bool,
     pub task_handler: std::process::Child,
 }
 
+/**
+ * The .rdp file to open through the local port, with `rdp_client` or the launcher of the target
+ */
+pub struct RdpLaunch {
+    pub path: String,
+    pub local_port_number: String,
+    pub rdp_client: String,
+    pub launcher: Option<LauncherConfig>,
+    /// That of the GUI, given to the launcher when there are no credentials
+    pub username: String,
+    pub credentials: Option<RdpCredentials>,
+}
+
 impl RDPTaskInstance {
+    /**
+     * Starts `rdp_client` on the .rdp file, or the launcher when the target has one
+     */
     pub fn spawn(
-        path: String,
+        launch: RdpLaunch,
         receiver_app_exit: Receiver<ApplicationExitedMessage>,
         logs_sender: std::sync::mpsc::Sender<String>,
     ) -> Result<RDPTaskInstance, RDPError> {
-        spawn_rdp(path, &logs_sender)
-            .map_err(|e| RDPError {
-                kind: RDPErrorKind::Spawn,
-                msg: "Failed to start RDP task, ".to_string() + &e.to_string(),
-            })
+        let RdpLaunch {
+            path,
+            local_port_number,
+            rdp_client,
+            launcher,
+            username,
+            credentials,
+        } = launch;
+        #[cfg(windows)]
+        let staged_credentials = credentials.as_ref().and_then(|credentials| {
+            // The host the client connects to, `{host}` for a launcher
+            let host = match &launcher {
+                Some(_) => LOCAL_HOST.to_string(),
+                None => read_rdp_file(&path)
+                    .and_then(|content| full_address_host(&content))
+                    .unwrap_or_else(|| DEFAULT_RDP_HOST.to_string()),
+            };
+            stage_credentials(&host, &local_port_number, credentials, &logs_sender)
+        });
+        #[cfg(not(windows))]
+        let staged_credentials: Option<StagedCredentials> = None;
+
+        let wait = launcher.as_ref().is_none_or(|launcher| launcher.wait);
+        let child = match &launcher {
+            Some(launcher) => {
+                let username = credentials
+                    .as_ref()
+                    .map(|credentials| credentials.username.as_str())
+                    .filter(|username| !username.is_empty())
+                    .unwrap_or(username.as_str());
+                let placeholders = [
+                    ("host", LOCAL_HOST),
+                    ("port", local_port_number.as_str()),
+                    ("rdp_file", path.as_str()),
+                    ("username", username),
+                ];
+                spawn_launcher(launcher, &placeholders, &logs_sender)
+            }
+            None => spawn_rdp(path, &rdp_client, credentials.as_ref(), &logs_sender),
+        };
+        // On failure the staged credentials are dropped, and removed, right here
+        child.map_err(spawn_error).map(|c| RDPTaskInstance {
+            receiver_app_exit,
+            logs_sender,
+            _staged_credentials: staged_credentials,
+            wait,
+            task_handler: c,
+        })
+    }
+
+    /**
+     * Starts the launcher of a forward, which has neither .rdp file nor credentials
+     */
+    pub fn spawn_forward(
+        launcher: LauncherConfig,
+        local_port_number: String,
+        username: Option<String>,
+        receiver_app_exit: Receiver<ApplicationExitedMessage>,
+        logs_sender: std::sync::mpsc::Sender<String>,
+    ) -> Result<RDPTaskInstance, RDPError> {
+        let placeholders = [
+            ("host", LOCAL_HOST),
+            ("port", local_port_number.as_str()),
+            ("username", username.as_deref().unwrap_or_default()),
+        ];
+        spawn_launcher(&launcher, &placeholders, &logs_sender)
+            .map_err(spawn_error)
             .map(|c| RDPTaskInstance {
                 receiver_app_exit,
                 logs_sender,
+                _staged_credentials: None,
+                wait: launcher.wait,
                 task_handler: c,
             })
     }
@@ -48,16 +159,35 @@ impl RDPTaskInstance {
                 Err(_) => {
                     // Check whether RDP task is still running
                     match self.task_handler.try_wait() {
-                        Ok(opt) => {
-                            if opt.is_some() {
-                                send_log(
-                                    "RDP Task Instance : RDP task over, stop tunnel and handler"
-                                        .into(),
-                                    &self.logs_sender,
-                                );
-                                break;
-                            }
+                        Ok(Some(status)) if !self.wait => {
+                            send_log(
+                                format!(
+                                    "RDP Task Instance : Launcher over ({}), the tunnel stays up until stopped",
+                                    status
+                                ),
+                                &self.logs_sender,
+                            );
+                            // Sender dropped means the app is gone too
+                            let _ = (&mut self.receiver_app_exit).await;
+                            return Ok(());
+                        }
+                        Ok(Some(status)) => {
+                            send_log(
+                                format!(
+                                    "RDP Task Instance : RDP task over ({}), stop tunnel and handler",
+                                    status
+                                ),
+                                &self.logs_sender,
+                            );
+                            return match status.success() {
+                                true => Ok(()),
+                                false => Err(RDPError {
+                                    kind: RDPErrorKind::Exit,
+                                    msg: format!("RDP client exited with {}", status),
+                                }),
+                            };
                         }
+                        Ok(None) => {}
                         Err(_) => {
                             send_log("RDP Task Instance : RDP task exited with error, stop tunnel and handler".into(), &self.logs_sender);
                             break;
@@ -67,30 +197,277 @@ impl RDPTaskInstance {
                 }
 
                 // App exit message has been received
-                _ => {
-                    if self.task_handler.try_wait().is_err() {
-                        return self.task_handler.kill().map_err(|_| RDPError {
-                            kind: RDPErrorKind::Kill,
-                            msg: "Error while trying to kill RDP task".into(),
-                        });
-                    }
-                }
+                Ok(_) => return self.kill(),
             }
         }
         Ok(())
     }
+
+    pub fn kill(&mut self) -> Result<(), RDPError> {
+        match self.task_handler.try_wait() {
+            Ok(None) => self.task_handler.kill().map_err(|_| RDPError {
+                kind: RDPErrorKind::Kill,
+                msg: "Error while trying to kill RDP task".into(),
+            }),
+            _ => Ok(()),
+        }
+    }
 }
 
+/**
+ * Credentials staged for the RDP client, removed when dropped whichever way the session ends
+ */
+// Only staged on Windows, FreeRDP gets the credentials on its stdin
+#[cfg_attr(not(windows), allow(dead_code))]
+struct StagedCredentials {
+    entry: keyring::Entry,
+    logs_sender: std::sync::mpsc::Sender<String>,
+}
+
+impl Drop for StagedCredentials {
+    fn drop(&mut self) {
+        // The credential is only needed while the client logs in, never keep it around
+        match self.entry.delete_credential() {
+            Ok(_) => send_log(
+                "RDP Task Instance : Staged credentials removed".into(),
+                &self.logs_sender,
+            ),
+            Err(e) => send_log(
+                format!(
+                    "RDP Task Instance : Unable to remove staged credentials : {}",
+                    e
+                ),
+                &self.logs_sender,
+            ),
+        }
+    }
+}
+
+/**
+ * mstsc picks up generic credentials named `TERMSRV/<host>:<port>` when connecting to
+ * `<host>:<port>`, the same mechanism as `cmdkey /generic`, without exposing the password in
+ * argv. Every session has its own local port, so sessions never share an entry
+ */
+#[cfg(windows)]
+fn stage_credentials(
+    host: &str,
+    local_port_number: &str,
+    credentials: &RdpCredentials,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Option<StagedCredentials> {
+    let address = format!("{}:{}", host, local_port_number);
+
+    let staged = keyring::Entry::new_with_target(
+        &format!("TERMSRV/{}", address),
+        crate::credentials::CREDENTIALS_SERVICE,
+        &credentials.username,
+    )
+    .and_then(|entry| entry.set_password(&credentials.password).map(|_| entry));
+
+    match staged {
+        Ok(entry) => {
+            send_log(
+                format!("RDP Task Instance : Credentials staged for {}", address),
+                logs_sender,
+            );
+            Some(StagedCredentials {
+                entry,
+                logs_sender: logs_sender.clone(),
+            })
+        }
+        Err(e) => {
+            send_log(
+                format!("RDP Task Instance : Unable to stage credentials : {}", e),
+                logs_sender,
+            );
+            None
+        }
+    }
+}
+
+// mstsc saves .rdp files as UTF-16LE with a BOM, hand written ones are usually UTF-8
+#[cfg(windows)]
+fn read_rdp_file(path: &str) -> Option<String> {
+    let bytes = std::fs::read(path).ok()?;
+    match bytes.strip_prefix(&[0xFF, 0xFE]) {
+        Some(utf16) => {
+            let units = utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect::<Vec<u16>>();
+            String::from_utf16(&units).ok()
+        }
+        None => String::from_utf8(bytes).ok(),
+    }
+}
+
+#[cfg(windows)]
+fn full_address_host(content: &str) -> Option<String> {
+    content
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("full address:s:"))
+        .map(|address| match address.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+            _ => address,
+        })
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+}
+
+#[cfg(windows)]
 fn spawn_rdp(
     path: String,
+    rdp_client: &str,
+    _credentials: Option<&RdpCredentials>,
     logs_sender: &std::sync::mpsc::Sender<String>,
 ) -> Result<std::process::Child, std::io::Error> {
     send_log(
         format!("RDP Task Instance : Launch RDP for file {}", path),
         logs_sender,
     );
+    // The empty title keeps `start` from taking a quoted client path for the window title
     Command::new("cmd")
-        .args(["/c", "start", "/wait", "mstsc", &path])
+        .args(["/c", "start", "", "/wait", rdp_client, &path])
         .creation_flags(CREATE_NO_WINDOW)
         .spawn()
 }
+
+/**
+ * FreeRDP 3 reads its whole command line from stdin with `/args-from:stdin`,
+ * so `/u` and `/p` never show up in the process list
+ */
+#[cfg(not(windows))]
+fn spawn_rdp(
+    path: String,
+    rdp_client: &str,
+    credentials: Option<&RdpCredentials>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<std::process::Child, std::io::Error> {
+    send_log(
+        format!("RDP Task Instance : Launch RDP for file {}", path),
+        logs_sender,
+    );
+    let mut child = Command::new(rdp_client)
+        .arg("/args-from:stdin")
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+
+    let mut args = vec![path];
+    if let Some(credentials) = credentials {
+        args.push(format!("/u:{}", credentials.username));
+        args.push(format!("/p:{}", credentials.password));
+    }
+
+    // stdin is closed when dropped at the end of this block, which ends the argument list
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(args.join("\n").as_bytes()) {
+            // A client started without its file or credentials is of no use, do not leave it behind
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+    }
+
+    Ok(child)
+}
+
+fn spawn_error(e: std::io::Error) -> RDPError {
+    RDPError {
+        kind: RDPErrorKind::Spawn,
+        msg: "Failed to start RDP task, ".to_string() + &e.to_string(),
+    }
+}
+
+/**
+ * Replaces each `{name}` by its value, leaving unknown names as they are
+ */
+fn expand(template: &str, placeholders: &[(&str, &str)]) -> String {
+    let mut expanded = String::new();
+    let mut rest = template;
+    // In one pass, so that a value holding braces is not expanded again
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let value = rest[start..].find('}').and_then(|end| {
+            let name = &rest[start + 1..start + end];
+            placeholders
+                .iter()
+                .find(|(placeholder, _)| *placeholder == name)
+                .map(|(_, value)| (*value, start + end + 1))
+        });
+        match value {
+            Some((value, next)) => {
+                expanded.push_str(value);
+                rest = &rest[next..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn spawn_launcher(
+    launcher: &LauncherConfig,
+    placeholders: &[(&str, &str)],
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<std::process::Child, std::io::Error> {
+    let program = expand(&launcher.command, placeholders);
+    let args: Vec<String> = launcher
+        .args
+        .iter()
+        .map(|arg| expand(arg, placeholders))
+        .collect();
+    send_log(
+        format!("RDP Task Instance : Launch {} {}", program, args.join(" ")),
+        logs_sender,
+    );
+    let mut command = Command::new(program);
+    command.args(args).envs(
+        launcher
+            .env
+            .iter()
+            .map(|(name, value)| (name, expand(value, placeholders))),
+    );
+    #[cfg(windows)]
+    command.creation_flags(CREATE_NO_WINDOW);
+    command.spawn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let placeholders = [("host", "127.0.0.1"), ("port", "55678"), ("username", "")];
+        assert_eq!(
+            expand("/v:{host}:{port}", &placeholders),
+            "/v:127.0.0.1:55678"
+        );
+        assert_eq!(
+            expand("rdp://{username}@{host}:{port}", &placeholders),
+            "rdp://@127.0.0.1:55678"
+        );
+        assert_eq!(expand("{rdp_file}", &placeholders), "{rdp_file}");
+        assert_eq!(
+            expand("{port}", &[("port", "{host}"), ("host", "localhost")]),
+            "{host}"
+        );
+        assert_eq!(expand("{{port}", &placeholders), "{55678");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_full_address_host() {
+        let content = "screen mode id:i:2\r\nfull address:s:localhost:55678\r\nusername:s:Administrator\r\n";
+        assert_eq!(full_address_host(content), Some("localhost".to_string()));
+        assert_eq!(
+            full_address_host("full address:s:127.0.0.1"),
+            Some("127.0.0.1".to_string())
+        );
+        assert_eq!(full_address_host("username:s:Administrator"), None);
+    }
+}
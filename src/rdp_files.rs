@@ -0,0 +1,231 @@
+use notify::event::{CreateKind, ModifyKind, RemoveKind};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::RdpFilesConfig;
+use crate::utils::{LOGS_FILE, send_log};
+use crate::{history, status};
+
+const RDP_EXTENSION: &str = "rdp";
+
+pub fn is_rdp_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(RDP_EXTENSION))
+}
+
+/**
+ * The .rdp files of the configured directories, sorted. A directory that cannot be read is
+ * logged and skipped
+ */
+pub fn find(
+    config: &RdpFilesConfig,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for directory in &config.directories {
+        if let Err(e) = collect(Path::new(directory), config.recursive, &mut files) {
+            send_log(
+                format!("RDP files : Unable to read {} : {}", directory, e),
+                logs_sender,
+            );
+        }
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+fn collect(directory: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        // Symbolic links are not followed into, a link to a parent would never end
+        if entry.file_type()?.is_dir() {
+            if recursive {
+                // An unreadable subdirectory does not hide the rest
+                let _ = collect(&path, recursive, files);
+            }
+        } else if is_rdp_file(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Sends on `changed_sender` whenever .rdp files may have been added, removed or renamed in the
+ * configured directories, until the watcher is dropped
+ */
+pub fn watch(
+    config: &RdpFilesConfig,
+    changed_sender: std::sync::mpsc::Sender<()>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Option<RecommendedWatcher> {
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if event.is_ok_and(|event| changes_rdp_files(&event)) {
+            // Receiver is gone when the app exited meanwhile
+            let _ = changed_sender.send(());
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            send_log(
+                format!("RDP files : Unable to watch for changes : {}", e),
+                logs_sender,
+            );
+            return None;
+        }
+    };
+    let mode = if config.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    for directory in &config.directories {
+        if let Err(e) = watcher.watch(Path::new(directory), mode) {
+            send_log(
+                format!("RDP files : Unable to watch {} : {}", directory, e),
+                logs_sender,
+            );
+        }
+    }
+    Some(watcher)
+}
+
+fn changes_rdp_files(event: &Event) -> bool {
+    // A directory coming or going may hold .rdp files, whatever its name
+    let folder = matches!(
+        event.kind,
+        EventKind::Create(CreateKind::Folder) | EventKind::Remove(RemoveKind::Folder)
+    );
+    match event.kind {
+        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+            event
+                .paths
+                .iter()
+                .filter(|path| !is_own_file(path))
+                .any(|path| folder || is_rdp_file(path) || may_be_directory(path))
+        }
+        _ => false,
+    }
+}
+
+// A path that is gone cannot be checked, one without an extension most likely was a directory
+fn may_be_directory(path: &Path) -> bool {
+    path.is_dir() || (!path.exists() && path.extension().is_none())
+}
+
+// Written by the application in its working directory, which is usually watched too
+fn is_own_file(path: &Path) -> bool {
+    let status_temp_file = status::temp_path(Path::new(status::STATUS_FILE));
+    path.file_name().is_some_and(|name| {
+        name == status::STATUS_FILE
+            || name == status_temp_file.as_os_str()
+            || name == history::HISTORY_FILE
+            || name == LOGS_FILE
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vm-connect-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("team").join("prod")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_find() {
+        let dir = test_dir("rdp-files");
+        for file in [
+            "VM 1.rdp",
+            "notes.txt",
+            "team/VM 2.RDP",
+            "team/prod/VM 3.rdp",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let (logs_sender, logs_receiver) = std::sync::mpsc::channel();
+        let mut config = RdpFilesConfig {
+            directories: vec![
+                dir.to_string_lossy().into_owned(),
+                dir.join("missing").to_string_lossy().into_owned(),
+            ],
+            recursive: false,
+        };
+
+        assert_eq!(find(&config, &logs_sender), vec![dir.join("VM 1.rdp")]);
+        assert!(logs_receiver.try_iter().any(|log| log.contains("missing")));
+
+        config.recursive = true;
+        assert_eq!(
+            find(&config, &logs_sender),
+            vec![
+                dir.join("VM 1.rdp"),
+                dir.join("team").join("VM 2.RDP"),
+                dir.join("team").join("prod").join("VM 3.rdp"),
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_changes_rdp_files() {
+        let dir = test_dir("rdp-changes");
+        let event = |kind: EventKind, path: PathBuf| Event::new(kind).add_path(path);
+
+        let create = EventKind::Create(CreateKind::File);
+        assert!(changes_rdp_files(&event(create, dir.join("VM 5.rdp"))));
+        assert!(changes_rdp_files(&event(create, dir.join("team"))));
+        assert!(!changes_rdp_files(&event(create, dir.join("notes.txt"))));
+        assert!(!changes_rdp_files(&event(
+            EventKind::Modify(ModifyKind::Any),
+            dir.join("VM 5.rdp")
+        )));
+        assert!(changes_rdp_files(&event(
+            EventKind::Remove(RemoveKind::Folder),
+            dir.join("old.d")
+        )));
+        assert!(changes_rdp_files(&event(
+            EventKind::Remove(RemoveKind::Any),
+            dir.join("old")
+        )));
+        for own_file in [
+            status::STATUS_FILE,
+            "vm-connect-sessions.json.tmp",
+            history::HISTORY_FILE,
+            LOGS_FILE,
+        ] {
+            assert!(!changes_rdp_files(&event(create, dir.join(own_file))));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = test_dir("rdp-watch");
+        let config = RdpFilesConfig {
+            directories: vec![dir.to_string_lossy().into_owned()],
+            recursive: true,
+        };
+        let (changed_sender, changed_receiver) = std::sync::mpsc::channel();
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let _watcher = watch(&config, changed_sender, &logs_sender).unwrap();
+
+        fs::write(dir.join("team").join("prod").join("VM 4.rdp"), "").unwrap();
+        assert!(
+            changed_receiver
+                .recv_timeout(Duration::from_secs(5))
+                .is_ok(),
+            "Adding a .rdp file in a subdirectory went unnoticed"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// TPKT header, X.224 Connection Request and RDP Negotiation Request asking for TLS,
+// CredSSP and CredSSP with early user authorization, as mstsc does
+const CONNECTION_REQUEST: [u8; 19] = [
+    0x03, 0x00, 0x00, 0x13, // TPKT, version 3, length 19
+    0x0e, 0xe0, 0x00, 0x00, 0x00, 0x00, 0x00, // X.224 CR, no cookie
+    0x01, 0x00, 0x08, 0x00, 0x0b, 0x00, 0x00, 0x00, // RDP_NEG_REQ
+];
+const TPKT_VERSION: u8 = 0x03;
+const TPKT_HEADER_LENGTH: usize = 4;
+const X224_CONNECTION_CONFIRM: u8 = 0xd0;
+const NEG_RESPONSE: u8 = 0x02;
+const NEG_FAILURE: u8 = 0x03;
+const NEG_LENGTH: usize = 8;
+
+#[derive(Debug)]
+pub struct ProbeError {
+    pub kind: ProbeErrorKind,
+    pub msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProbeErrorKind {
+    Connect,
+    Timeout,
+    Closed,
+    Invalid,
+}
+
+/**
+ * Security protocol chosen by the RDP service in its Connection Confirm
+ */
+#[derive(Debug, PartialEq)]
+pub enum SecurityProtocol {
+    /// Standard RDP security, the service did not negotiate
+    Rdp,
+    Tls,
+    CredSsp,
+    CredSspEarlyUserAuth,
+    Other(u32),
+}
+
+impl Display for SecurityProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rdp => write!(f, "Standard RDP Security"),
+            Self::Tls => write!(f, "TLS"),
+            Self::CredSsp => write!(f, "CredSSP"),
+            Self::CredSspEarlyUserAuth => write!(f, "CredSSP with Early User Authorization"),
+            Self::Other(protocol) => write!(f, "protocol {:#x}", protocol),
+        }
+    }
+}
+
+/**
+ * What the RDP service answered, either way it runs behind the tunnel
+ */
+#[derive(Debug, PartialEq)]
+pub enum ProbeAnswer {
+    /// Security protocol chosen in its Connection Confirm
+    Confirmed(SecurityProtocol),
+    /// None of the protocols of the probe, the client may still agree on another one
+    NegotiationFailed(String),
+}
+
+impl From<u32> for SecurityProtocol {
+    fn from(protocol: u32) -> Self {
+        match protocol {
+            0x0 => Self::Rdp,
+            0x1 => Self::Tls,
+            0x2 => Self::CredSsp,
+            0x8 => Self::CredSspEarlyUserAuth,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/**
+ * Opens a connection to the local port and checks that the RDP service behind the tunnel
+ * answers the Connection Request with a Connection Confirm. The connection is then dropped,
+ * which ends the session of a plugin without multiplexing: only probe through one that has it
+ */
+pub async fn probe(local_port: u16, timeout: Duration) -> Result<ProbeAnswer, ProbeError> {
+    tokio::time::timeout(timeout, exchange(local_port))
+        .await
+        .map_err(|_| ProbeError {
+            kind: ProbeErrorKind::Timeout,
+            msg: format!(
+                "probe : No answer from the RDP service within {}s",
+                timeout.as_secs()
+            ),
+        })?
+}
+
+async fn exchange(local_port: u16) -> Result<ProbeAnswer, ProbeError> {
+    let mut stream = TcpStream::connect(("127.0.0.1", local_port))
+        .await
+        .map_err(|e| ProbeError {
+            kind: ProbeErrorKind::Connect,
+            msg: format!("probe : Unable to connect to port {} : {}", local_port, e),
+        })?;
+    stream
+        .write_all(&CONNECTION_REQUEST)
+        .await
+        .map_err(closed)?;
+
+    let mut header = [0; TPKT_HEADER_LENGTH];
+    stream.read_exact(&mut header).await.map_err(closed)?;
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if header[0] != TPKT_VERSION || length <= TPKT_HEADER_LENGTH {
+        return Err(invalid(format!("not a TPKT header {:02x?}", header)));
+    }
+    let mut body = vec![0; length - TPKT_HEADER_LENGTH];
+    stream.read_exact(&mut body).await.map_err(closed)?;
+    parse_connection_confirm(&body)
+}
+
+/**
+ * Parses the X.224 part of the answer, without its TPKT header
+ */
+fn parse_connection_confirm(body: &[u8]) -> Result<ProbeAnswer, ProbeError> {
+    // Length indicator, then the fixed part of the TPDU starting with its code
+    let (Some(&length_indicator), Some(&code)) = (body.first(), body.get(1)) else {
+        return Err(invalid("empty X.224 TPDU".into()));
+    };
+    if code & 0xf0 != X224_CONNECTION_CONFIRM {
+        return Err(invalid(format!(
+            "expected a Connection Confirm, got TPDU code {:#04x}",
+            code
+        )));
+    }
+    let negotiation = match body.get(7..) {
+        Some(negotiation) if length_indicator as usize > 6 => negotiation,
+        _ => return Ok(ProbeAnswer::Confirmed(SecurityProtocol::Rdp)),
+    };
+    if negotiation.len() < NEG_LENGTH {
+        return Err(invalid("truncated negotiation data".into()));
+    }
+    let value = u32::from_le_bytes([
+        negotiation[4],
+        negotiation[5],
+        negotiation[6],
+        negotiation[7],
+    ]);
+    match negotiation[0] {
+        NEG_RESPONSE => Ok(ProbeAnswer::Confirmed(SecurityProtocol::from(value))),
+        // Still a Connection Confirm, the service is up
+        NEG_FAILURE => Ok(ProbeAnswer::NegotiationFailed(failure_reason(value))),
+        other => Err(invalid(format!("unknown negotiation type {:#04x}", other))),
+    }
+}
+
+fn failure_reason(code: u32) -> String {
+    match code {
+        0x1 => "TLS required by the server".into(),
+        0x2 => "TLS not allowed by the server".into(),
+        0x3 => "no certificate on the server".into(),
+        0x4 => "inconsistent flags".into(),
+        0x5 => "CredSSP required by the server".into(),
+        0x6 => "TLS with user authentication required by the server".into(),
+        other => format!("failure code {:#x}", other),
+    }
+}
+
+fn closed(e: std::io::Error) -> ProbeError {
+    ProbeError {
+        kind: ProbeErrorKind::Closed,
+        msg: format!(
+            "probe : Connection closed before the RDP service answered, it may not be running on the instance : {}",
+            e
+        ),
+    }
+}
+
+fn invalid(reason: String) -> ProbeError {
+    ProbeError {
+        kind: ProbeErrorKind::Invalid,
+        msg: format!("probe : Invalid answer from the RDP service, {}", reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Answers the first Connection Request it gets with `answer`
+    async fn rdp_service(answer: Vec<u8>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; CONNECTION_REQUEST.len()];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&answer).await.unwrap();
+        });
+        port
+    }
+
+    fn connection_confirm(negotiation: &[u8]) -> Vec<u8> {
+        let length = (TPKT_HEADER_LENGTH + 7 + negotiation.len()) as u16;
+        let mut answer = vec![TPKT_VERSION, 0x00];
+        answer.extend(length.to_be_bytes());
+        answer.extend([
+            6 + negotiation.len() as u8,
+            0xd0,
+            0x00,
+            0x00,
+            0x12,
+            0x34,
+            0x00,
+        ]);
+        answer.extend(negotiation);
+        answer
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let timeout = Duration::from_secs(1);
+
+        let port = rdp_service(connection_confirm(&[0x02, 0x1f, 0x08, 0x00, 0x02, 0, 0, 0])).await;
+        assert_eq!(
+            probe(port, timeout).await.unwrap(),
+            ProbeAnswer::Confirmed(SecurityProtocol::CredSsp)
+        );
+
+        let port = rdp_service(connection_confirm(&[])).await;
+        assert_eq!(
+            probe(port, timeout).await.unwrap(),
+            ProbeAnswer::Confirmed(SecurityProtocol::Rdp)
+        );
+
+        let port = rdp_service(connection_confirm(&[0x03, 0x00, 0x08, 0x00, 0x05, 0, 0, 0])).await;
+        assert_eq!(
+            probe(port, timeout).await.unwrap(),
+            ProbeAnswer::NegotiationFailed("CredSSP required by the server".into())
+        );
+
+        // What the tunnel does when nothing listens on the instance
+        let port = rdp_service(vec![]).await;
+        assert_eq!(
+            probe(port, timeout).await.unwrap_err().kind,
+            ProbeErrorKind::Closed
+        );
+
+        // An echo sends the Connection Request back
+        let port = rdp_service(CONNECTION_REQUEST.to_vec()).await;
+        assert_eq!(
+            probe(port, timeout).await.unwrap_err().kind,
+            ProbeErrorKind::Invalid
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let e = probe(port, Duration::from_millis(200)).await.unwrap_err();
+        assert_eq!(e.kind, ProbeErrorKind::Timeout);
+    }
+}
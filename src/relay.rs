@@ -0,0 +1,401 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::utils::send_log;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+// Weight of a new response time sample in the smoothed value, as for the TCP SRTT
+const RESPONSE_TIME_GAIN: f64 = 1.0 / 8.0;
+
+/**
+ * Traffic through the local port of a tunnel, shared between the relay and whoever
+ * watches the tunnel
+ */
+#[derive(Debug)]
+pub struct TrafficStats {
+    active_connections: AtomicUsize,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    last_activity: Mutex<Instant>,
+    up_since: Mutex<Option<Instant>>,
+    response_time: Mutex<Option<Duration>>,
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        TrafficStats {
+            active_connections: AtomicUsize::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            last_activity: Mutex::new(Instant::now()),
+            up_since: Mutex::new(None),
+            response_time: Mutex::new(None),
+        }
+    }
+}
+
+impl TrafficStats {
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// From the client to the instance
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// From the instance to the client
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Time since a byte went through, or a connection was opened or closed
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    /// Since the relay started, once the tunnel was up
+    pub fn uptime(&self) -> Duration {
+        self.up_since
+            .lock()
+            .unwrap()
+            .map(|up_since| up_since.elapsed())
+            .unwrap_or_default()
+    }
+
+    /**
+     * Smoothed time between bytes sent to the instance and the first bytes back, as seen on
+     * the local port. The data channel acknowledgements stay within the session manager
+     * plugin, so this is the round trip plus the time the instance takes to answer, not the
+     * round trip alone
+     */
+    pub fn response_time(&self) -> Option<Duration> {
+        *self.response_time.lock().unwrap()
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn add_response_time(&self, sample: Duration) {
+        let mut response_time = self.response_time.lock().unwrap();
+        *response_time = Some(match *response_time {
+            Some(smoothed) => {
+                smoothed.mul_f64(1.0 - RESPONSE_TIME_GAIN) + sample.mul_f64(RESPONSE_TIME_GAIN)
+            }
+            None => sample,
+        });
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    ToInstance,
+    ToClient,
+}
+
+/**
+ * Listens on the local port in place of the session manager plugin and relays each
+ * connection to the plugin, counting what goes through. Connections are closed when dropped
+ */
+pub struct Relay {
+    task: JoinHandle<()>,
+    plugin_port: u16,
+    // Whether the plugin serves several connections over the session
+    multiplexing: bool,
+    stats: Arc<TrafficStats>,
+}
+
+impl Relay {
+    pub fn start(
+        listener: TcpListener,
+        plugin_port: u16,
+        multiplexing: bool,
+        stats: Arc<TrafficStats>,
+        logs_sender: std::sync::mpsc::Sender<String>,
+    ) -> Relay {
+        stats.touch();
+        *stats.up_since.lock().unwrap() = Some(Instant::now());
+        Relay {
+            task: tokio::spawn(serve(listener, plugin_port, stats.clone(), logs_sender)),
+            plugin_port,
+            multiplexing,
+            stats,
+        }
+    }
+
+    /**
+     * Opens a stream to the instance through the plugin and closes it, traffic on the data
+     * channel that is not client traffic. Skipped, returning false, when the plugin does not
+     * multiplex connections, as it then ends the session when a connection closes, and while
+     * a client is connected
+     */
+    pub async fn keepalive(&self) -> std::io::Result<bool> {
+        if !self.multiplexing || self.stats.active_connections() > 0 {
+            return Ok(false);
+        }
+        let mut plugin = TcpStream::connect(("127.0.0.1", self.plugin_port)).await?;
+        plugin.shutdown().await?;
+        Ok(true)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(
+    listener: TcpListener,
+    plugin_port: u16,
+    stats: Arc<TrafficStats>,
+    logs_sender: std::sync::mpsc::Sender<String>,
+) {
+    // Owned here so that aborting the relay aborts its connections too
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((client, _)) => {
+                    connections.spawn(relay_connection(
+                        client,
+                        plugin_port,
+                        stats.clone(),
+                        logs_sender.clone(),
+                    ));
+                }
+                Err(e) => {
+                    send_log(
+                        format!("Relay : Unable to accept connection : {}", e),
+                        &logs_sender,
+                    );
+                    return;
+                }
+            },
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+async fn relay_connection(
+    client: TcpStream,
+    plugin_port: u16,
+    stats: Arc<TrafficStats>,
+    logs_sender: std::sync::mpsc::Sender<String>,
+) {
+    let plugin = match TcpStream::connect(("127.0.0.1", plugin_port)).await {
+        Ok(plugin) => plugin,
+        Err(e) => {
+            send_log(
+                format!("Relay : Unable to reach the session manager plugin : {}", e),
+                &logs_sender,
+            );
+            return;
+        }
+    };
+    let _ = client.set_nodelay(true);
+    let _ = plugin.set_nodelay(true);
+
+    stats.active_connections.fetch_add(1, Ordering::Relaxed);
+    stats.touch();
+    send_log(
+        format!(
+            "Relay : Connection opened, {} active",
+            stats.active_connections()
+        ),
+        &logs_sender,
+    );
+
+    let (client_reader, client_writer) = client.into_split();
+    let (plugin_reader, plugin_writer) = plugin.into_split();
+    // First bytes sent to the instance and not answered yet
+    let request_sent_at = Mutex::new(None);
+    tokio::join!(
+        pump(
+            client_reader,
+            plugin_writer,
+            Direction::ToInstance,
+            &stats,
+            &request_sent_at
+        ),
+        pump(
+            plugin_reader,
+            client_writer,
+            Direction::ToClient,
+            &stats,
+            &request_sent_at
+        ),
+    );
+
+    stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+    stats.touch();
+    send_log(
+        format!(
+            "Relay : Connection closed, {} active, {} bytes sent and {} received so far",
+            stats.active_connections(),
+            stats.bytes_sent(),
+            stats.bytes_received()
+        ),
+        &logs_sender,
+    );
+}
+
+async fn pump(
+    mut reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    direction: Direction,
+    stats: &TrafficStats,
+    request_sent_at: &Mutex<Option<Instant>>,
+) {
+    let counter = match direction {
+        Direction::ToInstance => &stats.bytes_sent,
+        Direction::ToClient => &stats.bytes_received,
+    };
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = match reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        match direction {
+            Direction::ToInstance => {
+                request_sent_at
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(Instant::now);
+            }
+            Direction::ToClient => {
+                if let Some(sent_at) = request_sent_at.lock().unwrap().take() {
+                    stats.add_response_time(sent_at.elapsed());
+                }
+            }
+        }
+        if writer.write_all(&buffer[..read]).await.is_err() {
+            break;
+        }
+        counter.fetch_add(read as u64, Ordering::Relaxed);
+        stats.touch();
+    }
+    // Passes the end of stream on, the other direction may still have data to deliver
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        port
+    }
+
+    /**
+     * Like a plugin without multiplexing: serves a single connection and ends the session,
+     * no longer listening, once it closes. Counts the connections it accepted
+     */
+    async fn single_connection_server() -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let server_accepted = accepted.clone();
+        tokio::spawn(async move {
+            if let Ok((mut stream, _)) = listener.accept().await {
+                server_accepted.fetch_add(1, Ordering::Relaxed);
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            }
+        });
+        (port, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_relay_counts_traffic() {
+        let plugin_port = echo_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = listener.local_addr().unwrap().port();
+        let stats = Arc::new(TrafficStats::default());
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let relay = Relay::start(listener, plugin_port, true, stats.clone(), logs_sender);
+
+        let mut client = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+        // Counted once written on the other side, which may come after the echo
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(stats.active_connections(), 1);
+        assert_eq!(stats.bytes_sent(), 5);
+        assert_eq!(stats.bytes_received(), 5);
+        assert!(stats.idle_for() < Duration::from_secs(1));
+        assert!(
+            stats
+                .response_time()
+                .is_some_and(|response_time| response_time < Duration::from_secs(1))
+        );
+        assert!(stats.uptime() > Duration::ZERO);
+
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(stats.active_connections(), 0);
+
+        // Dropping the relay frees the local port
+        drop(relay);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpListener::bind(("127.0.0.1", local_port)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_only_without_client() {
+        let plugin_port = echo_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = listener.local_addr().unwrap().port();
+        let stats = Arc::new(TrafficStats::default());
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let relay = Relay::start(listener, plugin_port, true, stats.clone(), logs_sender);
+
+        assert!(relay.keepalive().await.unwrap());
+        // Not client traffic
+        assert_eq!(stats.active_connections(), 0);
+        assert_eq!(stats.bytes_sent(), 0);
+
+        let _client = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!relay.keepalive().await.unwrap());
+    }
+    #[tokio::test]
+    async fn test_no_keepalive_without_multiplexing() {
+        let (plugin_port, accepted) = single_connection_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = listener.local_addr().unwrap().port();
+        let stats = Arc::new(TrafficStats::default());
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let relay = Relay::start(listener, plugin_port, false, stats.clone(), logs_sender);
+
+        assert!(!relay.keepalive().await.unwrap());
+        assert_eq!(accepted.load(Ordering::Relaxed), 0);
+
+        // The session is still there for the client
+        let mut client = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+    }
+}
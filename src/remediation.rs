@@ -0,0 +1,430 @@
+use aws_sdk_ssm::Client;
+use aws_sdk_ssm::types::{CommandInvocationStatus, ParameterType};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rdp::RdpCredentials;
+use crate::utils::send_log;
+
+pub const RUN_POWERSHELL_DOCUMENT: &str = "AWS-RunPowerShellScript";
+// How long the command may wait for the agent, then run on the instance
+const DELIVERY_TIMEOUT_SECS: u64 = 120;
+const EXECUTION_TIMEOUT_SECS: u64 = 600;
+// Used by the AWS-Run*Script documents when the parameters leave it out
+const DEFAULT_EXECUTION_TIMEOUT_SECS: u64 = 3600;
+const INVOCATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// Remote Desktop firewall rules, by their resource so that it works whatever the language
+const REMOTE_DESKTOP_RULE_GROUP: &str = "@FirewallAPI.dll,-28752";
+// PowerShell treats the typographic single quotes as quotes too
+const SINGLE_QUOTES: [char; 5] = ['\'', '\u{2018}', '\u{2019}', '\u{201A}', '\u{201B}'];
+// The password of a reset waits there for the agent, which resolves it on the instance
+const PASSWORD_PARAMETER_PREFIX: &str = "/vm-connect/reset-password/";
+
+#[derive(Debug)]
+pub struct RemediationError {
+    pub kind: RemediationErrorKind,
+    pub msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RemediationErrorKind {
+    Api,
+    Failed,
+    Timeout,
+}
+
+/**
+ * How a command ended on one instance, `status` being that of the invocation, e.g. `Success`,
+ * `Failed` or `TimedOut`
+ */
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub status: String,
+    pub response_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/**
+ * Fixes run on the instance through SSM Run Command, when the tunnel is up but RDP is not
+ */
+#[derive(Debug, Clone)]
+pub enum RemediationAction {
+    RestartRdpService,
+    EnableRdp,
+    /// The password goes through a SecureString parameter, only its name is in the command
+    ResetPassword(RdpCredentials),
+    PowerShell(String),
+}
+
+impl RemediationAction {
+    pub fn label(&self) -> String {
+        match self {
+            Self::RestartRdpService => "Restart the RDP service".into(),
+            Self::EnableRdp => "Enable RDP and its firewall rules".into(),
+            Self::ResetPassword(credentials) => {
+                format!("Reset the password of {}", credentials.username)
+            }
+            Self::PowerShell(_) => "Run a PowerShell script".into(),
+        }
+    }
+
+    /**
+     * `password_parameter` names the SecureString holding the password of a reset, a
+     * here-string keeping it as is whatever quotes it holds once resolved
+     */
+    fn commands(&self, password_parameter: &str) -> Vec<String> {
+        match self {
+            Self::RestartRdpService => vec![
+                "Restart-Service -Name TermService -Force".into(),
+                "Get-Service -Name TermService, UmRdpService | Format-Table -AutoSize Name, Status"
+                    .into(),
+            ],
+            Self::EnableRdp => vec![
+                "Set-ItemProperty -Path 'HKLM:\\System\\CurrentControlSet\\Control\\Terminal Server' -Name fDenyTSConnections -Value 0".into(),
+                format!(
+                    "Get-NetFirewallRule -Group '{}' | Enable-NetFirewallRule -PassThru | Format-Table -AutoSize DisplayName, Enabled",
+                    REMOTE_DESKTOP_RULE_GROUP
+                ),
+            ],
+            Self::ResetPassword(credentials) => vec![
+                "$password = ConvertTo-SecureString -AsPlainText -Force -String @'".into(),
+                format!("{{{{ssm-secure:{}}}}}", password_parameter),
+                "'@".into(),
+                format!(
+                    "Set-LocalUser -Name {} -Password $password",
+                    quote(&credentials.username)
+                ),
+                format!(
+                    "Write-Output 'Password of {} reset'",
+                    credentials.username.replace(SINGLE_QUOTES, "")
+                ),
+            ],
+            Self::PowerShell(script) => script.lines().map(|line| line.to_string()).collect(),
+        }
+    }
+}
+
+// Single-quoted PowerShell string, where nothing is expanded
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("'");
+    for c in value.chars() {
+        if SINGLE_QUOTES.contains(&c) {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/**
+ * Sends the action to the instance and logs its output as it comes, returns the whole output
+ * once the command succeeded
+ */
+pub async fn run(
+    client: &Client,
+    instance_id: &str,
+    action: &RemediationAction,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<String, RemediationError> {
+    send_log(
+        format!("Run Command : {} on {}...", action.label(), instance_id),
+        logs_sender,
+    );
+    let password_parameter = match action {
+        RemediationAction::ResetPassword(credentials) => {
+            Some(put_password(client, instance_id, &credentials.password).await?)
+        }
+        _ => None,
+    };
+    let parameters = HashMap::from([
+        (
+            "commands".to_string(),
+            action.commands(password_parameter.as_deref().unwrap_or_default()),
+        ),
+        (
+            "executionTimeout".to_string(),
+            vec![EXECUTION_TIMEOUT_SECS.to_string()],
+        ),
+    ]);
+    let invocation = send_and_wait(
+        client,
+        instance_id,
+        RUN_POWERSHELL_DOCUMENT,
+        parameters,
+        &action.label(),
+        logs_sender,
+    )
+    .await;
+    if let Some(name) = password_parameter {
+        delete_password(client, &name, logs_sender).await;
+    }
+    let invocation = invocation?;
+
+    match invocation.status.as_str() {
+        "Success" => Ok(invocation.stdout),
+        status => Err(RemediationError {
+            kind: RemediationErrorKind::Failed,
+            msg: format!(
+                "run : {} ended {} with code {} : {}",
+                action.label(),
+                status,
+                invocation.response_code,
+                invocation.stderr.trim()
+            ),
+        }),
+    }
+}
+
+/**
+ * Stores the password as a SecureString under a name of its own, so that concurrent resets do
+ * not overwrite each other
+ */
+async fn put_password(
+    client: &Client,
+    instance_id: &str,
+    password: &str,
+) -> Result<String, RemediationError> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let name = format!("{}{}-{}", PASSWORD_PARAMETER_PREFIX, instance_id, nanos);
+    client
+        .put_parameter()
+        .name(&name)
+        .value(password)
+        .r#type(ParameterType::SecureString)
+        .send()
+        .await
+        .map_err(|e| RemediationError {
+            kind: RemediationErrorKind::Api,
+            msg: format!("put_password : PutParameter {} failed : {}", name, e),
+        })?;
+    Ok(name)
+}
+
+// Once the command is over, whether it succeeded or not
+async fn delete_password(
+    client: &Client,
+    name: &str,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) {
+    if let Err(e) = client.delete_parameter().name(name).send().await {
+        send_log(
+            format!(
+                "Run Command : Warning : Unable to delete the parameter {} holding the password : {}",
+                name, e
+            ),
+            logs_sender,
+        );
+    }
+}
+
+/**
+ * Sends a command document to one instance and polls it until it is over, logging its
+ * output as it comes. A command that ran and failed is not an error
+ */
+pub async fn send_and_wait(
+    client: &Client,
+    instance_id: &str,
+    document_name: &str,
+    parameters: HashMap<String, Vec<String>>,
+    comment: &str,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<Invocation, RemediationError> {
+    let execution_timeout_secs = parameters
+        .get("executionTimeout")
+        .and_then(|values| values.first())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_EXECUTION_TIMEOUT_SECS);
+    let output = client
+        .send_command()
+        .instance_ids(instance_id)
+        .document_name(document_name)
+        .comment(comment)
+        .timeout_seconds(DELIVERY_TIMEOUT_SECS as i32)
+        .set_parameters(Some(parameters))
+        .send()
+        .await
+        .map_err(|e| RemediationError {
+            kind: RemediationErrorKind::Api,
+            msg: format!("send_and_wait : SendCommand failed : {}", e),
+        })?;
+    let command_id = output
+        .command()
+        .and_then(|command| command.command_id())
+        .ok_or_else(|| RemediationError {
+            kind: RemediationErrorKind::Api,
+            msg: "send_and_wait : SendCommand response is missing its command id".into(),
+        })?;
+    send_log(
+        format!("Run Command : {} sent to {}", command_id, instance_id),
+        logs_sender,
+    );
+
+    let give_up_at =
+        Instant::now() + Duration::from_secs(DELIVERY_TIMEOUT_SECS + execution_timeout_secs + 60);
+    let mut logged = 0;
+    let mut last_status = None;
+    loop {
+        if Instant::now() >= give_up_at {
+            return Err(RemediationError {
+                kind: RemediationErrorKind::Timeout,
+                msg: format!(
+                    "send_and_wait : Command {} did not complete in time on {}",
+                    command_id, instance_id
+                ),
+            });
+        }
+        tokio::time::sleep(INVOCATION_POLL_INTERVAL).await;
+
+        let invocation = match client
+            .get_command_invocation()
+            .command_id(command_id)
+            .instance_id(instance_id)
+            .send()
+            .await
+        {
+            Ok(invocation) => invocation,
+            // Not registered yet right after SendCommand
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_invocation_does_not_exist()) =>
+            {
+                continue;
+            }
+            Err(e) => {
+                return Err(RemediationError {
+                    kind: RemediationErrorKind::Api,
+                    msg: format!("send_and_wait : GetCommandInvocation failed : {}", e),
+                });
+            }
+        };
+
+        let status = invocation
+            .status()
+            .cloned()
+            .unwrap_or(CommandInvocationStatus::Pending);
+        if last_status.as_ref() != Some(&status) {
+            send_log(
+                format!(
+                    "Run Command : {} {} on {}",
+                    command_id,
+                    status.as_str(),
+                    instance_id
+                ),
+                logs_sender,
+            );
+        }
+        let done = !matches!(
+            status,
+            CommandInvocationStatus::Pending
+                | CommandInvocationStatus::InProgress
+                | CommandInvocationStatus::Delayed
+                | CommandInvocationStatus::Cancelling
+        );
+        let stdout = invocation.standard_output_content().unwrap_or_default();
+        for line in new_lines(stdout, &mut logged, done) {
+            send_log(
+                format!("Run Command : {} > {}", instance_id, line),
+                logs_sender,
+            );
+        }
+
+        if done {
+            return Ok(Invocation {
+                status: status.as_str().to_string(),
+                response_code: invocation.response_code(),
+                stdout: stdout.to_string(),
+                stderr: invocation
+                    .standard_error_content()
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+        last_status = Some(status);
+    }
+}
+
+/**
+ * Lines of the output past what was logged already, the last one only once complete
+ * unless the command is done
+ */
+fn new_lines<'a>(output: &'a str, logged: &mut usize, done: bool) -> Vec<&'a str> {
+    let Some(unlogged) = output.get(*logged..) else {
+        return vec![];
+    };
+    let complete = match done {
+        true => unlogged,
+        false => unlogged
+            .rfind('\n')
+            .map(|end| &unlogged[..=end])
+            .unwrap_or_default(),
+    };
+    *logged += complete.len();
+    complete
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_ssm_client;
+    use vm_connect_mock_ssm::{Backend, MockSsm};
+
+    const MOCK_TARGET: &str = "i-0123456789abcdef0";
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("P@ss$word"), "'P@ss$word'");
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote("it\u{2019}s"), "'it\u{2019}\u{2019}s'");
+    }
+
+    #[test]
+    fn test_new_lines() {
+        let mut logged = 0;
+        assert_eq!(new_lines("first\nsec", &mut logged, false), vec!["first"]);
+        assert_eq!(
+            new_lines("first\nsecond\nthird", &mut logged, false),
+            vec!["second"]
+        );
+        assert_eq!(
+            new_lines("first\nsecond\nthird", &mut logged, true),
+            vec!["third"]
+        );
+        assert!(new_lines("first\nsecond\nthird", &mut logged, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_against_mock() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let client = mock_ssm_client(mock.endpoint_url());
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+
+        let action = RemediationAction::ResetPassword(RdpCredentials {
+            username: "Administrator".into(),
+            password: "O'Brien$1".into(),
+        });
+        let output = run(&client, MOCK_TARGET, &action, &logs_sender)
+            .await
+            .unwrap();
+        // The mock resolves the parameter as the agent would, then prints the script back
+        assert!(output.contains("@'\nO'Brien$1\n'@"), "{}", output);
+        let (_, parameters) = mock.command("mock-command-1").unwrap();
+        assert!(!parameters.to_string().contains("O'Brien$1"));
+        assert!(mock.parameter_names().is_empty());
+        assert!(output.ends_with("Write-Output 'Password of Administrator reset'"));
+
+        let action = RemediationAction::PowerShell("Get-Service\nthrow 'broken'".into());
+        let e = run(&client, MOCK_TARGET, &action, &logs_sender)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind, RemediationErrorKind::Failed);
+        assert!(e.msg.contains("throw 'broken'"), "{}", e.msg);
+    }
+}
@@ -0,0 +1,421 @@
+use aws_sdk_ssm::Client;
+#[cfg(test)]
+use aws_sdk_ssm::types::{SessionFilter, SessionFilterKey, SessionState};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+
+use crate::config::TargetConfig;
+use crate::ssm::{SSMError, SSMErrorKind, client_region, initiate_aws_client};
+
+/**
+ * What the session-manager-plugin needs to open the data channel,
+ * serialized with the field names of the StartSession response
+ */
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionHandle {
+    pub session_id: String,
+    pub token_value: String,
+    pub stream_url: String,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct SessionDescription {
+    pub session_id: String,
+    pub target: String,
+    pub status: String,
+}
+
+/**
+ * The SSM session calls the tunnel relies on, implemented by the SDK client
+ * and by an in-memory fake for tests
+ */
+pub trait SessionApi: Send + Sync + 'static {
+    fn region(&self) -> String;
+
+    fn start_session(
+        &self,
+        target: &str,
+        document_name: &str,
+        parameters: HashMap<String, Vec<String>>,
+    ) -> impl Future<Output = Result<SessionHandle, SSMError>> + Send;
+
+    fn terminate_session(
+        &self,
+        session_id: &str,
+    ) -> impl Future<Output = Result<(), SSMError>> + Send;
+
+    // Resuming and listing sessions are not used by the tunnel yet, only checked against the mock
+    #[cfg(test)]
+    fn resume_session(
+        &self,
+        session_id: &str,
+    ) -> impl Future<Output = Result<SessionHandle, SSMError>> + Send;
+
+    #[cfg(test)]
+    fn describe_sessions(
+        &self,
+        target: &str,
+    ) -> impl Future<Output = Result<Vec<SessionDescription>, SSMError>> + Send;
+}
+
+pub struct SdkSessionApi {
+    client: Client,
+}
+
+impl SdkSessionApi {
+    pub async fn new(target: &TargetConfig) -> Self {
+        SdkSessionApi {
+            client: initiate_aws_client(target).await,
+        }
+    }
+
+    /// Wraps a client configured elsewhere, e.g. pointed at a mock endpoint
+    pub fn from_client(client: Client) -> Self {
+        SdkSessionApi { client }
+    }
+}
+
+fn session_handle(
+    session_id: Option<&str>,
+    token_value: Option<&str>,
+    stream_url: Option<&str>,
+) -> Result<SessionHandle, SSMError> {
+    match (session_id, token_value, stream_url) {
+        (Some(session_id), Some(token_value), Some(stream_url)) => Ok(SessionHandle {
+            session_id: session_id.into(),
+            token_value: token_value.into(),
+            stream_url: stream_url.into(),
+        }),
+        _ => Err(SSMError {
+            kind: SSMErrorKind::StartSession,
+            msg: "session_handle : Session response is missing its id, token or stream url".into(),
+        }),
+    }
+}
+
+impl SessionApi for SdkSessionApi {
+    fn region(&self) -> String {
+        client_region(&self.client)
+    }
+
+    async fn start_session(
+        &self,
+        target: &str,
+        document_name: &str,
+        parameters: HashMap<String, Vec<String>>,
+    ) -> Result<SessionHandle, SSMError> {
+        let output = self
+            .client
+            .start_session()
+            .target(target)
+            .document_name(document_name)
+            .set_parameters(Some(parameters))
+            .send()
+            .await
+            .map_err(|e| SSMError {
+                kind: SSMErrorKind::StartSession,
+                msg: format!("start_session : {}", e),
+            })?;
+
+        session_handle(
+            output.session_id(),
+            output.token_value(),
+            output.stream_url(),
+        )
+    }
+
+    async fn terminate_session(&self, session_id: &str) -> Result<(), SSMError> {
+        self.client
+            .terminate_session()
+            .session_id(session_id)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| SSMError {
+                kind: SSMErrorKind::TerminateSession,
+                msg: format!("terminate_session : {}", e),
+            })
+    }
+
+    #[cfg(test)]
+    async fn resume_session(&self, session_id: &str) -> Result<SessionHandle, SSMError> {
+        let output = self
+            .client
+            .resume_session()
+            .session_id(session_id)
+            .send()
+            .await
+            .map_err(|e| SSMError {
+                kind: SSMErrorKind::StartSession,
+                msg: format!("resume_session : {}", e),
+            })?;
+
+        session_handle(
+            output.session_id(),
+            output.token_value(),
+            output.stream_url(),
+        )
+    }
+
+    #[cfg(test)]
+    async fn describe_sessions(&self, target: &str) -> Result<Vec<SessionDescription>, SSMError> {
+        let filter = SessionFilter::builder()
+            .key(SessionFilterKey::Target)
+            .value(target)
+            .build()
+            .map_err(|e| SSMError {
+                kind: SSMErrorKind::DescribeSessions,
+                msg: format!("describe_sessions : {}", e),
+            })?;
+
+        let output = self
+            .client
+            .describe_sessions()
+            .state(SessionState::Active)
+            .filters(filter)
+            .send()
+            .await
+            .map_err(|e| SSMError {
+                kind: SSMErrorKind::DescribeSessions,
+                msg: format!("describe_sessions : {}", e),
+            })?;
+
+        Ok(output
+            .sessions()
+            .iter()
+            .map(|session| SessionDescription {
+                session_id: session.session_id().unwrap_or_default().into(),
+                target: session.target().unwrap_or_default().into(),
+                status: session
+                    .status()
+                    .map(|status| status.as_str().to_string())
+                    .unwrap_or_default(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeState {
+        targets: Vec<String>,
+        active_sessions: Vec<SessionDescription>,
+        terminated_sessions: Vec<String>,
+        fail_terminate: bool,
+        next_session: usize,
+        stream_query: Option<String>,
+        remote_ports: Vec<String>,
+    }
+
+    /**
+     * Keeps sessions in memory, clones share the same state so a test can
+     * inspect what the tunnel did with its own copy
+     */
+    #[derive(Clone, Default)]
+    pub struct FakeSessionApi {
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    impl FakeSessionApi {
+        pub fn with_targets(targets: &[&str]) -> Self {
+            let api = FakeSessionApi::default();
+            api.state.lock().unwrap().targets = targets.iter().map(|t| t.to_string()).collect();
+            api
+        }
+
+        pub fn failing_terminate(self) -> Self {
+            self.state.lock().unwrap().fail_terminate = true;
+            self
+        }
+
+        /**
+         * Appended to the stream url, the fake session manager plugin reads its
+         * behaviour from there (`mode=bind|exit|hang|crash`, `code=<exit code>`)
+         */
+        pub fn with_stream_query(self, query: &str) -> Self {
+            self.state.lock().unwrap().stream_query = Some(query.into());
+            self
+        }
+
+        pub fn active_sessions(&self) -> Vec<String> {
+            let state = self.state.lock().unwrap();
+            state
+                .active_sessions
+                .iter()
+                .map(|session| session.session_id.clone())
+                .collect()
+        }
+
+        pub fn terminated_sessions(&self) -> Vec<String> {
+            self.state.lock().unwrap().terminated_sessions.clone()
+        }
+
+        /// `portNumber` of each session started
+        pub fn remote_ports(&self) -> Vec<String> {
+            self.state.lock().unwrap().remote_ports.clone()
+        }
+    }
+
+    impl SessionApi for FakeSessionApi {
+        fn region(&self) -> String {
+            "eu-west-1".into()
+        }
+
+        async fn start_session(
+            &self,
+            target: &str,
+            _document_name: &str,
+            parameters: HashMap<String, Vec<String>>,
+        ) -> Result<SessionHandle, SSMError> {
+            let mut state = self.state.lock().unwrap();
+            if !state.targets.iter().any(|t| t == target) {
+                return Err(SSMError {
+                    kind: SSMErrorKind::StartSession,
+                    msg: format!("start_session : TargetNotConnected : {}", target),
+                });
+            }
+
+            state.remote_ports.extend(
+                parameters
+                    .get("portNumber")
+                    .and_then(|values| values.first())
+                    .cloned(),
+            );
+            state.next_session += 1;
+            let session_id = format!("fake-{}", state.next_session);
+            state.active_sessions.push(SessionDescription {
+                session_id: session_id.clone(),
+                target: target.into(),
+                status: "Connected".into(),
+            });
+
+            // The local port is only known to StartSession, pass it on to the fake plugin
+            let mut query = parameters
+                .get("localPortNumber")
+                .and_then(|values| values.first())
+                .map(|port| format!("port={}", port))
+                .unwrap_or_default();
+            if let Some(stream_query) = &state.stream_query {
+                query = format!("{}&{}", query, stream_query);
+            }
+
+            Ok(SessionHandle {
+                stream_url: format!(
+                    "wss://ssmmessages.invalid/v1/data-channel/{}?{}",
+                    session_id, query
+                ),
+                session_id,
+                token_value: "fake-token".into(),
+            })
+        }
+
+        async fn terminate_session(&self, session_id: &str) -> Result<(), SSMError> {
+            let mut state = self.state.lock().unwrap();
+            if state.fail_terminate {
+                return Err(SSMError {
+                    kind: SSMErrorKind::TerminateSession,
+                    msg: "terminate_session : fake failure".into(),
+                });
+            }
+            state
+                .active_sessions
+                .retain(|session| session.session_id != session_id);
+            state.terminated_sessions.push(session_id.into());
+            Ok(())
+        }
+
+        async fn resume_session(&self, session_id: &str) -> Result<SessionHandle, SSMError> {
+            let state = self.state.lock().unwrap();
+            state
+                .active_sessions
+                .iter()
+                .find(|session| session.session_id == session_id)
+                .map(|session| SessionHandle {
+                    session_id: session.session_id.clone(),
+                    token_value: "fake-token".into(),
+                    stream_url: format!(
+                        "wss://ssmmessages.invalid/v1/data-channel/{}",
+                        session.session_id
+                    ),
+                })
+                .ok_or_else(|| SSMError {
+                    kind: SSMErrorKind::StartSession,
+                    msg: format!("resume_session : unknown session {}", session_id),
+                })
+        }
+
+        async fn describe_sessions(
+            &self,
+            target: &str,
+        ) -> Result<Vec<SessionDescription>, SSMError> {
+            let state = self.state.lock().unwrap();
+            Ok(state
+                .active_sessions
+                .iter()
+                .filter(|session| session.target == target)
+                .cloned()
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_ssm_client;
+    use vm_connect_mock_ssm::client::DataChannelClient;
+    use vm_connect_mock_ssm::{Backend, MockSsm};
+
+    const MOCK_TARGET: &str = "i-0123456789abcdef0";
+
+    #[tokio::test]
+    async fn test_sdk_session_api_against_mock() {
+        let mock = MockSsm::start(Backend::Echo).await.unwrap();
+        let session_api = SdkSessionApi::from_client(mock_ssm_client(mock.endpoint_url()));
+
+        let parameters = HashMap::from([
+            ("portNumber".to_string(), vec!["3389".to_string()]),
+            ("localPortNumber".to_string(), vec!["55678".to_string()]),
+        ]);
+        let session = session_api
+            .start_session(MOCK_TARGET, "AWS-StartPortForwardingSession", parameters)
+            .await
+            .unwrap();
+        assert_eq!(mock.active_sessions(), vec![session.session_id.clone()]);
+        let sessions = session_api.describe_sessions(MOCK_TARGET).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].status, "Connected");
+        let resumed = session_api
+            .resume_session(&session.session_id)
+            .await
+            .unwrap();
+        assert_eq!(resumed.stream_url, session.stream_url);
+
+        // RDP-like bytes through the data channel of the session
+        let mut data_channel =
+            DataChannelClient::connect(&session.stream_url, &session.token_value)
+                .await
+                .unwrap();
+        let connection_request = b"\x03\x00\x00\x0b\x06\xe0\x00\x00\x00\x00\x00";
+        data_channel.send(connection_request).await.unwrap();
+        assert_eq!(
+            data_channel.recv().await.unwrap().as_deref(),
+            Some(&connection_request[..])
+        );
+
+        session_api
+            .terminate_session(&session.session_id)
+            .await
+            .unwrap();
+        assert_eq!(data_channel.recv().await.unwrap(), None);
+        assert_eq!(mock.terminated_sessions(), vec![session.session_id]);
+    }
+}
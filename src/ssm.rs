@@ -1,15 +1,15 @@
+use aws_config::default_provider::region::DefaultRegionChain;
 use aws_config::{BehaviorVersion, meta::region::RegionProviderChain};
-use aws_sdk_ssm::operation::start_session::StartSessionError;
-use aws_sdk_ssm::{
-    Client,
-    config::Region,
-    error::SdkError,
-    operation::{start_session::StartSessionOutput, terminate_session::TerminateSessionOutput},
-};
+use aws_sdk_ssm::{Client, config::Region};
 use clap::Parser;
-use serde::Serialize;
+use std::collections::HashMap;
 use std::io::Read;
+use std::net::TcpListener;
+#[cfg(windows)]
 use std::os::windows::process::CommandExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{
     io::{BufRead, BufReader},
     process::{Command, Stdio},
@@ -18,11 +18,34 @@ use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::RecvError;
 use tokio::sync::oneshot::{Receiver, Sender};
 
-use crate::messages::{ApplicationExitedMessage, SSMTunnelLaunchedMessage};
-use crate::utils::{CREATE_NO_WINDOW, send_log};
+use crate::cli::Opt;
+use crate::config::{CommandsConfig, ProxyConfig, TargetConfig};
+use crate::messages::{ApplicationExitedMessage, SSMTunnelLaunchedMessage, TunnelClosedMessage};
+use crate::proxy;
+use crate::relay::{Relay, TrafficStats};
+use crate::session_api::{SessionApi, SessionHandle};
+#[cfg(windows)]
+use crate::utils::CREATE_NO_WINDOW;
+use crate::utils::send_log;
 
 const LOCAL_PORT_NUMBER: &str = "9090";
-const REMOTE_PORT_NUMBER: &str = "3389";
+pub const RDP_PORT_NUMBER: &str = "3389";
+// Where the local port listens
+pub const LOCAL_HOST: &str = "127.0.0.1";
+const DEFAULT_REGION: &str = "eu-west-1";
+const PORT_FORWARDING_DOCUMENT: &str = "AWS-StartPortForwardingSession";
+// The plugin reads the StartSession response from the environment variable named in argv
+// instead of argv itself, which keeps the session token out of the process list
+const START_SESSION_RESPONSE_ENV: &str = "AWS_SSM_START_SESSION_RESPONSE";
+const TUNNEL_READY_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const PLUGIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Printed by the plugin when the agent closes the session for the idle session timeout
+const IDLE_TIMEOUT_OUTPUT: &str = "timed out due to inactivity";
+// Idle session timeout of an account that did not change it
+const DEFAULT_IDLE_SESSION_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+// First plugin version serving several connections over a port forwarding session, older ones
+// end the session when its connection closes. The agent needs 3.0.222.0 or later too
+const MULTIPLEXING_PLUGIN_VERSION: [u32; 4] = [1, 2, 7, 0];
 
 #[derive(Debug)]
 pub struct SSMError {
@@ -37,22 +60,50 @@ pub enum SSMErrorKind {
     Serde,
     IO,
     TerminateSession,
+    #[cfg(test)]
+    DescribeSessions,
     TokioChannel,
     CommandKill,
+    CommandExit,
+    PortInUse,
+    TunnelTimeout,
+    CallerIdentity,
 }
 
 pub struct TunnelTaskInstance {
     pub stop_sender: Sender<ApplicationExitedMessage>,
     pub stop_ack_receiver: Receiver<ApplicationExitedMessage>,
     pub tunnel_created_receiver: Option<Receiver<SSMTunnelLaunchedMessage>>,
+    pub tunnel_closed_receiver: Receiver<TunnelClosedMessage>,
     pub task_handler: tokio::task::JoinHandle<Result<(), SSMError>>,
     pub logs_sender: std::sync::mpsc::Sender<String>,
+    pub traffic: Arc<TrafficStats>,
+}
+
+/**
+ * Which port of which instance is forwarded to which local port, and with what
+ */
+pub struct TunnelSpec {
+    pub target: TargetConfig,
+    pub local_port_number: String,
+    pub remote_port_number: String,
+    pub commands: CommandsConfig,
+}
+
+/**
+ * The ends of the channels held by the tunnel task, the others are in its TunnelTaskInstance
+ */
+pub struct TunnelChannels {
+    pub tunnel_launched_sender: Sender<SSMTunnelLaunchedMessage>,
+    pub tunnel_closed_sender: Sender<TunnelClosedMessage>,
+    pub app_exit_receiver: Receiver<ApplicationExitedMessage>,
+    pub app_exit_ack_sender: Sender<ApplicationExitedMessage>,
 }
 
 impl TunnelTaskInstance {
-    pub fn spawn(
-        target: String,
-        local_port_number: String,
+    pub fn spawn<S: SessionApi>(
+        session_api: S,
+        spec: TunnelSpec,
         logs_sender: std::sync::mpsc::Sender<String>,
     ) -> Self {
         send_log(
@@ -60,8 +111,10 @@ impl TunnelTaskInstance {
             &logs_sender,
         );
         let (tx_tunnel_launched, rx_tunnel_launched) = oneshot::channel();
+        let (tx_tunnel_closed, rx_tunnel_closed) = oneshot::channel();
         let (tx_exit_ssm, rx_exit_ssm) = oneshot::channel();
         let (tx_exit_ssm_ack, rx_exit_ssm_ack) = oneshot::channel();
+        let traffic = Arc::new(TrafficStats::default());
 
         send_log(
             "TunnelTaskInstance : Spawning tunnel...".into(),
@@ -70,11 +123,15 @@ impl TunnelTaskInstance {
 
         let ssm_tunnel_task: tokio::task::JoinHandle<Result<(), SSMError>> =
             tokio::spawn(launch_ssm_tunnel(
-                target,
-                tx_tunnel_launched,
-                rx_exit_ssm,
-                tx_exit_ssm_ack,
-                local_port_number,
+                session_api,
+                spec,
+                TunnelChannels {
+                    tunnel_launched_sender: tx_tunnel_launched,
+                    tunnel_closed_sender: tx_tunnel_closed,
+                    app_exit_receiver: rx_exit_ssm,
+                    app_exit_ack_sender: tx_exit_ssm_ack,
+                },
+                traffic.clone(),
                 logs_sender.clone(),
             ));
 
@@ -84,8 +141,10 @@ impl TunnelTaskInstance {
             stop_sender: tx_exit_ssm,
             stop_ack_receiver: rx_exit_ssm_ack,
             tunnel_created_receiver: Some(rx_tunnel_launched),
+            tunnel_closed_receiver: rx_tunnel_closed,
             task_handler: ssm_tunnel_task,
             logs_sender,
+            traffic,
         }
     }
 
@@ -104,12 +163,27 @@ impl TunnelTaskInstance {
         );
         receive(self.task_handler.await)
     }
+
+    /** Why the tunnel task ended before signalling the tunnel, once it is over */
+    pub async fn start_error(self) -> SSMError {
+        match self.task_handler.await {
+            Ok(Err(e)) => e,
+            Ok(Ok(())) => SSMError {
+                kind: SSMErrorKind::TokioChannel,
+                msg: "TunnelTaskInstance : Tunnel task ended before the tunnel was up".into(),
+            },
+            Err(e) => SSMError {
+                kind: SSMErrorKind::TokioChannel,
+                msg: format!("TunnelTaskInstance : Tunnel task failed : {}", e),
+            },
+        }
+    }
 }
 
-async fn try_or_terminate_session<T>(
+async fn try_or_terminate_session<T, S: SessionApi>(
     res: Result<T, SSMError>,
-    client: &Client,
-    session_id: Option<String>,
+    client: &S,
+    session_id: &str,
 ) -> Result<T, SSMError> {
     match res {
         Err(e) => terminate_session_with_error(e, client, session_id).await,
@@ -117,21 +191,35 @@ async fn try_or_terminate_session<T>(
     }
 }
 
-pub async fn launch_ssm_tunnel(
-    vm_target: String,
-    tx_tunnel_launched: Sender<SSMTunnelLaunchedMessage>,
-    rx_app_exit: Receiver<ApplicationExitedMessage>,
-    tx_app_exit_ack: Sender<ApplicationExitedMessage>,
-    local_port_number: String,
+pub async fn launch_ssm_tunnel<S: SessionApi>(
+    aws_client: S,
+    spec: TunnelSpec,
+    channels: TunnelChannels,
+    traffic: Arc<TrafficStats>,
     logs_sender: std::sync::mpsc::Sender<String>,
 ) -> Result<(), SSMError> {
+    let TunnelSpec {
+        target: vm_target,
+        local_port_number,
+        remote_port_number,
+        commands,
+    } = spec;
+    let TunnelChannels {
+        tunnel_launched_sender: tx_tunnel_launched,
+        tunnel_closed_sender: tx_tunnel_closed,
+        app_exit_receiver: mut rx_app_exit,
+        app_exit_ack_sender: tx_app_exit_ack,
+    } = channels;
+    // The plugin listens on a port of its own, the local port goes through the relay
+    // which sees the traffic of the tunnel
+    let local_listener = bind_local_port(&local_port_number).await?;
+    let plugin_port = free_plugin_port()?;
+    let region = aws_client.region();
     send_log(
-        "TunnelTaskInstance/launch_ssm_tunnel : Initiate aws client...".into(),
-        &logs_sender,
-    );
-    let aws_client = initiate_aws_client().await;
-    send_log(
-        "TunnelTaskInstance/launch_ssm_tunnel : Initiate aws client OK".into(),
+        format!(
+            "TunnelTaskInstance/launch_ssm_tunnel : Using region {}",
+            region
+        ),
         &logs_sender,
     );
 
@@ -139,13 +227,19 @@ pub async fn launch_ssm_tunnel(
         "TunnelTaskInstance/launch_ssm_tunnel : Start session...".into(),
         &logs_sender,
     );
-    let start_session_output = match start_session(vm_target, &aws_client, local_port_number).await
+    let mut session = match start_session(
+        vm_target.instance_id,
+        &aws_client,
+        plugin_port.to_string(),
+        remote_port_number,
+    )
+    .await
     {
         Ok(s) => s,
-        _ => {
+        Err(e) => {
             return Err(SSMError {
                 kind: SSMErrorKind::StartSession,
-                msg: "launch_ssm_tunnel : Error when starting session".into(),
+                msg: "launch_ssm_tunnel : Error when starting session : ".to_string() + &e.msg,
             });
         }
     };
@@ -155,20 +249,56 @@ pub async fn launch_ssm_tunnel(
         &logs_sender,
     );
 
+    if let Some(endpoint_url) = &vm_target.endpoints.ssmmessages {
+        session.stream_url = stream_url_with_endpoint(&session.stream_url, endpoint_url);
+    }
+
     send_log(
         "TunnelTaskInstance/launch_ssm_tunnel : Initiate SSM port forwarding...".into(),
         &logs_sender,
     );
     let mut tunnel_child = try_or_terminate_session(
-        initiate_ssm_port_forwarding(&start_session_output).await,
+        initiate_ssm_port_forwarding(
+            &session,
+            &region,
+            &commands.session_manager_plugin,
+            &vm_target.proxy,
+        )
+        .await,
         &aws_client,
-        start_session_output.session_id.clone(),
+        &session.session_id,
     )
     .await?;
+    let plugin_output = tunnel_child
+        .stdout
+        .take()
+        .map(|stdout| PluginOutput::start(stdout, plugin_port, logs_sender.clone()));
+
+    let ready = wait_for_tunnel_ready(
+        &mut tunnel_child,
+        plugin_output.as_ref(),
+        plugin_port,
+        Duration::from_secs(commands.tunnel_ready_timeout_secs),
+    )
+    .await;
+    if ready.is_err() {
+        // Already exited or stuck, either way it must not outlive the session
+        let _ = tunnel_child.kill();
+    }
+    try_or_terminate_session(ready, &aws_client, &session.session_id).await?;
     send_log(
         "TunnelTaskInstance/launch_ssm_tunnel : Initiate SSM port forwarding OK".into(),
         &logs_sender,
     );
+    let multiplexing = plugin_multiplexing(&commands.session_manager_plugin, &logs_sender).await;
+    // Dropped with this task, which closes the local port and its connections
+    let relay = Relay::start(
+        local_listener,
+        plugin_port,
+        multiplexing,
+        traffic.clone(),
+        logs_sender.clone(),
+    );
 
     /*
      * DEACTIVATED FOR NOW
@@ -177,7 +307,7 @@ pub async fn launch_ssm_tunnel(
     //     match tunnel_child.stdout.take() {
     //         Some(c) => c,
     //         _ => {
-    //             match terminate_session(&aws_client, start_session_output.session_id.clone()).await {
+    //             match terminate_session(&aws_client, &session.session_id).await {
     //             Ok(_) => return Err(SSMError { kind: SSMErrorKind::IO, msg: "launch_ssm_tunnel : Error while attempting to unwrap tunnel child stdout".into() }),
     //             _ => return Err(SSMError { kind: SSMErrorKind::IO, msg: "launch_ssm_tunnel : Error while attempting to unwrap tunnel child stdout AND trying to terminate session".into() })
     //         }
@@ -204,9 +334,13 @@ pub async fn launch_ssm_tunnel(
 
     send_or_terminate_session(
         tx_tunnel_launched,
-        SSMTunnelLaunchedMessage { ok: true },
+        SSMTunnelLaunchedMessage {
+            ok: true,
+            session_id: session.session_id.clone(),
+            multiplexing,
+        },
         &aws_client,
-        start_session_output.session_id.clone(),
+        &session.session_id,
     )
     .await?;
 
@@ -215,12 +349,31 @@ pub async fn launch_ssm_tunnel(
         &logs_sender,
     );
 
-    receive_or_terminate_session(
-        rx_app_exit.await,
-        &aws_client,
-        start_session_output.session_id.clone(),
-    )
-    .await?;
+    let mut keepalive_interval = commands.keepalive_interval_secs.map(Duration::from_secs);
+    if keepalive_interval.is_some() && !multiplexing {
+        send_log(
+            "SSM Tunnel : The session manager plugin does not multiplex connections, idle sessions are not kept alive".into(),
+            &logs_sender,
+        );
+        keepalive_interval = None;
+    }
+    let app_exit = tokio::select! {
+        app_exit = &mut rx_app_exit => app_exit,
+        closed = watch_plugin(
+            &mut tunnel_child,
+            plugin_output,
+            &relay,
+            &traffic,
+            keepalive_interval,
+            &logs_sender,
+        ) => {
+            send_log(format!("SSM Tunnel : {}", closed.reason), &logs_sender);
+            // The handler then stops the tunnel like any other time
+            let _ = tx_tunnel_closed.send(closed);
+            rx_app_exit.await
+        }
+    };
+    receive_or_terminate_session(app_exit, &aws_client, &session.session_id).await?;
 
     send_log(
         "TunnelTaskInstance/launch_ssm_tunnel : Wait/receive app exit message OK".into(),
@@ -233,7 +386,7 @@ pub async fn launch_ssm_tunnel(
         tx_app_exit_ack,
         ApplicationExitedMessage,
         &aws_client,
-        start_session_output.session_id.clone(),
+        &session.session_id,
     )
     .await?;
 
@@ -242,7 +395,7 @@ pub async fn launch_ssm_tunnel(
         &logs_sender,
     );
 
-    match terminate_session(&aws_client, start_session_output.session_id.clone()).await {
+    match terminate_session(&aws_client, &session.session_id).await {
         Ok(_) => send_log("SSM Tunnel : Session terminated".into(), &logs_sender),
         _ => send_log(
             "SSM Tunnel : Error while trying to terminate session".into(),
@@ -271,10 +424,10 @@ fn receive<T, E>(res: Result<T, E>) -> Result<(), SSMError> {
     .map(|_| ())
 }
 
-async fn receive_or_terminate_session<T>(
+async fn receive_or_terminate_session<T, S: SessionApi>(
     res: Result<T, RecvError>,
-    client: &Client,
-    session_id: Option<String>,
+    client: &S,
+    session_id: &str,
 ) -> Result<(), SSMError> {
     let recv_res = receive(res);
     match recv_res {
@@ -290,11 +443,11 @@ fn send<T>(tx: Sender<T>, content: T) -> Result<(), SSMError> {
     })
 }
 
-async fn send_or_terminate_session<T>(
+async fn send_or_terminate_session<T, S: SessionApi>(
     tx: Sender<T>,
     content: T,
-    client: &Client,
-    session_id: Option<String>,
+    client: &S,
+    session_id: &str,
 ) -> Result<(), SSMError> {
     let res = send(tx, content);
     match res {
@@ -303,61 +456,119 @@ async fn send_or_terminate_session<T>(
     }
 }
 
-async fn initiate_aws_client() -> Client {
-    let Opt { region, verbose: _ } = Opt::parse();
+/**
+ * Region precedence : target region, then `--region`, then the default provider chain
+ * of the target profile
+ */
+pub async fn load_aws_config(target: &TargetConfig) -> aws_config::SdkConfig {
+    let Opt { region, .. } = Opt::parse();
+
+    let mut profile_region = DefaultRegionChain::builder();
+    if let Some(profile) = &target.profile {
+        profile_region = profile_region.profile_name(profile);
+    }
+    let region_provider = RegionProviderChain::first_try(target.region.clone().map(Region::new))
+        .or_else(region.map(Region::new))
+        .or_else(profile_region.build())
+        .or_else(Region::new(DEFAULT_REGION));
+
+    let mut loader = aws_config::defaults(BehaviorVersion::latest()).region(region_provider);
+    if let Some(profile) = &target.profile {
+        loader = loader.profile_name(profile);
+    }
+    // Settings that cannot give a client were already reported and dropped at config load
+    if let Ok(Some(http_client)) = proxy::http_client(&target.proxy) {
+        loader = loader.http_client(http_client);
+    }
+    loader.load().await
+}
+
+pub async fn initiate_aws_client(target: &TargetConfig) -> Client {
+    let shared_config = load_aws_config(target).await;
 
-    let region_provider = RegionProviderChain::first_try(region.map(Region::new))
-        .or_default_provider()
-        .or_else(Region::new("eu-west-1"));
+    let mut config = aws_sdk_ssm::config::Builder::from(&shared_config);
+    if let Some(endpoint_url) = &target.endpoints.ssm {
+        config = config.endpoint_url(endpoint_url);
+    }
+    Client::from_conf(config.build())
+}
 
-    let shared_config = aws_config::defaults(BehaviorVersion::latest())
-        .region(region_provider)
-        .load()
-        .await;
+/**
+ * ARN of who the calls for the target run as, through the STS endpoint of the target
+ */
+pub async fn get_caller_identity(target: &TargetConfig) -> Result<String, SSMError> {
+    let shared_config = load_aws_config(target).await;
+    let mut config = aws_sdk_sts::config::Builder::from(&shared_config);
+    if let Some(endpoint_url) = &target.endpoints.sts {
+        config = config.endpoint_url(endpoint_url);
+    }
 
-    Client::new(&shared_config)
+    let identity = aws_sdk_sts::Client::from_conf(config.build())
+        .get_caller_identity()
+        .send()
+        .await
+        .map_err(|e| SSMError {
+            kind: SSMErrorKind::CallerIdentity,
+            msg: format!("Unable to get caller identity : {}", e),
+        })?;
+    Ok(identity.arn().unwrap_or("unknown").to_string())
 }
 
-#[derive(Debug, Parser)]
-struct Opt {
-    /// The AWS Region.
-    #[structopt(short, long)]
-    region: Option<String>,
+/**
+ * Logs who the tunnel runs as and returns the ARN for the session history. Only
+ * informative, a failure here is left for StartSession to report
+ */
+pub async fn log_caller_identity(
+    target: &TargetConfig,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Option<String> {
+    match get_caller_identity(target).await {
+        Ok(arn) => {
+            send_log(format!("AWS : Identity {}", arn), logs_sender);
+            Some(arn)
+        }
+        Err(e) => {
+            send_log(format!("AWS : {}", e.msg), logs_sender);
+            None
+        }
+    }
+}
 
-    /// Whether to display additional information.
-    #[structopt(short, long)]
-    verbose: bool,
+pub fn client_region(client: &Client) -> String {
+    client
+        .config()
+        .region()
+        .map(|region| region.to_string())
+        .unwrap_or_else(|| DEFAULT_REGION.to_string())
 }
 
 // Starts a SSM session
 // snippet-start:[ssm.rust.start-session]
-async fn start_session(
+async fn start_session<S: SessionApi>(
     target: String,
-    client: &Client,
+    client: &S,
     local_port_number: String,
-) -> Result<StartSessionOutput, SdkError<StartSessionError>> {
+    remote_port_number: String,
+) -> Result<SessionHandle, SSMError> {
+    let parameters = HashMap::from([
+        ("localPortNumber".to_string(), vec![local_port_number]),
+        ("portNumber".to_string(), vec![remote_port_number]),
+    ]);
+
     client
-        .start_session()
-        .target(target)
-        .document_name("AWS-StartPortForwardingSession")
-        .parameters("localPortNumber", vec![local_port_number])
-        .parameters("portNumber", vec![REMOTE_PORT_NUMBER.to_string()])
-        .send()
+        .start_session(&target, PORT_FORWARDING_DOCUMENT, parameters)
         .await
 }
 
 async fn initiate_ssm_port_forwarding(
-    start_session_output: &StartSessionOutput,
+    session: &SessionHandle,
+    region: &str,
+    session_manager_plugin_command: &str,
+    proxy: &ProxyConfig,
 ) -> Result<std::process::Child, SSMError> {
     // create ssm plugin json message
-    let response = ResponseJson {
-        SessionId: start_session_output.session_id().unwrap().into(),
-        TokenValue: start_session_output.token_value().unwrap().into(), // Assuming `token` is defined elsewhere
-        StreamUrl: start_session_output.stream_url().unwrap().into(),
-    };
-
     let response_string =
-        match serde_json::to_string(&response) {
+        match serde_json::to_string(session) {
             Ok(res) => res,
             Err(_) => return Err(SSMError {
                 kind: SSMErrorKind::Serde,
@@ -367,12 +578,15 @@ async fn initiate_ssm_port_forwarding(
             }),
         };
 
-    let mut session_manager_plugin = Command::new("session-manager-plugin");
-    let run_command_output = session_manager_plugin
-        .args([response_string, "eu-west-1".into(), "StartSession".into()])
-        .creation_flags(CREATE_NO_WINDOW)
-        //.stdout(Stdio::piped())
-        .spawn();
+    let mut session_manager_plugin = Command::new(session_manager_plugin_command);
+    session_manager_plugin
+        .env(START_SESSION_RESPONSE_ENV, response_string)
+        .envs(proxy::plugin_env(proxy))
+        .args([START_SESSION_RESPONSE_ENV, region, "StartSession"])
+        .stdout(Stdio::piped());
+    #[cfg(windows)]
+    session_manager_plugin.creation_flags(CREATE_NO_WINDOW);
+    let run_command_output = session_manager_plugin.spawn();
 
     match run_command_output {
         Ok(c) => Ok(c),
@@ -380,6 +594,266 @@ async fn initiate_ssm_port_forwarding(
     }
 }
 
+/**
+ * Whether the plugin serves several connections over the session, from its version.
+ * An unknown version is taken as not doing it
+ */
+async fn plugin_multiplexing(
+    session_manager_plugin_command: &str,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> bool {
+    let mut plugin = tokio::process::Command::new(session_manager_plugin_command);
+    plugin.arg("--version");
+    #[cfg(windows)]
+    plugin.creation_flags(CREATE_NO_WINDOW);
+    let version = match plugin.output().await {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "unknown".into(),
+    };
+    let multiplexing = supports_multiplexing(&version);
+    send_log(
+        format!(
+            "TunnelTaskInstance/launch_ssm_tunnel : Session manager plugin version {}, {}",
+            version,
+            match multiplexing {
+                true => "multiplexing connections",
+                false => "one connection per session",
+            }
+        ),
+        logs_sender,
+    );
+    multiplexing
+}
+
+fn supports_multiplexing(version: &str) -> bool {
+    let Ok(version) = version
+        .split('.')
+        .map(|part| part.parse::<u32>())
+        .collect::<Result<Vec<u32>, _>>()
+    else {
+        return false;
+    };
+    version.as_slice() >= MULTIPLEXING_PLUGIN_VERSION.as_slice()
+}
+
+/**
+ * Keeps the path and query of the stream url with the scheme and host of the endpoint,
+ * an http endpoint giving ws and an https one wss
+ */
+fn stream_url_with_endpoint(stream_url: &str, endpoint_url: &str) -> String {
+    let path = stream_url
+        .split_once("://")
+        .and_then(|(_, rest)| rest.find('/').map(|index| &rest[index..]))
+        .unwrap_or_default();
+    let endpoint = endpoint_url.trim_end_matches('/');
+    let endpoint = match endpoint.split_once("://") {
+        Some(("http", host)) => format!("ws://{}", host),
+        Some(("https", host)) => format!("wss://{}", host),
+        Some(_) => endpoint.to_string(),
+        None => format!("wss://{}", endpoint),
+    };
+    format!("{}{}", endpoint, path)
+}
+
+fn parse_local_port(local_port_number: &str) -> Result<u16, SSMError> {
+    local_port_number
+        .trim()
+        .parse::<u16>()
+        .map_err(|_| SSMError {
+            kind: SSMErrorKind::PortInUse,
+            msg: format!(
+                "parse_local_port : Invalid local port {}",
+                local_port_number
+            ),
+        })
+}
+
+/**
+ * Held for the whole tunnel, so that nothing else takes the port while the session starts
+ */
+async fn bind_local_port(local_port_number: &str) -> Result<tokio::net::TcpListener, SSMError> {
+    let local_port = parse_local_port(local_port_number)?;
+    tokio::net::TcpListener::bind((LOCAL_HOST, local_port))
+        .await
+        .map_err(|_| SSMError {
+            kind: SSMErrorKind::PortInUse,
+            msg: format!(
+                "bind_local_port : Local port {} is already in use",
+                local_port
+            ),
+        })
+}
+
+/**
+ * Free when picked, another process may still take it before the plugin listens there,
+ * which the plugin then fails on and `wait_for_tunnel_ready` reports
+ */
+fn free_plugin_port() -> Result<u16, SSMError> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .map_err(|e| SSMError {
+            kind: SSMErrorKind::IO,
+            msg: format!("free_plugin_port : Unable to find a free port : {}", e),
+        })
+}
+
+/**
+ * The plugin is ready once it reports listening on its port. Its output tells without
+ * opening a connection, which would otherwise go all the way to the instance
+ */
+async fn wait_for_tunnel_ready(
+    tunnel_child: &mut std::process::Child,
+    plugin_output: Option<&PluginOutput>,
+    local_port: u16,
+    timeout: Duration,
+) -> Result<(), SSMError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if let Ok(Some(status)) = tunnel_child.try_wait() {
+            return Err(SSMError {
+                kind: SSMErrorKind::CommandExit,
+                msg: format!(
+                    "wait_for_tunnel_ready : Session manager plugin exited with {}",
+                    status
+                ),
+            });
+        }
+        if plugin_output.is_some_and(|plugin_output| plugin_output.opened()) {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(SSMError {
+                kind: SSMErrorKind::TunnelTimeout,
+                msg: format!(
+                    "wait_for_tunnel_ready : Session manager plugin not listening on port {} after {}s",
+                    local_port,
+                    timeout.as_secs()
+                ),
+            });
+        }
+        tokio::time::sleep(TUNNEL_READY_POLL_INTERVAL).await;
+    }
+}
+
+/**
+ * Logs what the plugin prints, on a thread of its own as reading blocks, and notes
+ * whether it opened its port and whether it reported the idle session timeout
+ */
+struct PluginOutput {
+    reader: std::thread::JoinHandle<()>,
+    opened: Arc<AtomicBool>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl PluginOutput {
+    fn start(
+        stdout: std::process::ChildStdout,
+        plugin_port: u16,
+        logs_sender: std::sync::mpsc::Sender<String>,
+    ) -> PluginOutput {
+        let opened = Arc::new(AtomicBool::new(false));
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let reader_opened = opened.clone();
+        let reader_timed_out = timed_out.clone();
+        // `Port 55678 opened for sessionId ...`, on the port asked for and not one taken meanwhile
+        let opened_output = format!("Port {} opened", plugin_port);
+        let reader = std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                if line.starts_with(&opened_output) {
+                    reader_opened.store(true, Ordering::Relaxed);
+                }
+                if line.contains(IDLE_TIMEOUT_OUTPUT) {
+                    reader_timed_out.store(true, Ordering::Relaxed);
+                }
+                send_log(format!("Session manager plugin : {}", line), &logs_sender);
+            }
+        });
+        PluginOutput {
+            reader,
+            opened,
+            timed_out,
+        }
+    }
+
+    fn opened(&self) -> bool {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    // Once the plugin exited, its last lines may still be on their way
+    async fn timed_out(self) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        self.timed_out.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * Returns once the plugin exits on its own, which means the session was closed on the
+ * server side. Meanwhile sends keepalives through the relay when nothing went through for
+ * their interval
+ */
+async fn watch_plugin(
+    tunnel_child: &mut std::process::Child,
+    plugin_output: Option<PluginOutput>,
+    relay: &Relay,
+    traffic: &TrafficStats,
+    keepalive_interval: Option<Duration>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> TunnelClosedMessage {
+    let mut last_keepalive = Instant::now();
+    loop {
+        tokio::time::sleep(PLUGIN_POLL_INTERVAL).await;
+        if let Ok(Some(status)) = tunnel_child.try_wait() {
+            let reported = match plugin_output {
+                Some(plugin_output) => plugin_output.timed_out().await,
+                None => false,
+            };
+            // The plugin ends cleanly on a timeout, its message may change between versions
+            let timed_out = status.success()
+                && (reported || traffic.idle_for() >= DEFAULT_IDLE_SESSION_TIMEOUT);
+            return TunnelClosedMessage {
+                timed_out,
+                reason: match timed_out {
+                    true => format!(
+                        "Session closed by SSM after its idle session timeout, session manager plugin exited with {}",
+                        status
+                    ),
+                    false => format!(
+                        "Session closed by the server, session manager plugin exited with {}",
+                        status
+                    ),
+                },
+            };
+        }
+        if let Some(interval) = keepalive_interval
+            && last_keepalive.elapsed() >= interval
+            && traffic.idle_for() >= interval
+        {
+            match relay.keepalive().await {
+                Ok(true) => send_log("SSM Tunnel : Keepalive sent".into(), logs_sender),
+                Ok(false) => send_log(
+                    "SSM Tunnel : Keepalive skipped, a client connection is open".into(),
+                    logs_sender,
+                ),
+                Err(e) => send_log(
+                    format!("SSM Tunnel : Unable to send keepalive : {}", e),
+                    logs_sender,
+                ),
+            }
+            last_keepalive = Instant::now();
+        }
+    }
+}
+
 /**
  * Wonky stuff, if AWS SSM changes its log output, it might make the following break
  */
@@ -417,26 +891,14 @@ fn output_tunnel(
 }
 
 // Terminates a SSM session
-// snippet-start:[ssm.rust.start-session]
-async fn terminate_session(
-    client: &Client,
-    session_id: Option<String>,
-) -> Result<Option<String>, aws_sdk_ssm::Error> {
-    let resp = client
-        .terminate_session()
-        .set_session_id(session_id)
-        .send()
-        .await?;
-
-    let TerminateSessionOutput { session_id, .. } = resp;
-
-    Ok(session_id)
+async fn terminate_session<S: SessionApi>(client: &S, session_id: &str) -> Result<(), SSMError> {
+    client.terminate_session(session_id).await
 }
 
-async fn terminate_session_with_error<T>(
+async fn terminate_session_with_error<T, S: SessionApi>(
     err: SSMError,
-    client: &Client,
-    session_id: Option<String>,
+    client: &S,
+    session_id: &str,
 ) -> Result<T, SSMError> {
     match terminate_session(client, session_id).await {
         Ok(_) => Err(err),
@@ -453,9 +915,80 @@ async fn terminate_session_with_error<T>(
     }
 }
 
-#[derive(Serialize)]
-struct ResponseJson {
-    SessionId: String,
-    TokenValue: String,
-    StreamUrl: String,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_api::fake::FakeSessionApi;
+
+    fn tunnel_error() -> SSMError {
+        SSMError {
+            kind: SSMErrorKind::CommandSpawn,
+            msg: "plugin not found".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_terminate_session_with_error_keeps_original_error() {
+        let session_api = FakeSessionApi::with_targets(&["i-0f30a1dd89600b0dc"]);
+        let session = start_session(
+            "i-0f30a1dd89600b0dc".into(),
+            &session_api,
+            "9090".into(),
+            RDP_PORT_NUMBER.into(),
+        )
+        .await
+        .unwrap();
+
+        let res: Result<(), SSMError> =
+            terminate_session_with_error(tunnel_error(), &session_api, &session.session_id).await;
+
+        assert!(res.is_err_and(|e| matches!(e.kind, SSMErrorKind::CommandSpawn)));
+        assert_eq!(session_api.terminated_sessions(), vec![session.session_id]);
+        assert!(session_api.active_sessions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_terminate_session_with_error_reports_terminate_failure() {
+        let session_api =
+            FakeSessionApi::with_targets(&["i-0f30a1dd89600b0dc"]).failing_terminate();
+        let session = start_session(
+            "i-0f30a1dd89600b0dc".into(),
+            &session_api,
+            "9090".into(),
+            RDP_PORT_NUMBER.into(),
+        )
+        .await
+        .unwrap();
+
+        let res: Result<(), SSMError> =
+            terminate_session_with_error(tunnel_error(), &session_api, &session.session_id).await;
+
+        assert!(res.is_err_and(|e| matches!(e.kind, SSMErrorKind::TerminateSession)));
+        assert_eq!(session_api.active_sessions(), vec![session.session_id]);
+    }
+
+    #[test]
+    fn test_supports_multiplexing() {
+        assert!(supports_multiplexing("1.2.707.0"));
+        assert!(supports_multiplexing("1.2.7.0"));
+        assert!(!supports_multiplexing("1.1.61.0"));
+        assert!(!supports_multiplexing("unknown"));
+        assert!(!supports_multiplexing(""));
+    }
+
+    #[test]
+    fn test_stream_url_with_endpoint() {
+        let stream_url = "wss://ssmmessages.eu-west-1.amazonaws.com/v1/data-channel/user-0abc?role=publish_subscribe";
+        assert_eq!(
+            stream_url_with_endpoint(
+                stream_url,
+                "https://vpce-0123-abcd.ssmmessages.eu-west-1.vpce.amazonaws.com/"
+            ),
+            "wss://vpce-0123-abcd.ssmmessages.eu-west-1.vpce.amazonaws.com/v1/data-channel/user-0abc?role=publish_subscribe"
+        );
+        assert_eq!(
+            stream_url_with_endpoint(stream_url, "http://localhost:4566"),
+            "ws://localhost:4566/v1/data-channel/user-0abc?role=publish_subscribe"
+        );
+    }
 }
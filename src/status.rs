@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const STATUS_FILE: &str = "vm-connect-sessions.json";
+
+// Rewritten every second by the GUI while tunnels are up, an older file was left by an
+// application that is gone
+const STALE_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct StatusError {
+    pub kind: StatusErrorKind,
+    pub msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StatusErrorKind {
+    IO,
+    Serde,
+    NotRunning,
+}
+
+/**
+ * Health of one active tunnel, written by the GUI for `vm-connect sessions`
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    pub target: String,
+    pub instance_id: String,
+    pub local_port: String,
+    pub session_id: Option<String>,
+    pub uptime_secs: u64,
+    /// From the client to the instance
+    pub bytes_sent: u64,
+    /// From the instance to the client
+    pub bytes_received: u64,
+    /// Both ways, in bytes per second over the last second
+    pub throughput: u64,
+    pub connections: usize,
+    /// Smoothed time for the instance to answer through the tunnel, unknown before any exchange
+    pub response_time_ms: Option<u64>,
+}
+
+// Written first, then renamed over the file
+pub fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension("json.tmp")
+}
+
+/**
+ * Replaces the file in one step, so that a reader never sees it half written
+ */
+pub fn write(path: &Path, tunnels: &[TunnelStatus]) -> Result<(), StatusError> {
+    let content = serde_json::to_string_pretty(tunnels).map_err(|e| StatusError {
+        kind: StatusErrorKind::Serde,
+        msg: format!("write : Unable to serialize tunnel status : {}", e),
+    })?;
+    let temp_path = temp_path(path);
+    fs::write(&temp_path, content)
+        .and_then(|_| fs::rename(&temp_path, path))
+        .map_err(|e| StatusError {
+            kind: StatusErrorKind::IO,
+            msg: format!("write : Unable to write {} : {}", path.display(), e),
+        })
+}
+
+/**
+ * No file means no active tunnel, the GUI only writes it while there are some
+ */
+pub fn read(path: &Path) -> Result<Vec<TunnelStatus>, StatusError> {
+    let not_running = || StatusError {
+        kind: StatusErrorKind::NotRunning,
+        msg: "vm-connect is not running in this directory".into(),
+    };
+    let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(_) => return Err(not_running()),
+    };
+    if SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age > STALE_AFTER)
+    {
+        return Err(not_running());
+    }
+
+    let content = fs::read_to_string(path).map_err(|e| StatusError {
+        kind: StatusErrorKind::IO,
+        msg: format!("read : Unable to read {} : {}", path.display(), e),
+    })?;
+    serde_json::from_str(&content).map_err(|e| StatusError {
+        kind: StatusErrorKind::Serde,
+        msg: format!("read : Invalid {} : {}", path.display(), e),
+    })
+}
+
+pub fn format_table(tunnels: &[TunnelStatus]) -> String {
+    let header = [
+        "TARGET",
+        "INSTANCE",
+        "PORT",
+        "SESSION",
+        "UPTIME",
+        "SENT",
+        "RECEIVED",
+        "THROUGHPUT",
+        "CONNECTIONS",
+        "RESPONSE",
+    ]
+    .map(String::from);
+    let rows = tunnels.iter().map(|tunnel| {
+        [
+            tunnel.target.clone(),
+            tunnel.instance_id.clone(),
+            tunnel.local_port.clone(),
+            tunnel.session_id.clone().unwrap_or_else(|| "-".into()),
+            format_duration(tunnel.uptime_secs),
+            format_bytes(tunnel.bytes_sent),
+            format_bytes(tunnel.bytes_received),
+            format!("{}/s", format_bytes(tunnel.throughput)),
+            tunnel.connections.to_string(),
+            format_response_time(tunnel.response_time_ms),
+        ]
+    });
+    align_columns(std::iter::once(header).chain(rows).collect())
+}
+
+/**
+ * Pads the cells so that columns line up, the first row being the header
+ */
+pub fn align_columns<const N: usize>(rows: Vec<[String; N]>) -> String {
+    let mut widths = [0; N];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn format_duration(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+pub fn format_response_time(response_time_ms: Option<u64>) -> String {
+    response_time_ms
+        .map(|ms| format!("{} ms", ms))
+        .unwrap_or_else(|| "-".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vm-connect-{}-{}", std::process::id(), name))
+    }
+
+    fn tunnel() -> TunnelStatus {
+        TunnelStatus {
+            target: "VM 1".into(),
+            instance_id: "i-0123456789abcdef0".into(),
+            local_port: "55678".into(),
+            session_id: Some("user-0123456789abcdef0".into()),
+            uptime_secs: 3723,
+            bytes_sent: 512,
+            bytes_received: 5 * 1024 * 1024 + 300 * 1024,
+            throughput: 2048,
+            connections: 1,
+            response_time_ms: Some(42),
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path = temp_file("sessions.json");
+        write(&path, &[tunnel()]).unwrap();
+        assert_eq!(read(&path).unwrap(), vec![tunnel()]);
+
+        // Left behind by an application that exited without cleaning up
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now() - Duration::from_secs(60)))
+            .unwrap();
+        assert!(read(&path).is_err_and(|e| e.kind == StatusErrorKind::NotRunning));
+
+        let _ = fs::remove_file(&path);
+        assert!(read(&path).is_ok_and(|tunnels| tunnels.is_empty()));
+    }
+
+    #[test]
+    fn test_format_table() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(5 * 1024 * 1024 + 300 * 1024), "5.3 MB");
+        assert_eq!(format_duration(3723), "1:02:03");
+
+        let table = format_table(&[
+            tunnel(),
+            TunnelStatus {
+                session_id: None,
+                response_time_ms: None,
+                ..tunnel()
+            },
+        ]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("TARGET  INSTANCE             PORT   SESSION"));
+        assert!(lines[1].ends_with("1:02:03  512 B  5.3 MB    2.0 KB/s    1            42 ms"));
+        assert!(lines[2].ends_with("1            -"));
+    }
+}
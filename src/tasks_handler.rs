@@ -1,22 +1,55 @@
+use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot::Receiver;
+use tokio::sync::watch;
 
-use crate::messages::ApplicationExitedMessage;
-use crate::rdp::{RDPError, RDPTaskInstance};
-use crate::ssm::{SSMError, TunnelTaskInstance};
+use crate::config::{CommandsConfig, ForwardConfig, LauncherConfig, TargetConfig};
+use crate::credentials::{self, CredentialsError};
+use crate::messages::{ApplicationExitedMessage, SessionInfoMessage, TunnelClosedMessage};
+use crate::rdp::{RDPError, RDPTaskInstance, RdpCredentials, RdpLaunch};
+use crate::rdp_probe::{self, ProbeAnswer, ProbeError, ProbeErrorKind};
+use crate::relay::TrafficStats;
+use crate::session_api::SessionApi;
+use crate::ssm::{LOCAL_HOST, RDP_PORT_NUMBER, SSMError, TunnelSpec, TunnelTaskInstance};
 use crate::utils::send_log;
 
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * What is opened on the local port once the tunnel is up
+ */
+#[derive(Debug, Clone)]
+pub enum Connection {
+    /// The .rdp file, opened with `rdp_client` or the launcher of the target, and the username
+    /// typed in the GUI, for `{username}` when no credentials are injected
+    Rdp(String, String),
+    /// Another port of the instance, opened with the launcher of the forward
+    Forward(ForwardConfig),
+    /// Only the tunnel to this port of the instance, kept up until stopped
+    Tunnel(String),
+}
+
+// The connection with its launcher, resolved before the tunnel is opened
+enum Client {
+    Rdp(String, Option<LauncherConfig>, String),
+    Forward(LauncherConfig, Option<String>),
+    Tunnel,
+}
+
 #[derive(Debug)]
 pub struct TaskHandlerError {
     pub kind: TaskHandlerErrorKind,
     pub msg: String,
 }
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum TaskHandlerErrorKind {
     SSM,
     RDP,
     RDPAndSSM,
+    Credentials,
+    SSMTimeout,
 }
 
 impl Display for TaskHandlerErrorKind {
@@ -25,36 +58,123 @@ impl Display for TaskHandlerErrorKind {
             Self::SSM => write!(f, "SSM Error"),
             Self::RDP => write!(f, "RDP Error"),
             Self::RDPAndSSM => write!(f, "SSM and RDP Error"),
+            Self::Credentials => write!(f, "Credentials Error"),
+            Self::SSMTimeout => write!(f, "SSM Idle Timeout"),
         }
     }
 }
 
-pub async fn start(
-    target: String,
-    rdp_file_path: String,
-    rx_app_exit: Receiver<ApplicationExitedMessage>,
-    local_port_number: String,
-    logs_sender: std::sync::mpsc::Sender<String>,
+/**
+ * What to open on which local port, and with what
+ */
+pub struct ConnectionSpec {
+    pub target: TargetConfig,
+    pub connection: Connection,
+    /// Typed in the GUI, only given when they are to be injected
+    pub credentials: Option<RdpCredentials>,
+    pub local_port_number: String,
+    pub commands: CommandsConfig,
+}
+
+/**
+ * Between the GUI and the handler of one connection
+ */
+pub struct HandlerChannels {
+    pub app_exit_receiver: Receiver<ApplicationExitedMessage>,
+    pub session_info_sender: std::sync::mpsc::Sender<SessionInfoMessage>,
+    /// Maximum duration of the connection, which the GUI may extend
+    pub deadline_receiver: watch::Receiver<Option<Instant>>,
+    pub logs_sender: std::sync::mpsc::Sender<String>,
+}
+
+pub async fn start<S: SessionApi>(
+    session_api: S,
+    spec: ConnectionSpec,
+    channels: HandlerChannels,
 ) -> Result<(), TaskHandlerError> {
+    let ConnectionSpec {
+        target,
+        connection,
+        credentials,
+        local_port_number,
+        commands,
+    } = spec;
+    let HandlerChannels {
+        app_exit_receiver: rx_app_exit,
+        session_info_sender,
+        mut deadline_receiver,
+        logs_sender,
+    } = channels;
     send_log("Task handler : Starting handler...".into(), &logs_sender);
-    let mut tunnel_task_instance =
-        TunnelTaskInstance::spawn(target, local_port_number, logs_sender.clone());
+
+    // Fetched before the tunnel is opened so that a missing secret does not leave a session behind,
+    // and only when credentials are to be injected at all
+    let credentials = match (&connection, &target.credentials_source, credentials) {
+        (Connection::Rdp(..), Some(source), Some(typed)) => Some(
+            credentials::fetch(&target, source, Some(&typed), &logs_sender)
+                .await
+                .map_err(transform_credentials_error)?,
+        ),
+        (_, _, credentials) => credentials,
+    };
+    let (client, remote_port_number) = match connection {
+        Connection::Rdp(rdp_file_path, username) => (
+            commands
+                .launcher_for(&target)
+                .map(|launcher| Client::Rdp(rdp_file_path, launcher, username)),
+            RDP_PORT_NUMBER.to_string(),
+        ),
+        Connection::Forward(forward) => (
+            commands
+                .forward_launcher(&forward)
+                .map(|launcher| Client::Forward(launcher, forward.username)),
+            forward.remote_port.to_string(),
+        ),
+        Connection::Tunnel(remote_port_number) => (Ok(Client::Tunnel), remote_port_number),
+    };
+    let client = client.map_err(|msg| TaskHandlerError {
+        kind: TaskHandlerErrorKind::RDP,
+        msg,
+    })?;
+
+    let _ = session_info_sender.send(SessionInfoMessage::Region(session_api.region()));
+    let rdp_port = local_port_number.clone();
+    let mut tunnel_task_instance = TunnelTaskInstance::spawn(
+        session_api,
+        TunnelSpec {
+            target,
+            local_port_number,
+            remote_port_number,
+            commands: commands.clone(),
+        },
+        logs_sender.clone(),
+    );
 
     send_log("Task handler : Spawned SSM task".into(), &logs_sender);
 
     // Wait for tunnel to be set up
     // take() because when value has been received it is invalidated
-    if tunnel_task_instance
+    let tunnel_launched = match tunnel_task_instance
         .tunnel_created_receiver
         .take()
         .unwrap()
         .await
-        .map_err(|err| TaskHandlerError {
-            kind: TaskHandlerErrorKind::SSM,
-            msg: err.to_string(),
-        })?
-        .ok
     {
+        Ok(tunnel_launched) => tunnel_launched,
+        // The tunnel task drops the sender when it fails before the tunnel is up,
+        // its own error tells why
+        Err(_) => {
+            return Err(transform_ssm_error(
+                tunnel_task_instance.start_error().await,
+            ));
+        }
+    };
+    if tunnel_launched.ok {
+        // Receiver is gone when nobody keeps the history of this connection
+        let _ = session_info_sender.send(SessionInfoMessage::SessionId(tunnel_launched.session_id));
+        let _ = session_info_sender.send(SessionInfoMessage::Traffic(
+            tunnel_task_instance.traffic.clone(),
+        ));
         send_log(
             "Task handler : Should spawn RDP now, just wait for now".into(),
             &logs_sender,
@@ -67,16 +187,81 @@ pub async fn start(
         });
     }
 
+    // Fails before the client is launched rather than leaving it to report a dead service
+    if let Client::Rdp(..) = client
+        && let Some(timeout) =
+            rdp_probe_timeout(&commands, tunnel_launched.multiplexing, &logs_sender)
+        && let Err(e) = probe_rdp_service(&rdp_port, timeout, &logs_sender).await
+    {
+        if let Err(ssm_err) = tunnel_task_instance.stop().await {
+            send_log("Task handler : ".to_string() + &ssm_err.msg, &logs_sender);
+        }
+        return Err(e);
+    }
+
     send_log("Task handler : Try to spawn RDP".into(), &logs_sender);
 
-    let rdp_task_instance_result =
-        RDPTaskInstance::spawn(rdp_file_path, rx_app_exit, logs_sender.clone());
+    let rdp_task_instance_result = match client {
+        Client::Tunnel => {
+            let result = keep_tunnel_open(
+                tunnel_task_instance,
+                rx_app_exit,
+                &rdp_port,
+                &commands,
+                &session_info_sender,
+                &mut deadline_receiver,
+                &logs_sender,
+            )
+            .await;
+            send_log("Task handler : Stop handler".into(), &logs_sender);
+            return result;
+        }
+        Client::Rdp(rdp_file_path, launcher, username) => RDPTaskInstance::spawn(
+            RdpLaunch {
+                path: rdp_file_path,
+                local_port_number: rdp_port,
+                rdp_client: commands.rdp_client.clone(),
+                launcher,
+                username,
+                credentials,
+            },
+            rx_app_exit,
+            logs_sender.clone(),
+        ),
+        Client::Forward(launcher, username) => RDPTaskInstance::spawn_forward(
+            launcher,
+            rdp_port,
+            username,
+            rx_app_exit,
+            logs_sender.clone(),
+        ),
+    };
 
     let result = match rdp_task_instance_result {
         Ok(mut rdp_task_instance) => {
-            let rdp_exit_result = rdp_task_instance.wait_for_exit_or_task_done().await;
+            let mut tunnel_closed = None;
+            let rdp_exit_result = tokio::select! {
+                result = rdp_task_instance.wait_for_exit_or_task_done() => result,
+                _ = wait_for_idle(
+                    &tunnel_task_instance.traffic,
+                    &commands,
+                    &session_info_sender,
+                    &logs_sender,
+                ) => rdp_task_instance.kill(),
+                _ = wait_for_deadline(&mut deadline_receiver) => {
+                    max_duration_reached(&session_info_sender, &logs_sender);
+                    rdp_task_instance.kill()
+                }
+                Ok(closed) = &mut tunnel_task_instance.tunnel_closed_receiver => {
+                    tunnel_closed = Some(closed);
+                    rdp_task_instance.kill()
+                }
+            };
             let ssm_exit_result = tunnel_task_instance.stop().await;
-            combine_ssm_rdp_results(ssm_exit_result, rdp_exit_result)
+            match tunnel_closed {
+                Some(closed) => Err(transform_tunnel_closed(closed)),
+                None => combine_ssm_rdp_results(ssm_exit_result, rdp_exit_result),
+            }
         }
         Err(e) => combine_ssm_rdp_results(tunnel_task_instance.stop().await, Err(e)),
     };
@@ -86,6 +271,198 @@ pub async fn start(
     result
 }
 
+/**
+ * Keeps the tunnel up without a client, until it is stopped from the application,
+ * closed for inactivity or at the maximum duration
+ */
+async fn keep_tunnel_open(
+    mut tunnel_task_instance: TunnelTaskInstance,
+    rx_app_exit: Receiver<ApplicationExitedMessage>,
+    local_port_number: &str,
+    commands: &CommandsConfig,
+    session_info_sender: &std::sync::mpsc::Sender<SessionInfoMessage>,
+    deadline_receiver: &mut watch::Receiver<Option<Instant>>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<(), TaskHandlerError> {
+    send_log(
+        format!(
+            "Task handler : Tunnel open on {}:{}, no client launched",
+            LOCAL_HOST, local_port_number
+        ),
+        logs_sender,
+    );
+    let mut tunnel_closed = None;
+    tokio::select! {
+        // Sender dropped means the app is gone too
+        _ = rx_app_exit => {}
+        _ = wait_for_idle(
+            &tunnel_task_instance.traffic,
+            commands,
+            session_info_sender,
+            logs_sender,
+        ) => {}
+        _ = wait_for_deadline(deadline_receiver) => {
+            max_duration_reached(session_info_sender, logs_sender);
+        }
+        Ok(closed) = &mut tunnel_task_instance.tunnel_closed_receiver => {
+            tunnel_closed = Some(closed);
+        }
+    }
+    let ssm_exit_result = tunnel_task_instance.stop().await;
+    match tunnel_closed {
+        Some(closed) => Err(transform_tunnel_closed(closed)),
+        None => ssm_exit_result.map_err(transform_ssm_error),
+    }
+}
+
+fn max_duration_reached(
+    session_info_sender: &std::sync::mpsc::Sender<SessionInfoMessage>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) {
+    send_log(
+        "Task handler : Maximum session duration reached, closing the connection".into(),
+        logs_sender,
+    );
+    let _ = session_info_sender.send(SessionInfoMessage::MaxDurationReached);
+}
+
+/**
+ * Returns once nothing went through the local port for the idle timeout, after warning
+ * ahead of it. Never returns without an idle timeout
+ */
+async fn wait_for_idle(
+    traffic: &TrafficStats,
+    commands: &CommandsConfig,
+    session_info_sender: &std::sync::mpsc::Sender<SessionInfoMessage>,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) {
+    let Some(idle_timeout) = commands.idle_timeout_secs.map(Duration::from_secs) else {
+        return std::future::pending().await;
+    };
+    let warn_after = idle_timeout.saturating_sub(Duration::from_secs(commands.idle_warning_secs));
+    let mut warned = false;
+
+    loop {
+        tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        let idle_for = traffic.idle_for();
+        if idle_for >= idle_timeout {
+            send_log(
+                format!(
+                    "Task handler : No traffic for {}s, closing the connection",
+                    idle_for.as_secs()
+                ),
+                logs_sender,
+            );
+            let _ = session_info_sender.send(SessionInfoMessage::IdleClosed);
+            return;
+        }
+        match (idle_for >= warn_after, warned) {
+            (true, false) => {
+                let closes_in = (idle_timeout - idle_for).as_secs();
+                send_log(
+                    format!(
+                        "Task handler : No traffic for {}s, closing the connection in {}s",
+                        idle_for.as_secs(),
+                        closes_in
+                    ),
+                    logs_sender,
+                );
+                let _ = session_info_sender.send(SessionInfoMessage::IdleWarning(closes_in));
+                warned = true;
+            }
+            (false, true) => {
+                send_log(
+                    "Task handler : Traffic again, idle close cancelled".into(),
+                    logs_sender,
+                );
+                let _ = session_info_sender.send(SessionInfoMessage::Active);
+                warned = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Probe timeout when the RDP service is to be probed. The probe connection closing would end
+ * the session of a plugin without multiplexing, the client is then left to report a dead service
+ */
+fn rdp_probe_timeout(
+    commands: &CommandsConfig,
+    multiplexing: bool,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Option<Duration> {
+    let timeout = commands.rdp_probe_timeout_secs.map(Duration::from_secs)?;
+    if !multiplexing {
+        send_log(
+            "Task handler : The session manager plugin does not multiplex connections, RDP service not probed".into(),
+            logs_sender,
+        );
+        return None;
+    }
+    Some(timeout)
+}
+
+async fn probe_rdp_service(
+    local_port_number: &str,
+    timeout: Duration,
+    logs_sender: &std::sync::mpsc::Sender<String>,
+) -> Result<(), TaskHandlerError> {
+    let local_port = local_port_number.parse().map_err(|_| TaskHandlerError {
+        kind: TaskHandlerErrorKind::RDP,
+        msg: format!("Invalid local port {}", local_port_number),
+    })?;
+    match rdp_probe::probe(local_port, timeout)
+        .await
+        .map_err(transform_probe_error)?
+    {
+        ProbeAnswer::Confirmed(protocol) => send_log(
+            format!(
+                "Task handler : RDP service answered through the tunnel, security protocol {}",
+                protocol
+            ),
+            logs_sender,
+        ),
+        ProbeAnswer::NegotiationFailed(reason) => send_log(
+            format!(
+                "Task handler : Warning, RDP service answered through the tunnel but refused the security protocols of the probe, {}",
+                reason
+            ),
+            logs_sender,
+        ),
+    }
+    Ok(())
+}
+
+/**
+ * Returns once the deadline set by the GUI is passed, following its extensions.
+ * Never returns without a deadline
+ */
+async fn wait_for_deadline(deadline_receiver: &mut watch::Receiver<Option<Instant>>) {
+    loop {
+        let deadline = *deadline_receiver.borrow_and_update();
+        match deadline {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deadline.into()) => return,
+                    changed = deadline_receiver.changed() => {
+                        // Nobody left to extend it, the last deadline stands
+                        if changed.is_err() {
+                            tokio::time::sleep_until(deadline.into()).await;
+                            return;
+                        }
+                    }
+                }
+            }
+            None => {
+                if deadline_receiver.changed().await.is_err() {
+                    return std::future::pending().await;
+                }
+            }
+        }
+    }
+}
+
 fn combine_ssm_rdp_errors(ssm_err: SSMError, rdp_err: RDPError) -> TaskHandlerError {
     TaskHandlerError {
         kind: TaskHandlerErrorKind::RDPAndSSM,
@@ -119,29 +496,884 @@ fn transform_rdp_error(rdp_err: RDPError) -> TaskHandlerError {
     }
 }
 
+fn transform_probe_error(probe_err: ProbeError) -> TaskHandlerError {
+    TaskHandlerError {
+        kind: match probe_err.kind {
+            // The local port of the tunnel itself did not take the connection
+            ProbeErrorKind::Connect => TaskHandlerErrorKind::SSM,
+            ProbeErrorKind::Timeout | ProbeErrorKind::Closed | ProbeErrorKind::Invalid => {
+                TaskHandlerErrorKind::RDP
+            }
+        },
+        msg: probe_err.msg,
+    }
+}
+
+fn transform_tunnel_closed(closed: TunnelClosedMessage) -> TaskHandlerError {
+    TaskHandlerError {
+        kind: match closed.timed_out {
+            true => TaskHandlerErrorKind::SSMTimeout,
+            false => TaskHandlerErrorKind::SSM,
+        },
+        msg: closed.reason,
+    }
+}
+
+fn transform_credentials_error(credentials_err: CredentialsError) -> TaskHandlerError {
+    TaskHandlerError {
+        kind: TaskHandlerErrorKind::Credentials,
+        msg: credentials_err.msg,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::config::{EndpointsConfig, ProxyConfig};
+    use crate::history::SessionRecord;
+    use crate::messages::ApplicationExitedMessage;
+    use crate::session_api::SdkSessionApi;
+    use crate::session_api::fake::FakeSessionApi;
+    use crate::test_support::{
+        fake_commands, fake_plugin_without_multiplexing, free_local_port, mock_ssm_client, rdp_file,
+    };
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::time::SystemTime;
+    use vm_connect_mock_ssm::proxy::MockProxy;
+    use vm_connect_mock_ssm::{Backend, MockSsm};
+
+    const FAKE_TARGET: &str = "i-0123456789abcdef0";
+
+    fn fake_target() -> TargetConfig {
+        TargetConfig {
+            name: "Fake VM".into(),
+            instance_id: FAKE_TARGET.into(),
+            ..Default::default()
+        }
+    }
+
+    fn start_with_fakes<S: SessionApi>(
+        session_api: S,
+        rdp_file_path: String,
+        rx_app_exit: Receiver<ApplicationExitedMessage>,
+        local_port_number: String,
+        commands: CommandsConfig,
+    ) -> Result<(), TaskHandlerError> {
+        start_target_with_fakes(
+            session_api,
+            fake_target(),
+            rdp_file_path,
+            rx_app_exit,
+            local_port_number,
+            commands,
+        )
+    }
+
+    fn start_target_with_fakes<S: SessionApi>(
+        session_api: S,
+        target: TargetConfig,
+        rdp_file_path: String,
+        rx_app_exit: Receiver<ApplicationExitedMessage>,
+        local_port_number: String,
+        commands: CommandsConfig,
+    ) -> Result<(), TaskHandlerError> {
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(start(
+                session_api,
+                ConnectionSpec {
+                    target,
+                    connection: Connection::Rdp(rdp_file_path, "Administrator".into()),
+                    credentials: None,
+                    local_port_number,
+                    commands,
+                },
+                HandlerChannels {
+                    app_exit_receiver: rx_app_exit,
+                    session_info_sender: std::sync::mpsc::channel().0,
+                    deadline_receiver: watch::channel(None).1,
+                    logs_sender,
+                },
+            ))
+    }
+
+    fn assert_session_closed(session_api: &FakeSessionApi) {
+        assert!(
+            session_api.active_sessions().is_empty(),
+            "Session left open after the task handler stopped"
+        );
+        assert_eq!(
+            session_api.terminated_sessions().len(),
+            1,
+            "Session was not terminated exactly once"
+        );
+    }
 
     #[test]
     fn test_task_handler_start_with_wrong_target() {
         let (_, rx) = tokio::sync::oneshot::channel();
         let (logs_sender, _) = std::sync::mpsc::channel();
+        let session_api = FakeSessionApi::with_targets(&["i-0f30a1dd89600b0dc"]);
         let start_res = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
             .unwrap()
             .block_on(start(
-                format!("NO TARGET"),
-                format!("NO RDP FILE"),
-                rx,
-                format!("9090"),
-                logs_sender,
+                session_api.clone(),
+                ConnectionSpec {
+                    target: TargetConfig {
+                        name: format!("NO TARGET"),
+                        instance_id: format!("NO TARGET"),
+                        ..Default::default()
+                    },
+                    connection: Connection::Rdp(format!("NO RDP FILE"), "Administrator".into()),
+                    credentials: None,
+                    local_port_number: format!("9090"),
+                    commands: CommandsConfig::default(),
+                },
+                HandlerChannels {
+                    app_exit_receiver: rx,
+                    session_info_sender: std::sync::mpsc::channel().0,
+                    deadline_receiver: watch::channel(None).1,
+                    logs_sender,
+                },
             ));
         assert!(
             start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::SSM),
             "Calling task_handler::start with incorrect target did not return SSM error"
         );
+        assert!(
+            session_api.active_sessions().is_empty(),
+            "A session was left open for an incorrect target"
+        );
+    }
+
+    #[test]
+    fn test_task_handler_start_session_error_recorded() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let target = TargetConfig {
+            name: "unknown".into(),
+            instance_id: "i-00000000000000000".into(),
+            ..Default::default()
+        };
+        let started_at = SystemTime::now();
+        let start_res = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(start(
+                session_api,
+                ConnectionSpec {
+                    target: target.clone(),
+                    connection: Connection::Rdp(format!("NO RDP FILE"), "Administrator".into()),
+                    credentials: None,
+                    local_port_number: free_local_port(),
+                    commands: fake_commands(5),
+                },
+                HandlerChannels {
+                    app_exit_receiver: rx,
+                    session_info_sender: std::sync::mpsc::channel().0,
+                    deadline_receiver: watch::channel(None).1,
+                    logs_sender,
+                },
+            ));
+        let record = SessionRecord::new(
+            &target,
+            "9090",
+            started_at,
+            SystemTime::now(),
+            false,
+            &start_res,
+        );
+        assert_eq!(record.error_kind, Some(TaskHandlerErrorKind::SSM));
+        let error = record.error.unwrap_or_default();
+        assert!(
+            error.contains("TargetNotConnected"),
+            "StartSession failure not in the history record : {}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_task_handler_start_with_fakes() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("ok", &port, &[]),
+            rx,
+            port,
+            fake_commands(10),
+        );
+        assert!(
+            start_res.is_ok(),
+            "Tunnel and RDP client through the fakes failed : {:?}",
+            start_res
+        );
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_reports_session_id_and_traffic() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let (session_info_sender, session_info_receiver) = std::sync::mpsc::channel();
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let start_res = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(start(
+                session_api.clone(),
+                ConnectionSpec {
+                    target: fake_target(),
+                    connection: Connection::Rdp(
+                        rdp_file("session-id", &port, &[]),
+                        "Administrator".into(),
+                    ),
+                    credentials: None,
+                    local_port_number: port,
+                    commands: fake_commands(10),
+                },
+                HandlerChannels {
+                    app_exit_receiver: rx,
+                    session_info_sender,
+                    deadline_receiver: watch::channel(None).1,
+                    logs_sender,
+                },
+            ));
+        assert!(start_res.is_ok(), "{:?}", start_res);
+
+        let messages: Vec<SessionInfoMessage> = session_info_receiver.try_iter().collect();
+        let reported: Vec<String> = messages
+            .iter()
+            .filter_map(|message| match message {
+                SessionInfoMessage::SessionId(session_id) => Some(session_id.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reported, session_api.terminated_sessions());
+        assert!(messages.iter().any(
+            |message| matches!(message, SessionInfoMessage::Region(region) if region == "eu-west-1")
+        ));
+
+        // The probe of the fake RDP client, echoed by the fake plugin
+        let traffic = messages
+            .iter()
+            .find_map(|message| match message {
+                SessionInfoMessage::Traffic(traffic) => Some(traffic),
+                _ => None,
+            })
+            .expect("No traffic counters reported");
+        assert_eq!(traffic.bytes_sent(), "fake-rdp-client".len() as u64);
+        assert_eq!(traffic.bytes_received(), traffic.bytes_sent());
+        assert!(traffic.response_time().is_some());
+    }
+
+    #[test]
+    fn test_task_handler_start_with_port_in_use() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("port-in-use", &port, &[]),
+            rx,
+            port,
+            fake_commands(10),
+        );
+        assert!(start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::SSM));
+        assert!(
+            session_api.terminated_sessions().is_empty()
+                && session_api.active_sessions().is_empty(),
+            "A session was started although the local port was taken"
+        );
+    }
+
+    #[test]
+    fn test_task_handler_start_with_plugin_exiting() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api =
+            FakeSessionApi::with_targets(&[FAKE_TARGET]).with_stream_query("mode=exit&code=5");
+        let port = free_local_port();
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("plugin-exit", &port, &[]),
+            rx,
+            port,
+            fake_commands(10),
+        );
+        assert!(start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::SSM));
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_with_plugin_hanging() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api =
+            FakeSessionApi::with_targets(&[FAKE_TARGET]).with_stream_query("mode=hang");
+        let port = free_local_port();
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("plugin-hang", &port, &[]),
+            rx,
+            port,
+            fake_commands(1),
+        );
+        assert!(start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::SSM));
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_with_rdp_client_failing() {
+        for (name, extra) in [
+            ("rdp-crash", "fake mode:s:crash"),
+            ("rdp-exit-code", "fake exit code:i:4"),
+        ] {
+            let (_tx, rx) = tokio::sync::oneshot::channel();
+            let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+            let port = free_local_port();
+            let start_res = start_with_fakes(
+                session_api.clone(),
+                rdp_file(name, &port, &[extra]),
+                rx,
+                port,
+                fake_commands(10),
+            );
+            assert!(
+                start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::RDP),
+                "RDP client failure ({}) was not reported as an RDP error",
+                name
+            );
+            assert_session_closed(&session_api);
+        }
+    }
+
+    #[test]
+    fn test_task_handler_start_with_rdp_probe_failing() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let commands = CommandsConfig {
+            rdp_probe_timeout_secs: Some(5),
+            ..fake_commands(10)
+        };
+        // The fake plugin echoes the Connection Request instead of confirming it
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("rdp-probe", &port, &[]),
+            rx,
+            port,
+            commands,
+        );
+        assert!(
+            start_res
+                .as_ref()
+                .is_err_and(|e| e.kind == TaskHandlerErrorKind::RDP
+                    && e.msg.contains("Invalid answer from the RDP service")),
+            "{:?}",
+            start_res
+        );
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_without_multiplexing_skips_rdp_probe() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let commands = CommandsConfig {
+            session_manager_plugin: fake_plugin_without_multiplexing(),
+            rdp_probe_timeout_secs: Some(5),
+            ..fake_commands(10)
+        };
+        // The probe would fail on the echo, the client is launched instead
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("rdp-probe-skipped", &port, &[]),
+            rx,
+            port,
+            commands,
+        );
+        assert!(start_res.is_ok(), "{:?}", start_res);
+        assert_session_closed(&session_api);
+    }
+
+    // cmd start reports a missing program through a dialog on Windows
+    #[cfg(not(windows))]
+    #[test]
+    fn test_task_handler_start_with_launcher() {
+        let launcher = |wait: bool| LauncherConfig {
+            command: fake_commands(10).rdp_client,
+            args: vec!["/v:{host}:{port}".into(), "{rdp_file}".into()],
+            env: HashMap::new(),
+            wait,
+        };
+        let commands = CommandsConfig {
+            launchers: HashMap::from([
+                ("fake".into(), launcher(true)),
+                ("fake-detached".into(), launcher(false)),
+            ]),
+            launcher: Some("fake".into()),
+            ..fake_commands(10)
+        };
+
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("launcher-failing", &port, &["fake exit code:i:4"]),
+            rx,
+            port,
+            commands.clone(),
+        );
+        assert!(
+            start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::RDP),
+            "The launcher exit code was not reported"
+        );
+        assert_session_closed(&session_api);
+
+        // Without waiting on the client, the tunnel stays up until the app stops it
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let handler = std::thread::spawn({
+            let session_api = session_api.clone();
+            let commands = CommandsConfig {
+                launcher: Some("fake-detached".into()),
+                ..commands.clone()
+            };
+            move || {
+                start_with_fakes(
+                    session_api,
+                    rdp_file("launcher-detached", &port, &["fake exit code:i:4"]),
+                    rx,
+                    port,
+                    commands,
+                )
+            }
+        });
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(
+            !handler.is_finished(),
+            "The tunnel closed with the launcher"
+        );
+        let _ = tx.send(ApplicationExitedMessage);
+        assert!(handler.join().unwrap().is_ok());
+        assert_session_closed(&session_api);
+
+        let commands = CommandsConfig {
+            launcher: Some("royal-ts".into()),
+            ..commands
+        };
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("launcher-unknown", &port, &[]),
+            rx,
+            port,
+            commands,
+        );
+        assert!(start_res.is_err_and(|e| e.msg.contains("royal-ts")));
+        assert!(session_api.active_sessions().is_empty());
+    }
+
+    #[test]
+    fn test_task_handler_start_forward() {
+        let start_forward = |session_api: FakeSessionApi, forward: ForwardConfig, port: String| {
+            let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+            let commands = CommandsConfig {
+                launchers: HashMap::from([(
+                    "fake-ssh".into(),
+                    LauncherConfig {
+                        command: fake_commands(10).rdp_client,
+                        // The fake client finds the tunnel in a .rdp file
+                        args: vec![
+                            rdp_file("forward", &port, &[]),
+                            "-l".into(),
+                            "{username}".into(),
+                        ],
+                        env: HashMap::new(),
+                        wait: true,
+                    },
+                )]),
+                // Neither asked for nor probed outside of RDP
+                rdp_probe_timeout_secs: Some(1),
+                ..fake_commands(10)
+            };
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(start(
+                    session_api,
+                    ConnectionSpec {
+                        target: fake_target(),
+                        connection: Connection::Forward(forward),
+                        credentials: None,
+                        local_port_number: port,
+                        commands,
+                    },
+                    HandlerChannels {
+                        app_exit_receiver: tokio::sync::oneshot::channel().1,
+                        session_info_sender: std::sync::mpsc::channel().0,
+                        deadline_receiver: watch::channel(None).1,
+                        logs_sender,
+                    },
+                ))
+        };
+        let forward = ForwardConfig {
+            name: "SSH".into(),
+            remote_port: 22,
+            launcher: "fake-ssh".into(),
+            username: Some("ec2-user".into()),
+        };
+
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let start_res = start_forward(session_api.clone(), forward.clone(), free_local_port());
+        assert!(start_res.is_ok(), "{:?}", start_res);
+        assert_eq!(session_api.remote_ports(), vec!["22".to_string()]);
+        assert_session_closed(&session_api);
+
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let forward = ForwardConfig {
+            launcher: "telnet".into(),
+            ..forward
+        };
+        let start_res = start_forward(session_api.clone(), forward, free_local_port());
+        assert!(start_res.is_err_and(|e| e.msg.contains("telnet")));
+        assert!(session_api.remote_ports().is_empty());
+    }
+
+    #[test]
+    fn test_task_handler_start_tunnel_only() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let handler = std::thread::spawn({
+            let session_api = session_api.clone();
+            let port = port.clone();
+            move || {
+                let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+                tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap()
+                    .block_on(start(
+                        session_api,
+                        ConnectionSpec {
+                            target: fake_target(),
+                            connection: Connection::Tunnel("5432".into()),
+                            credentials: None,
+                            local_port_number: port,
+                            commands: fake_commands(10),
+                        },
+                        HandlerChannels {
+                            app_exit_receiver: rx,
+                            session_info_sender: std::sync::mpsc::channel().0,
+                            deadline_receiver: watch::channel(None).1,
+                            logs_sender,
+                        },
+                    ))
+            }
+        });
+        std::thread::sleep(Duration::from_secs(2));
+
+        // Whatever connects to the local port goes through, the fake plugin echoing it
+        let mut stream = std::net::TcpStream::connect((LOCAL_HOST, port.parse().unwrap())).unwrap();
+        stream.write_all(b"tunnel only").unwrap();
+        let mut echoed = [0u8; 11];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"tunnel only");
+        drop(stream);
+
+        assert!(!handler.is_finished(), "The tunnel closed without a stop");
+        let _ = tx.send(ApplicationExitedMessage);
+        assert!(handler.join().unwrap().is_ok());
+        assert_eq!(session_api.remote_ports(), vec!["5432".to_string()]);
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_with_missing_rdp_client() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let commands = CommandsConfig {
+            rdp_client: "vm-connect-no-such-rdp-client".into(),
+            ..fake_commands(10)
+        };
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file("rdp-missing", &port, &[]),
+            rx,
+            port,
+            commands,
+        );
+        assert!(start_res.is_err_and(|e| e.kind == TaskHandlerErrorKind::RDP));
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_stopped_by_app_exit() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let rdp_file_path = rdp_file("rdp-hang", &port, &["fake mode:s:hang"]);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+            let _ = tx.send(ApplicationExitedMessage);
+        });
+        let start_res = start_with_fakes(
+            session_api.clone(),
+            rdp_file_path,
+            rx,
+            port,
+            fake_commands(10),
+        );
+        assert!(
+            start_res.is_ok(),
+            "Stopping a running session failed : {:?}",
+            start_res
+        );
+        assert_session_closed(&session_api);
+    }
+
+    #[test]
+    fn test_task_handler_start_closed_when_idle() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let (session_info_sender, session_info_receiver) = std::sync::mpsc::channel();
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        // Stays connected without sending anything after its probe
+        let rdp_file_path = rdp_file("rdp-idle", &port, &["fake mode:s:hang"]);
+        let commands = CommandsConfig {
+            idle_timeout_secs: Some(3),
+            idle_warning_secs: 2,
+            ..fake_commands(10)
+        };
+        let start_res = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(start(
+                session_api.clone(),
+                ConnectionSpec {
+                    target: fake_target(),
+                    connection: Connection::Rdp(rdp_file_path, "Administrator".into()),
+                    credentials: None,
+                    local_port_number: port,
+                    commands,
+                },
+                HandlerChannels {
+                    app_exit_receiver: rx,
+                    session_info_sender,
+                    deadline_receiver: watch::channel(None).1,
+                    logs_sender,
+                },
+            ));
+        assert!(
+            start_res.is_ok(),
+            "Closing an idle session failed : {:?}",
+            start_res
+        );
+        assert_session_closed(&session_api);
+
+        let idle_messages: Vec<SessionInfoMessage> = session_info_receiver
+            .try_iter()
+            .filter(|message| {
+                !matches!(
+                    message,
+                    SessionInfoMessage::SessionId(_)
+                        | SessionInfoMessage::Region(_)
+                        | SessionInfoMessage::Traffic(_)
+                )
+            })
+            .collect();
+        assert!(
+            matches!(
+                idle_messages.as_slice(),
+                [
+                    SessionInfoMessage::IdleWarning(_),
+                    SessionInfoMessage::IdleClosed
+                ]
+            ),
+            "Unexpected idle messages : {:?}",
+            idle_messages
+        );
+    }
+
+    #[test]
+    fn test_task_handler_start_closed_at_deadline() {
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let (session_info_sender, session_info_receiver) = std::sync::mpsc::channel();
+        let (logs_sender, _logs_receiver) = std::sync::mpsc::channel();
+        let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]);
+        let port = free_local_port();
+        let rdp_file_path = rdp_file("rdp-deadline", &port, &["fake mode:s:hang"]);
+        let started_at = Instant::now();
+        let (deadline_sender, deadline_receiver) =
+            watch::channel(Some(started_at + Duration::from_secs(2)));
+        // Extended once by 2 seconds while running, the deadline stands once the sender is gone
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(1));
+            deadline_sender
+                .send_modify(|deadline| *deadline = deadline.map(|d| d + Duration::from_secs(2)));
+        });
+        let start_res = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(start(
+                session_api.clone(),
+                ConnectionSpec {
+                    target: fake_target(),
+                    connection: Connection::Rdp(rdp_file_path, "Administrator".into()),
+                    credentials: None,
+                    local_port_number: port,
+                    commands: fake_commands(10),
+                },
+                HandlerChannels {
+                    app_exit_receiver: rx,
+                    session_info_sender,
+                    deadline_receiver,
+                    logs_sender,
+                },
+            ));
+        assert!(
+            start_res.is_ok(),
+            "Closing a session at its deadline failed : {:?}",
+            start_res
+        );
+        assert!(started_at.elapsed() >= Duration::from_secs(4));
+        assert_session_closed(&session_api);
+        assert!(
+            session_info_receiver
+                .try_iter()
+                .any(|message| matches!(message, SessionInfoMessage::MaxDurationReached))
+        );
+    }
+
+    #[test]
+    fn test_task_handler_start_closed_by_server() {
+        for (query, kind) in [
+            (
+                "close_after_ms=1500&close=timeout",
+                TaskHandlerErrorKind::SSMTimeout,
+            ),
+            ("close_after_ms=1500", TaskHandlerErrorKind::SSM),
+        ] {
+            let (_tx, rx) = tokio::sync::oneshot::channel();
+            let session_api = FakeSessionApi::with_targets(&[FAKE_TARGET]).with_stream_query(query);
+            let port = free_local_port();
+            let start_res = start_with_fakes(
+                session_api.clone(),
+                rdp_file("server-close", &port, &["fake mode:s:hang"]),
+                rx,
+                port,
+                fake_commands(10),
+            );
+            assert!(
+                start_res.as_ref().is_err_and(|e| e.kind == kind),
+                "Closing by the server ({}) gave {:?}",
+                query,
+                start_res
+            );
+            assert_session_closed(&session_api);
+        }
+    }
+
+    #[test]
+    fn test_task_handler_start_through_proxy() {
+        let proxy_runtime = tokio::runtime::Runtime::new().unwrap();
+        let proxy = proxy_runtime
+            .block_on(MockProxy::start(Some(("user", "p@ss:word"))))
+            .unwrap();
+        // Stands in for the data channel endpoint, the fake plugin only connects to it
+        let data_channel = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let data_channel_address = data_channel.local_addr().unwrap();
+
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let session_api =
+            FakeSessionApi::with_targets(&[FAKE_TARGET]).with_stream_query("channel=open");
+        let target = TargetConfig {
+            endpoints: EndpointsConfig {
+                ssmmessages: Some(format!("http://{}", data_channel_address)),
+                ..Default::default()
+            },
+            proxy: ProxyConfig {
+                url: Some(proxy.url()),
+                username: Some("user".into()),
+                password: Some("p@ss:word".into()),
+                ..Default::default()
+            },
+            ..fake_target()
+        };
+        let port = free_local_port();
+        let start_res = start_target_with_fakes(
+            session_api.clone(),
+            target,
+            rdp_file("proxy", &port, &[]),
+            rx,
+            port,
+            fake_commands(10),
+        );
+        assert!(
+            start_res.is_ok(),
+            "Tunnel through the proxy failed : {:?}",
+            start_res
+        );
+        assert_session_closed(&session_api);
+        assert_eq!(
+            proxy.requests(),
+            vec![format!("CONNECT {} HTTP/1.1", data_channel_address)]
+        );
+    }
+
+    // Needs the real session-manager-plugin in PATH, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_task_handler_start_end_to_end_with_mock_ssm() {
+        let mock_runtime = tokio::runtime::Runtime::new().unwrap();
+        let mock = mock_runtime
+            .block_on(MockSsm::start(Backend::Echo))
+            .unwrap();
+        let session_api = SdkSessionApi::from_client(mock_ssm_client(mock.endpoint_url()));
+
+        let (_tx, rx) = tokio::sync::oneshot::channel();
+        let port = free_local_port();
+        let commands = CommandsConfig {
+            session_manager_plugin: CommandsConfig::default().session_manager_plugin,
+            ..fake_commands(30)
+        };
+        // The fake RDP client only exits successfully once its probe came back through the agent
+        let start_res = start_with_fakes(
+            session_api,
+            rdp_file("end-to-end", &port, &[]),
+            rx,
+            port,
+            commands,
+        );
+        assert!(
+            start_res.is_ok(),
+            "Tunnel through the mock SSM endpoint failed : {:?}",
+            start_res
+        );
+        assert!(mock.active_sessions().is_empty());
+        assert_eq!(mock.terminated_sessions().len(), 1);
     }
 }
@@ -0,0 +1,77 @@
+//! Stands in for mstsc / xfreerdp in the vm-connect tests.
+//!
+//! Takes the .rdp file from its arguments, or from stdin after `/args-from:stdin`,
+//! connects to its `full address` and sends a probe through the tunnel. Extra lines
+//! in the .rdp file choose what happens next :
+//! - `fake mode:s:exit` (default) exits with `fake exit code:i:<code>`
+//! - `fake mode:s:hang` stays connected until killed
+//! - `fake mode:s:crash` aborts
+//! - `fake delay ms:i:<ms>` waits before exiting or crashing
+
+use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::time::Duration;
+
+const EXIT_BAD_ARGS: i32 = 3;
+const EXIT_NO_TUNNEL: i32 = 2;
+const PROBE: &[u8] = b"fake-rdp-client";
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "/args-from:stdin") {
+        args.extend(std::io::stdin().lock().lines().map_while(Result::ok));
+    }
+
+    // Options look like `/u:name`, which an absolute path on Unix would too
+    let Some(path) = args.iter().find(|arg| arg.to_lowercase().ends_with(".rdp")) else {
+        eprintln!("fake-rdp-client : no .rdp file in {:?}", args);
+        exit(EXIT_BAD_ARGS);
+    };
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("fake-rdp-client : unable to read {} : {}", path, e);
+            exit(EXIT_BAD_ARGS);
+        }
+    };
+
+    let setting = |name: &str| {
+        content.lines().find_map(|line| {
+            let (key, rest) = line.trim().split_once(':')?;
+            let (_, value) = rest.split_once(':')?;
+            (key == name).then(|| value.to_string())
+        })
+    };
+
+    let address = setting("full address").unwrap_or_default();
+    let mut stream = match TcpStream::connect(&address) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("fake-rdp-client : unable to connect to {} : {}", address, e);
+            exit(EXIT_NO_TUNNEL);
+        }
+    };
+    let mut echoed = [0u8; PROBE.len()];
+    if stream.write_all(PROBE).is_err() || stream.read_exact(&mut echoed).is_err() {
+        eprintln!("fake-rdp-client : no answer through {}", address);
+        exit(EXIT_NO_TUNNEL);
+    }
+
+    let delay = setting("fake delay ms")
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(0);
+    std::thread::sleep(Duration::from_millis(delay));
+
+    match setting("fake mode").as_deref().unwrap_or("exit") {
+        "hang" => loop {
+            std::thread::sleep(Duration::from_secs(60));
+        },
+        "crash" => std::process::abort(),
+        _ => exit(
+            setting("fake exit code")
+                .and_then(|code| code.parse().ok())
+                .unwrap_or(0),
+        ),
+    }
+}
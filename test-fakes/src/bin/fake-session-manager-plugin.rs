@@ -0,0 +1,256 @@
+//! Stands in for session-manager-plugin in the vm-connect tests.
+//!
+//! Invoked like the real plugin, `<env var name> <region> StartSession`, it reads the
+//! StartSession response from the environment and takes its behaviour from the query
+//! of the stream url :
+//! - `mode=bind` (default) listens on `port` and echoes whatever it receives
+//! - `mode=exit` exits right away with `code`
+//! - `mode=hang` never listens
+//! - `mode=crash` aborts
+//!
+//! In `bind` mode, `close_after_ms=<ms>` ends the session like the server closing it,
+//! `close=timeout` printing the idle timeout message of the agent first.
+//!
+//! With `channel=open` it first connects to the host of the stream url, through
+//! `HTTPS_PROXY` (wss) or `HTTP_PROXY` (ws) when set, like the plugin opens its WebSocket.
+//!
+//! `--version` prints a version like the real plugin, one from before multiplexing when
+//! the executable is named `fake-session-manager-plugin-without-multiplexing`.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::exit;
+use std::time::Duration;
+
+const EXIT_BAD_ARGS: i32 = 3;
+const EXIT_DATA_CHANNEL: i32 = 5;
+const VERSION: &str = "1.2.707.0";
+const VERSION_WITHOUT_MULTIPLEXING: &str = "1.1.61.0";
+const WITHOUT_MULTIPLEXING_SUFFIX: &str = "-without-multiplexing";
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--version") {
+        let without_multiplexing = std::env::current_exe()
+            .ok()
+            .and_then(|exe| {
+                exe.file_stem().map(|stem| {
+                    stem.to_string_lossy()
+                        .ends_with(WITHOUT_MULTIPLEXING_SUFFIX)
+                })
+            })
+            .unwrap_or(false);
+        match without_multiplexing {
+            true => println!("{}", VERSION_WITHOUT_MULTIPLEXING),
+            false => println!("{}", VERSION),
+        }
+        return;
+    }
+    if args.len() < 4 || args[3] != "StartSession" {
+        eprintln!(
+            "fake-session-manager-plugin : unexpected arguments {:?}",
+            args
+        );
+        exit(EXIT_BAD_ARGS);
+    }
+    // The token must travel through the environment, never on the command line
+    if args.iter().any(|arg| arg.contains("TokenValue")) {
+        eprintln!("fake-session-manager-plugin : session token found in arguments");
+        exit(EXIT_BAD_ARGS);
+    }
+
+    let response = match std::env::var(&args[1]) {
+        Ok(response) => response,
+        Err(_) => {
+            eprintln!("fake-session-manager-plugin : {} is not set", args[1]);
+            exit(EXIT_BAD_ARGS);
+        }
+    };
+    let stream_url = stream_url(&response);
+    let query = stream_query(stream_url);
+
+    if query.get("channel").map(String::as_str) == Some("open")
+        && let Err(e) = open_data_channel(stream_url)
+    {
+        eprintln!(
+            "fake-session-manager-plugin : unable to open the data channel : {}",
+            e
+        );
+        exit(EXIT_DATA_CHANNEL);
+    }
+
+    match query.get("mode").map(String::as_str).unwrap_or("bind") {
+        "exit" => exit(query.get("code").and_then(|c| c.parse().ok()).unwrap_or(1)),
+        "hang" => loop {
+            std::thread::sleep(Duration::from_secs(60));
+        },
+        "crash" => std::process::abort(),
+        _ => {
+            let port = query.get("port").map(String::as_str).unwrap_or("0");
+            let listener = match TcpListener::bind(format!("127.0.0.1:{}", port)) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!(
+                        "fake-session-manager-plugin : unable to bind {} : {}",
+                        port, e
+                    );
+                    exit(1);
+                }
+            };
+            // What the real plugin prints once it listens
+            println!("Port {} opened for sessionId fake.", port);
+            println!("Waiting for connections...");
+            if let Some(close_after) = query.get("close_after_ms").and_then(|ms| ms.parse().ok()) {
+                let timed_out = query.get("close").map(String::as_str) == Some("timeout");
+                std::thread::spawn(move || close_after_delay(close_after, timed_out));
+            }
+            for stream in listener.incoming().flatten() {
+                std::thread::spawn(move || echo(stream));
+            }
+        }
+    }
+}
+
+fn close_after_delay(delay_ms: u64, timed_out: bool) {
+    std::thread::sleep(Duration::from_millis(delay_ms));
+    let reason = match timed_out {
+        true => "Your session timed out due to inactivity and has been terminated.",
+        false => "Session terminated.",
+    };
+    println!("\n\nSessionId: fake : {}\n\n", reason);
+    exit(0);
+}
+
+fn echo(mut stream: std::net::TcpStream) {
+    let mut buffer = [0u8; 4096];
+    while let Ok(read) = stream.read(&mut buffer) {
+        if read == 0 || stream.write_all(&buffer[..read]).is_err() {
+            break;
+        }
+    }
+}
+
+/**
+ * Pulls `"StreamUrl":"..."` out of the response without a JSON parser, the fakes are
+ * kept free of dependencies
+ */
+fn stream_url(response: &str) -> &str {
+    response
+        .split("\"StreamUrl\":\"")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap_or_default()
+}
+
+fn stream_query(stream_url: &str) -> HashMap<String, String> {
+    stream_url
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/**
+ * Only the connection, with a CONNECT tunnel when going through a proxy, nothing is
+ * sent once it is open
+ */
+fn open_data_channel(stream_url: &str) -> Result<(), String> {
+    let (scheme, rest) = stream_url.split_once("://").ok_or("no scheme")?;
+    let host = rest.split(['/', '?']).next().unwrap_or_default();
+    let (proxy_var, default_port) = match scheme {
+        "wss" => ("HTTPS_PROXY", 443),
+        _ => ("HTTP_PROXY", 80),
+    };
+    let address = with_port(host, default_port);
+
+    let Some(proxy) = std::env::var(proxy_var)
+        .ok()
+        .filter(|proxy| !proxy.is_empty())
+    else {
+        TcpStream::connect(&address).map_err(|e| e.to_string())?;
+        return Ok(());
+    };
+    let proxy = proxy
+        .split_once("://")
+        .map_or(proxy.as_str(), |(_, rest)| rest);
+    let proxy = proxy.trim_end_matches('/');
+    let (userinfo, proxy_host) = match proxy.rsplit_once('@') {
+        Some((userinfo, proxy_host)) => (Some(userinfo), proxy_host),
+        None => (None, proxy),
+    };
+
+    let mut stream = TcpStream::connect(with_port(proxy_host, 80)).map_err(|e| e.to_string())?;
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", address);
+    if let Some(userinfo) = userinfo {
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(&percent_decode(userinfo))
+        ));
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut response = [0u8; 1024];
+    let read = stream.read(&mut response).map_err(|e| e.to_string())?;
+    let response = String::from_utf8_lossy(&response[..read]);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(format!("proxy answered {}", status_line)),
+    }
+}
+
+fn with_port(host: &str, default_port: u16) -> String {
+    match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:{}", host, default_port),
+    }
+}
+
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}